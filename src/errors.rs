@@ -1,8 +1,96 @@
+use arrow::error::ArrowError;
+use wasmtime::{Trap, WasmBacktrace};
+
+use crate::runner::limits::LimitExceeded;
 
 #[derive(thiserror::Error, Debug)]
 pub enum WasmError {
     #[error("general wasm error: {msg}")]
     GeneralError { msg: String },
+    #[error("failed to compile module: {msg}")]
+    ModuleCompile { msg: String },
+    #[error("failed to link module: {msg}")]
+    Link { msg: String },
+    #[error("failed to instantiate module: {msg}")]
+    Instantiation { msg: String },
+    #[error("module does not export {kind} `{name}`")]
+    MissingExport { name: String, kind: String },
+    #[error("export `{name}` has signature {actual}, expected {expected}")]
+    SignatureMismatch {
+        name: String,
+        expected: String,
+        actual: String,
+    },
+    #[error("guest function `{func}` trapped: {msg}")]
+    GuestTrap {
+        func: String,
+        msg: String,
+        backtrace: Option<String>,
+    },
+    #[error("guest function `{func}` ran out of fuel")]
+    FuelExhausted { func: String },
+    #[error("guest function `{func}` exceeded its time budget")]
+    Timeout { func: String },
+    #[error("guest ran out of memory: {msg}")]
+    OutOfMemory { msg: String },
+    #[error("guest function `{func}` leaked {bytes} bytes of guest heap")]
+    MemoryLeak { func: String, bytes: u32 },
+    #[error("failed to decode arrow ipc data: {msg}")]
+    IpcDecode { msg: String },
+    #[error("unsupported type: {data_type}")]
+    UnsupportedType { data_type: String },
+    #[error("invalid input: {msg}")]
+    InvalidInput { msg: String },
+    #[error("invalid udf manifest: {msg}")]
+    InvalidManifest { msg: String },
+    #[error("invalid result from `{func}`: {msg}")]
+    InvalidUdfResult { func: String, msg: String },
+    #[error(transparent)]
+    Arrow(#[from] ArrowError),
+}
+
+impl WasmError {
+    /// Converts the error of a call into the guest export `func`, keeping the trap reason
+    /// and the wasm backtrace when wasmtime captured one.
+    pub fn from_guest_call(func: &str, err: wasmtime::Error) -> Self {
+        if let Some(limit) = err.downcast_ref::<LimitExceeded>() {
+            return WasmError::OutOfMemory {
+                msg: format!("`{}` {}", func, limit),
+            };
+        }
+        match err.downcast_ref::<Trap>() {
+            Some(Trap::OutOfFuel) => {
+                return WasmError::FuelExhausted {
+                    func: func.to_string(),
+                }
+            }
+            Some(Trap::Interrupt) => {
+                return WasmError::Timeout {
+                    func: func.to_string(),
+                }
+            }
+            _ => {}
+        }
+        let backtrace = err
+            .downcast_ref::<WasmBacktrace>()
+            .map(|backtrace| backtrace.to_string());
+        let msg = match err.downcast_ref::<Trap>() {
+            Some(trap) => trap.to_string(),
+            None => format!("{:#}", err),
+        };
+        WasmError::GuestTrap {
+            func: func.to_string(),
+            msg,
+            backtrace,
+        }
+    }
+
+    pub fn invalid_result(func: &str, msg: impl Into<String>) -> Self {
+        WasmError::InvalidUdfResult {
+            func: func.to_string(),
+            msg: msg.into(),
+        }
+    }
 }
 
 impl From<String> for WasmError {
@@ -16,4 +104,3 @@ impl From<&str> for WasmError {
         WasmError::GeneralError { msg: msg.into() }
     }
 }
-
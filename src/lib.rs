@@ -1,4 +1,4 @@
 
 
 pub mod runner;
-mod errors;
+pub mod errors;
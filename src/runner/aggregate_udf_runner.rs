@@ -0,0 +1,235 @@
+use wasmtime::{Engine, Module, ValType};
+
+use arrow::datatypes::{DataType, Field, Fields};
+use arrow::record_batch::RecordBatch;
+use crate::errors::WasmError;
+use crate::runner::binds::wasm_ops::{
+    call_with_buffers, check_func_export, get_func, get_guest_memory, with_guest_buffers,
+    GuestExports,
+};
+use crate::runner::fuel::RunStats;
+use crate::runner::instance_pool::{InstancePool, PooledInstance};
+use crate::runner::ipc::{decode_ipc, encode_ipc};
+use crate::runner::options::WasmRunnerOptions;
+use crate::runner::runner_base::{check_input_types, conform_result_batch, WasmUdfRunner};
+
+/// Intermediate state of an aggregate, as opaque bytes owned by the guest's encoding.
+///
+/// The state lives on the host between calls, so partial aggregates computed on different
+/// partitions (or processes) can be shipped around and combined with
+/// [`WasmAggregateUdfRunner::merge`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AggregateState(Vec<u8>);
+
+impl AggregateState {
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+/// Drives an aggregate UDF `name` through the guest exports
+///
+/// * `{name}_init() -> state`
+/// * `{name}_update(state, batch) -> state`
+/// * `{name}_merge(state, other) -> state`
+/// * `{name}_finalize(state) -> result`
+///
+/// where every value is a packed `(len << 32) | ptr` buffer, `batch` is an Arrow IPC stream
+/// of the input columns and `result` an Arrow IPC stream holding a single one-row column.
+pub struct WasmAggregateUdfRunner {
+    pool: InstancePool,
+    exports: GuestExports,
+    update: String,
+    merge: String,
+    finalize: String,
+    input_types: Vec<DataType>,
+    output_type: DataType,
+}
+
+impl WasmAggregateUdfRunner {
+    pub fn new(
+        engine: Engine,
+        module: Module,
+        name: String,
+        input_types: Vec<DataType>,
+        output_type: DataType,
+    ) -> Result<Self, WasmError> {
+        Self::new_with_options(
+            engine,
+            module,
+            name,
+            input_types,
+            output_type,
+            WasmRunnerOptions::default(),
+        )
+    }
+
+    pub fn new_with_options(
+        engine: Engine,
+        module: Module,
+        name: String,
+        input_types: Vec<DataType>,
+        output_type: DataType,
+        options: WasmRunnerOptions,
+    ) -> Result<Self, WasmError> {
+        let exports = GuestExports::resolve(&module, &format!("{}_init", name), &[], &[ValType::I64])?;
+        let update = format!("{}_update", name);
+        let merge = format!("{}_merge", name);
+        let finalize = format!("{}_finalize", name);
+        check_func_export(&module, &update, &[ValType::I64, ValType::I64], &[ValType::I64])?;
+        check_func_export(&module, &merge, &[ValType::I64, ValType::I64], &[ValType::I64])?;
+        check_func_export(&module, &finalize, &[ValType::I64], &[ValType::I64])?;
+        Ok(Self {
+            pool: InstancePool::new(engine, &module, &name, &options)?,
+            exports,
+            update,
+            merge,
+            finalize,
+            input_types,
+            output_type,
+        })
+    }
+
+    pub fn new_from_raw(
+        name: String,
+        input_types: Vec<DataType>,
+        output_type: DataType,
+        wasm_data: &[u8],
+    ) -> Result<Self, WasmError> {
+        Self::new_from_raw_with_options(
+            name,
+            input_types,
+            output_type,
+            wasm_data,
+            WasmRunnerOptions::default(),
+        )
+    }
+
+    pub fn new_from_raw_with_options(
+        name: String,
+        input_types: Vec<DataType>,
+        output_type: DataType,
+        wasm_data: &[u8],
+        options: WasmRunnerOptions,
+    ) -> Result<Self, WasmError> {
+        let (engine, module) = options.compile(wasm_data)?;
+        Self::new_with_options(engine, module, name, input_types, output_type, options)
+    }
+
+    pub fn input_types(&self) -> &[DataType] {
+        &self.input_types
+    }
+
+    pub fn output_type(&self) -> &DataType {
+        &self.output_type
+    }
+
+    /// Returns the state of an empty aggregate.
+    pub fn init(&self) -> Result<AggregateState, WasmError> {
+        self.pool.with_instance(|pooled| {
+            pooled.begin_batch(1);
+            let state = call_guest(pooled, &self.exports.func, &[])?;
+            Ok(AggregateState(state))
+        })
+    }
+
+    /// Folds the rows of `batch` into `state`.
+    pub fn update(
+        &self,
+        state: &AggregateState,
+        batch: &RecordBatch,
+    ) -> Result<AggregateState, WasmError> {
+        let batch = &check_input_types(batch, &self.input_types)?;
+        let rows = batch.num_rows();
+        let batch = encode_ipc(batch)?;
+        self.pool.with_instance(|pooled| {
+            pooled.begin_batch(rows);
+            let state = call_guest(pooled, &self.update, &[state.as_bytes(), &batch])?;
+            Ok(AggregateState(state))
+        })
+    }
+
+    /// Combines two partial aggregates.
+    pub fn merge(
+        &self,
+        state: &AggregateState,
+        other: &AggregateState,
+    ) -> Result<AggregateState, WasmError> {
+        self.pool.with_instance(|pooled| {
+            pooled.begin_batch(1);
+            let state = call_guest(pooled, &self.merge, &[state.as_bytes(), other.as_bytes()])?;
+            Ok(AggregateState(state))
+        })
+    }
+
+    /// Computes the result of the aggregate as a one-row batch.
+    pub fn finalize(&self, state: &AggregateState) -> Result<RecordBatch, WasmError> {
+        let result = self.pool.with_instance(|pooled| {
+            pooled.begin_batch(1);
+            call_guest(pooled, &self.finalize, &[state.as_bytes()])
+        })?;
+        self.final_batch(&result)
+    }
+
+    /// Decodes the buffer returned by `_finalize`.
+    fn final_batch(&self, result: &[u8]) -> Result<RecordBatch, WasmError> {
+        let result_batch = decode_ipc(&self.finalize, result)?;
+        let outputs = Fields::from(vec![Field::new("", self.output_type.clone(), true)]);
+        let result_batch = conform_result_batch(&self.finalize, &result_batch, &outputs)?;
+        if result_batch.num_rows() != 1 {
+            return Err(WasmError::invalid_result(
+                &self.finalize,
+                format!("expected a single row, got {}", result_batch.num_rows()),
+            ));
+        }
+        Ok(result_batch)
+    }
+}
+
+impl WasmUdfRunner for WasmAggregateUdfRunner {
+    /// Aggregates the whole batch in one go.
+    fn run(&self, batch: &RecordBatch) -> Result<RecordBatch, WasmError> {
+        Ok(self.run_with_stats(batch)?.0)
+    }
+
+    /// Runs the whole lifecycle on a single instance, as one batch of the fuel budget.
+    fn run_with_stats(&self, batch: &RecordBatch) -> Result<(RecordBatch, RunStats), WasmError> {
+        let batch = &check_input_types(batch, &self.input_types)?;
+        let batch_ipc = encode_ipc(batch)?;
+        let (result, stats) = self.pool.with_instance(|pooled| {
+            pooled.begin_batch(batch.num_rows());
+            let state = call_guest(pooled, &self.exports.func, &[])?;
+            let state = call_guest(pooled, &self.update, &[&state, &batch_ipc])?;
+            let result = call_guest(pooled, &self.finalize, &[&state])?;
+            Ok((result, pooled.stats()))
+        })?;
+        Ok((self.final_batch(&result)?, stats))
+    }
+}
+
+/// Calls `func` with each of `args` copied into a guest buffer and returns the bytes of the
+/// buffer it returns.
+fn call_guest(
+    pooled: &mut PooledInstance,
+    func: &str,
+    args: &[&[u8]],
+) -> Result<Vec<u8>, WasmError> {
+    let PooledInstance {
+        store, instance, ..
+    } = pooled;
+    let instance = *instance;
+    let func_def = get_func(instance, &mut *store, func)?;
+    let memory = get_guest_memory(instance, &mut *store)?;
+
+    with_guest_buffers(instance, store, |store, buffers| {
+        call_with_buffers(instance, store, memory, func_def, func, args, buffers)
+    })
+}
@@ -1,50 +1,475 @@
-#[macro_use]
-use anyhow;
-use wasmtime::*;
-use wasmtime::{AsContextMut, TypedFunc};
-use wasmtime::{Caller, Instance};
-use wasmtime_wasi::WasiCtx;
+use itertools::Itertools;
+use wasmtime::{
+    AsContext, AsContextMut, ExternType, Func, Instance, Memory, Module, Store, TypedFunc, Val,
+    ValType,
+};
 use crate::errors::WasmError;
 
+/// Export names under which a guest may publish its allocator. `_cellforce_*` is the
+/// naming used by modules built with the cellforce guest SDK.
+pub const ALLOC_EXPORTS: [&str; 2] = ["wasm_alloc", "_cellforce_malloc"];
+pub const FREE_EXPORTS: [&str; 2] = ["wasm_free", "_cellforce_free"];
+/// Export names of the optional `() -> i32` function reporting the bytes a guest has
+/// allocated, which leak checks compare before and after a run.
+pub const HEAP_USED_EXPORTS: [&str; 2] = ["wasm_heap_used", "_cellforce_heap_used"];
+
+/// Names of the guest exports a runner calls, checked against the module at construction.
+#[derive(Clone, Debug)]
+pub struct GuestExports {
+    pub func: String,
+    pub alloc: &'static str,
+    pub free: &'static str,
+}
+
+impl GuestExports {
+    /// Verifies that `module` exports `func` with the given signature, an allocator pair
+    /// and a `memory`. The free function takes either the pointer, or the pointer and the
+    /// size of the buffer.
+    pub fn resolve(
+        module: &Module,
+        func: &str,
+        params: &[ValType],
+        results: &[ValType],
+    ) -> Result<Self, WasmError> {
+        check_func_export(module, func, params, results)?;
+        let alloc = resolve_func_export(module, &ALLOC_EXPORTS, &[ValType::I32], &[ValType::I32])?;
+        let free = resolve_func_export(module, &FREE_EXPORTS, &[ValType::I32], &[])
+            .or_else(|_| {
+                resolve_func_export(module, &FREE_EXPORTS, &[ValType::I32, ValType::I32], &[])
+            })?;
+        match module.get_export("memory") {
+            Some(ExternType::Memory(_)) => {}
+            _ => {
+                return Err(WasmError::MissingExport {
+                    name: "memory".to_string(),
+                    kind: "memory".to_string(),
+                })
+            }
+        }
+        Ok(Self {
+            func: func.to_string(),
+            alloc,
+            free,
+        })
+    }
+}
+
+fn format_signature(
+    params: impl IntoIterator<Item = ValType>,
+    results: impl IntoIterator<Item = ValType>,
+) -> String {
+    format!(
+        "({}) -> ({})",
+        params.into_iter().join(", "),
+        results.into_iter().join(", ")
+    )
+}
+
+/// Returns the export reporting the guest's heap usage, for leak checks.
+pub fn resolve_heap_used_export(module: &Module) -> Result<&'static str, WasmError> {
+    resolve_func_export(module, &HEAP_USED_EXPORTS, &[], &[ValType::I32])
+}
+
+/// Verifies that `module` exports the function `name` with the given signature.
+pub fn check_func_export(
+    module: &Module,
+    name: &str,
+    params: &[ValType],
+    results: &[ValType],
+) -> Result<(), WasmError> {
+    let Some(ExternType::Func(func_type)) = module.get_export(name) else {
+        return Err(WasmError::MissingExport {
+            name: name.to_string(),
+            kind: "function".to_string(),
+        });
+    };
+    let matches = func_type.params().len() == params.len()
+        && func_type.results().len() == results.len()
+        && func_type.params().zip(params).all(|(a, b)| ValType::eq(&a, b))
+        && func_type.results().zip(results).all(|(a, b)| ValType::eq(&a, b));
+    if !matches {
+        return Err(WasmError::SignatureMismatch {
+            name: name.to_string(),
+            expected: format_signature(params.iter().cloned(), results.iter().cloned()),
+            actual: format_signature(func_type.params(), func_type.results()),
+        });
+    }
+    Ok(())
+}
+
+/// Returns the first of `names` exported by `module`, checking its signature.
+fn resolve_func_export(
+    module: &Module,
+    names: &[&'static str],
+    params: &[ValType],
+    results: &[ValType],
+) -> Result<&'static str, WasmError> {
+    let Some(name) = names
+        .iter()
+        .find(|name| module.get_export(name).is_some())
+    else {
+        return Err(WasmError::MissingExport {
+            name: names.join("` or `"),
+            kind: "function".to_string(),
+        });
+    };
+    check_func_export(module, name, params, results)?;
+    Ok(name)
+}
+
+fn get_first_func<T>(
+    instance: Instance,
+    mut store: impl AsContextMut<Data = T>,
+    names: &[&str],
+) -> Option<Func> {
+    names
+        .iter()
+        .find_map(|name| instance.get_func(&mut store, name))
+}
+
+/// Looks up the function `func` among the exports of `instance`.
+pub fn get_func<T>(
+    instance: Instance,
+    store: impl AsContextMut<Data = T>,
+    func: &str,
+) -> Result<Func, WasmError> {
+    get_first_func(instance, store, &[func]).ok_or_else(|| missing_func(&[func]))
+}
+
+fn missing_func(names: &[&str]) -> WasmError {
+    WasmError::MissingExport {
+        name: names.join("` or `"),
+        kind: "function".to_string(),
+    }
+}
+
 /// Wrapper around the allocate function of the WASM module to allocate shared WASM memory. Allocate some memory for the application to write data for the module
-/// Note: It is up to the application (and not the WASM module) to provide enough pages, so the module does not run out of memory
+/// Note: The guest grows its memory as needed, up to the runner's `ResourceLimits`; allocating past them fails with `WasmError::OutOfMemory`
 /// # Arguments
 /// * `size` - size of memory to allocaten
+///
 /// returns a pointer to the allocated memory area
 pub fn wrapper_wasm_allocate<T>(
     instance: Instance,
     mut store: impl AsContextMut<Data = T>,
     size: u32,
 ) -> Result<*const u8, WasmError> {
-    // Load function an instantiate it
-
     // get the function
-    let func_def = instance
-        .get_func(&mut store, "wasm_alloc")
-        .expect("`wasm_alloc` was not an exported function");
+    let func_def =
+        get_first_func(instance, &mut store, &ALLOC_EXPORTS).ok_or(missing_func(&ALLOC_EXPORTS))?;
     // validate that it corresponds to the parameters and return types we need
-    let func_validated = func_def.typed::<u32, u32>(&store).unwrap();
+    let func_validated = func_def
+        .typed::<u32, u32>(&store)
+        .map_err(|e| WasmError::SignatureMismatch {
+            name: ALLOC_EXPORTS[0].to_string(),
+            expected: "(i32) -> (i32)".to_string(),
+            actual: e.to_string(),
+        })?;
     // call function
-    let result = func_validated.call(store, size).unwrap();
+    let result = func_validated
+        .call(store, size)
+        .map_err(|e| WasmError::from_guest_call(ALLOC_EXPORTS[0], e))?;
+    if result == 0 && size > 0 {
+        return Err(WasmError::OutOfMemory {
+            msg: format!("`{}` failed to allocate {} bytes", ALLOC_EXPORTS[0], size),
+        });
+    }
     Ok(result as *const u8)
 }
 
 ///  Wrapper around the deallocate function of the WASM module to deallocate shared WASM memory. Deallocates existing memory for the purpose of the application
 /// # Arguments
 /// * `ptr` - mutuable pointer to the memory to deallocate
+/// * `size` - size of the memory area, passed to guests whose free function takes it
+///
 /// returns a code if it was successful or not
 pub fn wrapper_wasm_deallocate<T>(
     instance: Instance,
     mut store: impl AsContextMut<Data = T>,
     ptr: *const u8,
+    size: u32,
 ) -> Result<i32, WasmError> {
     // get the function
-    let func_def = instance
-        .get_func(&mut store, "wasm_free")
-        .expect("`wasm_free` was not an exported function");
-    // validate that it corresponds to the parameters and return types we need
-    let func_validated = func_def.typed::<u32, ()>(&store).unwrap();
-    // call function
-    func_validated.call(store, ptr as u32).unwrap();
+    let func_def =
+        get_first_func(instance, &mut store, &FREE_EXPORTS).ok_or(missing_func(&FREE_EXPORTS))?;
+    // call it with the size if its signature asks for it
+    let result = match func_def.ty(&store).params().len() {
+        2 => free_with_size(func_def, &store)?.call(&mut store, (ptr as u32, size)),
+        _ => free_ptr(func_def, &store)?.call(&mut store, ptr as u32),
+    };
+    result.map_err(|e| WasmError::from_guest_call(FREE_EXPORTS[0], e))?;
+    Ok(0)
+}
+
+/// Like [`wrapper_wasm_deallocate`], for instances of async engines. The pointer is passed
+/// as a guest address, raw pointers not being `Send`.
+pub async fn wrapper_wasm_deallocate_async<T: Send>(
+    instance: Instance,
+    mut store: impl AsContextMut<Data = T>,
+    ptr: u32,
+    size: u32,
+) -> Result<i32, WasmError> {
+    let func_def =
+        get_first_func(instance, &mut store, &FREE_EXPORTS).ok_or(missing_func(&FREE_EXPORTS))?;
+    let result = match func_def.ty(&store).params().len() {
+        2 => {
+            free_with_size(func_def, &store)?
+                .call_async(&mut store, (ptr, size))
+                .await
+        }
+        _ => free_ptr(func_def, &store)?.call_async(&mut store, ptr).await,
+    };
+    result.map_err(|e| WasmError::from_guest_call(FREE_EXPORTS[0], e))?;
     Ok(0)
 }
+
+fn free_ptr(func_def: Func, store: impl AsContext) -> Result<TypedFunc<u32, ()>, WasmError> {
+    func_def
+        .typed::<u32, ()>(store)
+        .map_err(|e| WasmError::SignatureMismatch {
+            name: FREE_EXPORTS[0].to_string(),
+            expected: "(i32) -> ()".to_string(),
+            actual: e.to_string(),
+        })
+}
+
+fn free_with_size(
+    func_def: Func,
+    store: impl AsContext,
+) -> Result<TypedFunc<(u32, u32), ()>, WasmError> {
+    func_def
+        .typed::<(u32, u32), ()>(store)
+        .map_err(|e| WasmError::SignatureMismatch {
+            name: FREE_EXPORTS[0].to_string(),
+            expected: "(i32, i32) -> ()".to_string(),
+            actual: e.to_string(),
+        })
+}
+
+/// Unique non-null buffers among packed `(len << 32) | ptr` values, as `(ptr, len)` pairs.
+/// The arguments and the result of a call are freed together once the result was copied
+/// out, and a guest may return one of its arguments, which must only be freed once.
+fn unique_buffers(packed: &[i64]) -> Vec<(u32, u32)> {
+    let mut buffers = packed
+        .iter()
+        .map(|&packed| (packed as u64 as u32, (packed as u64 >> 32) as u32))
+        .filter(|&(ptr, _)| ptr != 0)
+        .collect::<Vec<_>>();
+    buffers.sort_unstable();
+    buffers.dedup_by_key(|&mut (ptr, _)| ptr);
+    buffers
+}
+
+/// Hands the buffers described by packed `(len << 32) | ptr` values back to the guest
+/// allocator, once the host is done with them.
+pub fn free_guest_buffers<T>(
+    instance: Instance,
+    mut store: impl AsContextMut<Data = T>,
+    packed: &[i64],
+) -> Result<(), WasmError> {
+    for (ptr, size) in unique_buffers(packed) {
+        wrapper_wasm_deallocate(instance, &mut store, ptr as *const u8, size)?;
+    }
+    Ok(())
+}
+
+/// Like [`free_guest_buffers`], for instances of async engines.
+pub async fn free_guest_buffers_async<T: Send>(
+    instance: Instance,
+    mut store: impl AsContextMut<Data = T>,
+    packed: &[i64],
+) -> Result<(), WasmError> {
+    for (ptr, size) in unique_buffers(packed) {
+        wrapper_wasm_deallocate_async(instance, &mut store, ptr, size).await?;
+    }
+    Ok(())
+}
+
+/// Runs `call` with an empty list of guest buffers for it to fill, then frees them whether the
+/// call succeeded or not. When both the call and the free fail, the error of the call is
+/// returned.
+pub fn with_guest_buffers<T, R>(
+    instance: Instance,
+    store: &mut Store<T>,
+    call: impl FnOnce(&mut Store<T>, &mut Vec<i64>) -> Result<R, WasmError>,
+) -> Result<R, WasmError> {
+    let mut buffers = vec![];
+    let result = call(&mut *store, &mut buffers);
+    let freed = free_guest_buffers(instance, &mut *store, &buffers);
+    let result = result?;
+    freed?;
+    Ok(result)
+}
+
+/// Like [`with_guest_buffers`], for instances of async engines.
+pub async fn with_guest_buffers_async<T: Send, R>(
+    instance: Instance,
+    store: &mut Store<T>,
+    call: impl AsyncFnOnce(&mut Store<T>, &mut Vec<i64>) -> Result<R, WasmError>,
+) -> Result<R, WasmError> {
+    let mut buffers = vec![];
+    let result = call(&mut *store, &mut buffers).await;
+    let freed = free_guest_buffers_async(instance, &mut *store, &buffers).await;
+    let result = result?;
+    freed?;
+    Ok(result)
+}
+
+/// Bytes the guest reports as allocated through its `wasm_heap_used` export.
+pub fn guest_heap_used<T>(
+    instance: Instance,
+    mut store: impl AsContextMut<Data = T>,
+) -> Result<u32, WasmError> {
+    let func_def = get_first_func(instance, &mut store, &HEAP_USED_EXPORTS)
+        .ok_or(missing_func(&HEAP_USED_EXPORTS))?;
+    heap_used(func_def, &store)?
+        .call(&mut store, ())
+        .map_err(|e| WasmError::from_guest_call(HEAP_USED_EXPORTS[0], e))
+}
+
+/// Like [`guest_heap_used`], for instances of async engines.
+pub async fn guest_heap_used_async<T: Send>(
+    instance: Instance,
+    mut store: impl AsContextMut<Data = T>,
+) -> Result<u32, WasmError> {
+    let func_def = get_first_func(instance, &mut store, &HEAP_USED_EXPORTS)
+        .ok_or(missing_func(&HEAP_USED_EXPORTS))?;
+    heap_used(func_def, &store)?
+        .call_async(&mut store, ())
+        .await
+        .map_err(|e| WasmError::from_guest_call(HEAP_USED_EXPORTS[0], e))
+}
+
+fn heap_used(func_def: Func, store: impl AsContext) -> Result<TypedFunc<(), u32>, WasmError> {
+    func_def
+        .typed::<(), u32>(store)
+        .map_err(|e| WasmError::SignatureMismatch {
+            name: HEAP_USED_EXPORTS[0].to_string(),
+            expected: "() -> (i32)".to_string(),
+            actual: e.to_string(),
+        })
+}
+
+pub fn get_guest_memory<T>(
+    instance: Instance,
+    store: impl AsContextMut<Data = T>,
+) -> Result<Memory, WasmError> {
+    instance
+        .get_memory(store, "memory")
+        .ok_or(WasmError::MissingExport {
+            name: "memory".to_string(),
+            kind: "memory".to_string(),
+        })
+}
+
+/// The allocator handed out a buffer that does not fit in the guest's memory: a guest bug,
+/// which splitting the batch would not fix.
+fn out_of_bounds_allocation(size: u32, offset: u32) -> WasmError {
+    WasmError::invalid_result(
+        ALLOC_EXPORTS[0],
+        format!("allocated buffer of {} bytes at {} is out of bounds", size, offset),
+    )
+}
+
+/// Copies `data` into a freshly allocated guest buffer and returns it as the packed
+/// `(len << 32) | ptr` value the guest ABI uses for variable-length arguments.
+pub fn write_guest_buffer<T>(
+    instance: Instance,
+    mut store: impl AsContextMut<Data = T>,
+    memory: Memory,
+    data: &[u8],
+) -> Result<i64, WasmError> {
+    let size = u32::try_from(data.len()).map_err(|_| WasmError::InvalidInput {
+        msg: format!("{} bytes do not fit in a 32-bit guest address space", data.len()),
+    })?;
+    let offset = wrapper_wasm_allocate(instance, &mut store, size)? as u32;
+    if memory.write(&mut store, offset as usize, data).is_err() {
+        // the allocation is handed back, reporting the bad buffer over a failed free
+        let _ = wrapper_wasm_deallocate(instance, &mut store, offset as *const u8, size);
+        return Err(out_of_bounds_allocation(size, offset));
+    }
+    Ok((((size as u64) << 32) | offset as u64) as i64)
+}
+
+/// Like [`write_guest_buffer`], for instances of async engines.
+pub async fn write_guest_buffer_async<T: Send>(
+    instance: Instance,
+    mut store: impl AsContextMut<Data = T>,
+    memory: Memory,
+    data: &[u8],
+) -> Result<i64, WasmError> {
+    let size = u32::try_from(data.len()).map_err(|_| WasmError::InvalidInput {
+        msg: format!("{} bytes do not fit in a 32-bit guest address space", data.len()),
+    })?;
+    let func_def =
+        get_first_func(instance, &mut store, &ALLOC_EXPORTS).ok_or(missing_func(&ALLOC_EXPORTS))?;
+    let func_validated = func_def
+        .typed::<u32, u32>(&store)
+        .map_err(|e| WasmError::SignatureMismatch {
+            name: ALLOC_EXPORTS[0].to_string(),
+            expected: "(i32) -> (i32)".to_string(),
+            actual: e.to_string(),
+        })?;
+    let offset = func_validated
+        .call_async(&mut store, size)
+        .await
+        .map_err(|e| WasmError::from_guest_call(ALLOC_EXPORTS[0], e))?;
+    if offset == 0 && size > 0 {
+        return Err(WasmError::OutOfMemory {
+            msg: format!("`{}` failed to allocate {} bytes", ALLOC_EXPORTS[0], size),
+        });
+    }
+    if memory.write(&mut store, offset as usize, data).is_err() {
+        let _ = wrapper_wasm_deallocate_async(instance, &mut store, offset, size).await;
+        return Err(out_of_bounds_allocation(size, offset));
+    }
+    Ok((((size as u64) << 32) | offset as u64) as i64)
+}
+
+/// Calls `func` with each of `args` copied into a guest buffer and returns the bytes of the
+/// buffer it returns. The guest buffers of the arguments and of the result are added to
+/// `buffers`, for the caller to free whether the call succeeded or not.
+pub fn call_with_buffers<T>(
+    instance: Instance,
+    mut store: impl AsContextMut<Data = T>,
+    memory: Memory,
+    func_def: Func,
+    func: &str,
+    args: &[impl AsRef<[u8]>],
+    buffers: &mut Vec<i64>,
+) -> Result<Vec<u8>, WasmError> {
+    for arg in args {
+        buffers.push(write_guest_buffer(instance, &mut store, memory, arg.as_ref())?);
+    }
+    let input_vals = buffers.iter().map(|&ptr| Val::I64(ptr)).collect::<Vec<_>>();
+    let mut tmp_result_vals = vec![Val::I64(0)];
+    func_def
+        .call(&mut store, input_vals.as_slice(), &mut tmp_result_vals)
+        .map_err(|e| WasmError::from_guest_call(func, e))?;
+    let Some(Val::I64(result_ptr)) = tmp_result_vals.first() else {
+        return Err(WasmError::invalid_result(func, "expected an i64 result"));
+    };
+    buffers.push(*result_ptr);
+    read_guest_buffer(&mut store, memory, func, *result_ptr)
+}
+
+/// Reads the guest buffer described by a packed `(len << 32) | ptr` value returned by `func`.
+pub fn read_guest_buffer<T>(
+    store: impl AsContextMut<Data = T>,
+    memory: Memory,
+    func: &str,
+    packed: i64,
+) -> Result<Vec<u8>, WasmError> {
+    let size = (packed as u64 >> 32) as usize;
+    let offset = (packed as u64 & 0xffffffff) as usize;
+    let mut data = vec![0; size];
+    memory
+        .read(&store, offset, &mut data)
+        .map_err(|_| {
+            WasmError::invalid_result(
+                func,
+                format!("buffer of {} bytes at {} is out of bounds", size, offset),
+            )
+        })?;
+    Ok(data)
+}
@@ -1,18 +1,273 @@
-use arrow_schema::DataType;
+use std::sync::Arc;
 
-pub fn udf_type_to_arrow_type(udf_type: &str) -> arrow::datatypes::DataType {
-    match udf_type {
-        "int8" => DataType::Int8,
-        "int16" => DataType::Int16,
-        "int32" => DataType::Int32,
-        "int64" => DataType::Int64,
-        "float32" => DataType::Float16,
-        "float64" => DataType::Float64,
-        "string" => DataType::LargeUtf8,
-        "date32" => DataType::Date32,
-        "date64" => DataType::Date64,
-        "boolean" => DataType::Boolean,
-        _ => panic!("unsupported udf type: {}", udf_type),
+use arrow::array::{make_array, Array, ArrayData, ArrayRef};
+use arrow::error::ArrowError;
+use arrow_schema::{
+    DataType, Field, Fields, IntervalUnit, TimeUnit, DECIMAL128_MAX_PRECISION,
+    DECIMAL256_MAX_PRECISION,
+};
+use wasmtime::ValType;
+
+use crate::errors::WasmError;
+
+/// Parses a UDF type string into an Arrow type.
+///
+/// Besides the plain names (`int32`, `string`, ...) parameterized types are written as
+/// `decimal(38,10)`, `timestamp(us)`, `timestamp(us, UTC)`, `duration(ms)` and
+/// `interval(year_month|day_time|month_day_nano)`, and nested types as `list<int32>`,
+/// `struct<name: string, score: float64>` and `map<string, int64>`.
+pub fn udf_type_to_arrow_type(udf_type: &str) -> Result<DataType, WasmError> {
+    let mut parser = TypeParser { rest: udf_type };
+    parser
+        .parse_type()
+        .filter(|_| parser.rest.trim().is_empty())
+        .ok_or_else(|| WasmError::UnsupportedType {
+            data_type: udf_type.to_string(),
+        })
+}
+
+struct TypeParser<'a> {
+    rest: &'a str,
+}
+
+impl<'a> TypeParser<'a> {
+    fn parse_type(&mut self) -> Option<DataType> {
+        let data_type = match self.ident()? {
+            "int8" => DataType::Int8,
+            "int16" => DataType::Int16,
+            "int32" => DataType::Int32,
+            "int64" => DataType::Int64,
+            "uint8" => DataType::UInt8,
+            "uint16" => DataType::UInt16,
+            "uint32" => DataType::UInt32,
+            "uint64" => DataType::UInt64,
+            "float32" => DataType::Float32,
+            "float64" => DataType::Float64,
+            "string" => DataType::LargeUtf8,
+            "binary" => DataType::LargeBinary,
+            "date32" => DataType::Date32,
+            "date64" => DataType::Date64,
+            "boolean" => DataType::Boolean,
+            "decimal" => match self.args()?.as_slice() {
+                [precision, scale] => {
+                    let precision = precision.parse::<u8>().ok()?;
+                    let scale = scale.parse::<i8>().ok()?;
+                    if scale > precision as i8 {
+                        return None;
+                    }
+                    match precision {
+                        0 => return None,
+                        p if p <= DECIMAL128_MAX_PRECISION => DataType::Decimal128(precision, scale),
+                        p if p <= DECIMAL256_MAX_PRECISION => DataType::Decimal256(precision, scale),
+                        _ => return None,
+                    }
+                }
+                _ => return None,
+            },
+            "timestamp" => match self.args()?.as_slice() {
+                [unit] => DataType::Timestamp(parse_time_unit(unit)?, None),
+                [unit, tz] if !tz.is_empty() => {
+                    DataType::Timestamp(parse_time_unit(unit)?, Some((*tz).into()))
+                }
+                _ => return None,
+            },
+            "duration" => match self.args()?.as_slice() {
+                [unit] => DataType::Duration(parse_time_unit(unit)?),
+                _ => return None,
+            },
+            "interval" => match self.args()?.as_slice() {
+                ["year_month"] => DataType::Interval(IntervalUnit::YearMonth),
+                ["day_time"] => DataType::Interval(IntervalUnit::DayTime),
+                ["month_day_nano"] => DataType::Interval(IntervalUnit::MonthDayNano),
+                _ => return None,
+            },
+            "list" => {
+                self.expect('<')?;
+                let item = self.parse_type()?;
+                self.expect('>')?;
+                DataType::List(Arc::new(Field::new_list_field(item, true)))
+            }
+            "struct" => {
+                self.expect('<')?;
+                let mut fields = vec![];
+                loop {
+                    let name = self.ident()?;
+                    self.expect(':')?;
+                    fields.push(Field::new(name, self.parse_type()?, true));
+                    if self.expect('>').is_some() {
+                        break;
+                    }
+                    self.expect(',')?;
+                }
+                DataType::Struct(fields.into())
+            }
+            "map" => {
+                self.expect('<')?;
+                let key = self.parse_type()?;
+                self.expect(',')?;
+                let value = self.parse_type()?;
+                self.expect('>')?;
+                let entries = DataType::Struct(Fields::from(vec![
+                    Field::new("key", key, false),
+                    Field::new("value", value, true),
+                ]));
+                DataType::Map(Arc::new(Field::new("entries", entries, false)), false)
+            }
+            _ => return None,
+        };
+        Some(data_type)
+    }
+
+    fn ident(&mut self) -> Option<&'a str> {
+        let rest = self.rest.trim_start();
+        let len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        let (ident, rest) = rest.split_at(len);
+        self.rest = rest;
+        Some(ident).filter(|ident| !ident.is_empty())
+    }
+
+    fn expect(&mut self, c: char) -> Option<()> {
+        self.rest = self.rest.trim_start().strip_prefix(c)?;
+        Some(())
+    }
+
+    /// Parses a parenthesized, comma separated argument list such as `(us, UTC)`.
+    fn args(&mut self) -> Option<Vec<&'a str>> {
+        self.expect('(')?;
+        let (args, rest) = self.rest.split_once(')')?;
+        self.rest = rest;
+        Some(args.split(',').map(str::trim).collect())
     }
 }
 
+fn parse_time_unit(unit: &str) -> Option<TimeUnit> {
+    match unit {
+        "s" => Some(TimeUnit::Second),
+        "ms" => Some(TimeUnit::Millisecond),
+        "us" => Some(TimeUnit::Microsecond),
+        "ns" => Some(TimeUnit::Nanosecond),
+        _ => None,
+    }
+}
+
+/// Wasm value type an Arrow type is passed as by the row-at-a-time ABI.
+///
+/// * integers up to 32 bits, booleans (0 or 1), `Date32` and year-month intervals are
+///   passed as `i32`
+/// * 64-bit integers, `Date64`, timestamps and durations are passed as `i64`
+/// * day-time intervals are passed as an `i64` packing `(days << 32) | millis`
+/// * floats are passed as `f32`/`f64`
+/// * strings and binaries travel as an `i64` packing `(len << 32) | ptr`; string
+///   arguments are NUL-terminated, string results and binaries are not
+/// * decimals and month-day-nano intervals travel like binaries, holding the
+///   little-endian `i128`/`i256` or `(months: i32, days: i32, nanos: i64)`
+/// * lists, structs and maps travel like binaries, holding an Arrow IPC stream with a
+///   single one-row column
+pub fn arrow_type_to_wasm_type(data_type: &DataType) -> Result<ValType, WasmError> {
+    match data_type {
+        DataType::Boolean
+        | DataType::Int8
+        | DataType::Int16
+        | DataType::Int32
+        | DataType::UInt8
+        | DataType::UInt16
+        | DataType::UInt32
+        | DataType::Date32
+        | DataType::Interval(IntervalUnit::YearMonth) => Ok(ValType::I32),
+        DataType::Int64
+        | DataType::UInt64
+        | DataType::Date64
+        | DataType::Timestamp(_, _)
+        | DataType::Duration(_)
+        | DataType::Interval(IntervalUnit::DayTime) => Ok(ValType::I64),
+        DataType::Float32 => Ok(ValType::F32),
+        DataType::Float64 => Ok(ValType::F64),
+        DataType::Utf8
+        | DataType::LargeUtf8
+        | DataType::Utf8View
+        | DataType::Binary
+        | DataType::LargeBinary
+        | DataType::Decimal128(_, _)
+        | DataType::Decimal256(_, _)
+        | DataType::Interval(IntervalUnit::MonthDayNano)
+        | DataType::List(_)
+        | DataType::LargeList(_)
+        | DataType::Struct(_)
+        | DataType::Map(_, _) => Ok(ValType::I64),
+        _ => Err(WasmError::UnsupportedType {
+            data_type: data_type.to_string(),
+        }),
+    }
+}
+
+/// Whether a column of type `actual` produced by a guest can stand for the `declared` type.
+/// Field names inside lists and maps, including the key and value names of map entries, are
+/// ignored, struct field names must match, and a nullable field may only be produced where
+/// the declared field is nullable too.
+pub(crate) fn matches_declared_type(declared: &DataType, actual: &DataType) -> bool {
+    let field_matches = |declared: &Field, actual: &Field| {
+        (declared.is_nullable() || !actual.is_nullable())
+            && matches_declared_type(declared.data_type(), actual.data_type())
+    };
+    match (declared, actual) {
+        (DataType::List(declared), DataType::List(actual))
+        | (DataType::LargeList(declared), DataType::LargeList(actual)) => {
+            field_matches(declared, actual)
+        }
+        (DataType::Struct(declared), DataType::Struct(actual)) => {
+            declared.len() == actual.len()
+                && declared
+                    .iter()
+                    .zip(actual.iter())
+                    .all(|(d, a)| d.name() == a.name() && field_matches(d, a))
+        }
+        (DataType::Map(declared, declared_sorted), DataType::Map(actual, actual_sorted)) => {
+            let entries_match = match (declared.data_type(), actual.data_type()) {
+                (DataType::Struct(declared), DataType::Struct(actual)) => {
+                    declared.len() == actual.len()
+                        && declared
+                            .iter()
+                            .zip(actual.iter())
+                            .all(|(d, a)| field_matches(d, a))
+                }
+                _ => false,
+            };
+            declared_sorted == actual_sorted && entries_match
+        }
+        _ => declared == actual,
+    }
+}
+
+/// Relabels an array accepted by `matches_declared_type` with the declared type, so nested
+/// field names and nullability follow the declaration.
+pub(crate) fn cast_to_declared_type(
+    array: &ArrayRef,
+    declared: &DataType,
+) -> Result<ArrayRef, ArrowError> {
+    if array.data_type() == declared {
+        return Ok(array.clone());
+    }
+    Ok(make_array(relabel_data(array.to_data(), declared)?))
+}
+
+fn relabel_data(data: ArrayData, declared: &DataType) -> Result<ArrayData, ArrowError> {
+    let child_types: Vec<&DataType> = match declared {
+        DataType::List(field) | DataType::LargeList(field) | DataType::Map(field, _) => {
+            vec![field.data_type()]
+        }
+        DataType::Struct(fields) => fields.iter().map(|f| f.data_type()).collect(),
+        _ => return Ok(data),
+    };
+    let child_data = data
+        .child_data()
+        .iter()
+        .zip(child_types)
+        .map(|(child, child_type)| relabel_data(child.clone(), child_type))
+        .collect::<Result<Vec<_>, _>>()?;
+    data.into_builder()
+        .data_type(declared.clone())
+        .child_data(child_data)
+        .build()
+}
@@ -0,0 +1,133 @@
+use wasmtime::{Config, InstanceAllocationStrategy, PoolingAllocationConfig, Strategy};
+
+/// Code generator that compiles guests to native code.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compiler {
+    /// Optimizing compiler, for the fastest guest code.
+    #[default]
+    Cranelift,
+    /// Baseline compiler, compiling much faster into slower code. Only available on x86-64,
+    /// and without relaxed SIMD.
+    Winch,
+}
+
+/// How hard Cranelift optimizes; ignored by Winch.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OptLevel {
+    None,
+    #[default]
+    Speed,
+    SpeedAndSize,
+}
+
+/// Sizing of the pooling instance allocator, which reserves the memories and tables of all
+/// instances up front and reuses them instead of mapping fresh ones for every instance.
+/// Linear memories are initialized copy-on-write from an image of the module's data segments,
+/// so instantiating a fresh instance costs a few page mappings, and a slot is reset to that
+/// image when its instance is dropped: nothing leaks from one instance to the next.
+///
+/// Instantiating beyond the pool's capacity fails with `WasmError::Instantiation`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PoolingOptions {
+    /// Instances that may be alive at once, across all runners of the engine.
+    pub total_instances: u32,
+    /// Linear memories that may be alive at once.
+    pub total_memories: u32,
+    /// Tables that may be alive at once.
+    pub total_tables: u32,
+    /// Largest size a linear memory may grow to, in bytes.
+    pub max_memory_size: usize,
+    /// Slots kept warm, with their pages still mapped, once their instance is gone.
+    pub max_unused_warm_slots: u32,
+}
+
+impl PoolingOptions {
+    /// Pool for `concurrency` instances alive at once, each with one memory and one table.
+    /// Instances kept warm by an instance pool and those of in-flight streams count toward
+    /// the concurrency, across all the runners sharing the engine.
+    pub fn for_concurrency(concurrency: u32) -> Self {
+        Self {
+            total_instances: concurrency,
+            total_memories: concurrency,
+            total_tables: concurrency,
+            max_unused_warm_slots: concurrency,
+            ..Default::default()
+        }
+    }
+}
+
+impl Default for PoolingOptions {
+    fn default() -> Self {
+        Self {
+            total_instances: 1000,
+            total_memories: 1000,
+            total_tables: 1000,
+            max_memory_size: 1 << 30,
+            max_unused_warm_slots: 100,
+        }
+    }
+}
+
+/// Configuration of the engines that compile and run guests. The wasm feature toggles left
+/// at `None` keep the defaults of wasmtime for the chosen compiler.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EngineOptions {
+    pub compiler: Compiler,
+    pub opt_level: OptLevel,
+    pub simd: Option<bool>,
+    pub relaxed_simd: Option<bool>,
+    pub bulk_memory: Option<bool>,
+    pub multi_memory: Option<bool>,
+    pub memory64: Option<bool>,
+    /// Compile the functions of a module on several threads; on by default.
+    pub parallel_compilation: Option<bool>,
+    /// Allocate instances from a pool instead of on demand; see
+    /// [`PoolingOptions::for_concurrency`].
+    pub pooling: Option<PoolingOptions>,
+}
+
+impl EngineOptions {
+    /// Applies these options to `config`.
+    pub(crate) fn apply(&self, config: &mut Config) {
+        config.strategy(match self.compiler {
+            Compiler::Cranelift => Strategy::Cranelift,
+            Compiler::Winch => Strategy::Winch,
+        });
+        config.cranelift_opt_level(match self.opt_level {
+            OptLevel::None => wasmtime::OptLevel::None,
+            OptLevel::Speed => wasmtime::OptLevel::Speed,
+            OptLevel::SpeedAndSize => wasmtime::OptLevel::SpeedAndSize,
+        });
+        if let Some(enable) = self.simd {
+            config.wasm_simd(enable);
+        }
+        if let Some(enable) = self.relaxed_simd {
+            config.wasm_relaxed_simd(enable);
+        }
+        if let Some(enable) = self.bulk_memory {
+            config.wasm_bulk_memory(enable);
+        }
+        if let Some(enable) = self.multi_memory {
+            config.wasm_multi_memory(enable);
+        }
+        if let Some(enable) = self.memory64 {
+            config.wasm_memory64(enable);
+        }
+        if let Some(enable) = self.parallel_compilation {
+            config.parallel_compilation(enable);
+        }
+        if let Some(pooling) = &self.pooling {
+            let mut pool = PoolingAllocationConfig::default();
+            pool.total_core_instances(pooling.total_instances)
+                .total_memories(pooling.total_memories)
+                .total_tables(pooling.total_tables)
+                .max_memory_size(pooling.max_memory_size)
+                .max_unused_warm_slots(pooling.max_unused_warm_slots)
+                // one fiber stack per instance running asynchronously
+                .total_stacks(pooling.total_instances);
+            config
+                .allocation_strategy(InstanceAllocationStrategy::Pooling(pool))
+                .memory_init_cow(true);
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::thread::{self, Thread};
+use std::time::Duration;
+
+use std::future::Future;
+use std::pin::pin;
+use std::task::{Context, Poll, Waker};
+
+use wasmtime::{Engine, EngineWeak, Instance, Module, Store, Trap};
+
+use crate::errors::WasmError;
+
+/// Interval at which the shared ticker thread advances the epoch of registered engines.
+pub const EPOCH_TICK: Duration = Duration::from_millis(1);
+
+static ENGINES: Mutex<Vec<EngineWeak>> = Mutex::new(Vec::new());
+static TICKER: OnceLock<Result<Thread, String>> = OnceLock::new();
+/// Number of live [`Ticking`] guards; the ticker sleeps while there are none.
+static TICKING: AtomicUsize = AtomicUsize::new(0);
+
+/// Deadline, in ticks, of stores without a timeout on engines with epoch interruption: far
+/// enough never to fire, and small enough not to overflow when added to the current epoch.
+pub(crate) const NO_DEADLINE: u64 = u64::MAX / 2;
+
+/// Number of ticks after which a deadline of `timeout` may fire. One tick is added since the
+/// current tick may be almost over when the deadline is set.
+pub(crate) fn deadline_ticks(timeout: Duration) -> u64 {
+    let ticks = timeout.as_nanos().div_ceil(EPOCH_TICK.as_nanos()) + 1;
+    u64::try_from(ticks).unwrap_or(u64::MAX).min(NO_DEADLINE)
+}
+
+/// Whether `engine` has `Config::epoch_interruption` enabled, which wasmtime does not expose:
+/// an empty function is called past its deadline, which only traps when the engine
+/// instruments code with epoch checks.
+fn interruption_enabled(engine: &Engine) -> Result<bool, WasmError> {
+    let module = Module::new(engine, r#"(module (func (export "probe")))"#)
+        .map_err(|e| WasmError::from(format!("failed to compile the epoch probe: {:#}", e)))?;
+    let mut store = Store::new(engine, ());
+    // engines metering fuel need some to enter the probe; others refuse it
+    let _ = store.set_fuel(u64::MAX);
+    store.set_epoch_deadline(0);
+    let result = match engine.is_async() {
+        // nothing in the probe suspends, so the call completes on the first poll
+        true => match poll_once(async {
+            let instance = Instance::new_async(&mut store, &module, &[]).await?;
+            let probe = instance.get_typed_func::<(), ()>(&mut store, "probe")?;
+            probe.call_async(&mut store, ()).await
+        }) {
+            Poll::Ready(result) => result,
+            Poll::Pending => return Err(WasmError::from("the epoch probe did not complete")),
+        },
+        false => Instance::new(&mut store, &module, &[]).and_then(|instance| {
+            let probe = instance.get_typed_func::<(), ()>(&mut store, "probe")?;
+            probe.call(&mut store, ())
+        }),
+    };
+    match result {
+        Ok(()) => Ok(false),
+        Err(e) if e.downcast_ref::<Trap>() == Some(&Trap::Interrupt) => Ok(true),
+        Err(e) => Err(WasmError::from(format!("the epoch probe failed: {:#}", e))),
+    }
+}
+
+fn poll_once<F: Future>(future: F) -> Poll<F::Output> {
+    pin!(future).poll(&mut Context::from_waker(Waker::noop()))
+}
+
+/// Has the ticker thread increment the epoch of `engine` every [`EPOCH_TICK`] while a
+/// [`Ticking`] guard is alive, until the engine is dropped. Fails when the engine does not
+/// have `Config::epoch_interruption` enabled, since its deadlines would never fire.
+pub(crate) fn register(engine: &Engine) -> Result<(), WasmError> {
+    TICKER
+        .get_or_init(|| {
+            thread::Builder::new()
+                .name("wasm-epoch-ticker".to_string())
+                .spawn(tick)
+                .map(|handle| handle.thread().clone())
+                .map_err(|e| format!("failed to start the epoch ticker: {}", e))
+        })
+        .as_ref()
+        .map_err(|msg| WasmError::GeneralError { msg: msg.clone() })?;
+    let registered = |engines: &[EngineWeak]| {
+        engines
+            .iter()
+            .filter_map(EngineWeak::upgrade)
+            .any(|registered| Engine::same(&registered, engine))
+    };
+    let lock = || {
+        ENGINES
+            .lock()
+            .map_err(|_| WasmError::from("epoch ticker registry is poisoned"))
+    };
+    if registered(&lock()?) {
+        return Ok(());
+    }
+    // probed without holding the registry, which the ticker needs on every tick
+    if !interruption_enabled(engine)? {
+        return Err(WasmError::InvalidInput {
+            msg: "a timeout needs an engine with `Config::epoch_interruption` enabled"
+                .to_string(),
+        });
+    }
+    let mut engines = lock()?;
+    if !registered(&engines) {
+        engines.push(engine.weak());
+    }
+    Ok(())
+}
+
+/// Keeps the ticker advancing the epochs of registered engines while alive. A store holds
+/// one from the moment its deadline is armed until it goes idle, so that the ticker does not
+/// wake up every tick for engines that are kept alive but run nothing.
+pub(crate) struct Ticking(());
+
+impl Ticking {
+    pub(crate) fn start() -> Self {
+        if TICKING.fetch_add(1, Ordering::SeqCst) == 0 {
+            if let Some(Ok(ticker)) = TICKER.get() {
+                ticker.unpark();
+            }
+        }
+        Self(())
+    }
+}
+
+impl Drop for Ticking {
+    fn drop(&mut self) {
+        TICKING.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn tick() {
+    loop {
+        thread::sleep(EPOCH_TICK);
+        match ENGINES.lock() {
+            Ok(mut engines) => engines.retain(|engine| match engine.upgrade() {
+                Some(engine) => {
+                    engine.increment_epoch();
+                    true
+                }
+                None => false,
+            }),
+            Err(_) => return,
+        }
+        // sleep until a deadline is armed; `Ticking::start` unparks after counting itself,
+        // so a guard created right after this check still wakes the thread
+        if TICKING.load(Ordering::SeqCst) == 0 {
+            thread::park();
+        }
+    }
+}
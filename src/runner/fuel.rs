@@ -0,0 +1,96 @@
+use wasmtime::Store;
+
+use crate::runner::guest_output::GuestOutput;
+use crate::runner::host::HostState;
+
+/// How much fuel a guest may burn before the call fails with `WasmError::FuelExhausted`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FuelBudget {
+    /// Fuel for every row. Runners that hand the guest a whole batch at once grant the
+    /// budget times the number of rows of the batch.
+    PerRow(u64),
+    /// Fuel for every batch.
+    PerBatch(u64),
+    /// Fuel for a whole `run_stream`; a single `run` counts as a stream of one batch.
+    PerStream(u64),
+}
+
+/// Resources consumed by a run.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RunStats {
+    /// Fuel burnt by the guest, zero if the engine does not meter fuel.
+    pub fuel_consumed: u64,
+    /// What the guest printed, when the WASI policy captures and returns its output.
+    pub output: Option<GuestOutput>,
+}
+
+/// Refuels the store of an instance as the budget prescribes and keeps track of the fuel it
+/// consumed. Stores of engines without fuel metering are left alone.
+pub(crate) struct FuelMeter {
+    budget: Option<FuelBudget>,
+    /// Fuel left in the store when it was last refuelled or read.
+    remaining: u64,
+    consumed: u64,
+}
+
+impl FuelMeter {
+    pub fn new(budget: Option<FuelBudget>) -> Self {
+        Self {
+            budget,
+            remaining: 0,
+            consumed: 0,
+        }
+    }
+
+    /// Starts a stream: resets the consumption and refuels the store. Without a budget a
+    /// metering store gets all the fuel there is, so only async yielding uses it.
+    pub fn begin_stream(&mut self, store: &mut Store<HostState>) {
+        self.consumed = 0;
+        let fuel = match self.budget {
+            Some(FuelBudget::PerRow(fuel))
+            | Some(FuelBudget::PerBatch(fuel))
+            | Some(FuelBudget::PerStream(fuel)) => fuel,
+            None => u64::MAX,
+        };
+        self.refuel(store, fuel);
+    }
+
+    pub fn begin_batch(&mut self, store: &mut Store<HostState>, rows: usize) {
+        match self.budget {
+            Some(FuelBudget::PerBatch(fuel)) => self.refuel(store, fuel),
+            Some(FuelBudget::PerRow(fuel)) => {
+                self.refuel(store, fuel.saturating_mul(rows.max(1) as u64))
+            }
+            _ => {}
+        }
+    }
+
+    pub fn begin_row(&mut self, store: &mut Store<HostState>) {
+        if let Some(FuelBudget::PerRow(fuel)) = self.budget {
+            self.refuel(store, fuel);
+        }
+    }
+
+    /// Resources consumed since the stream began.
+    pub fn stats(&mut self, store: &Store<HostState>) -> RunStats {
+        self.settle(store);
+        RunStats {
+            fuel_consumed: self.consumed,
+            output: None,
+        }
+    }
+
+    fn settle(&mut self, store: &Store<HostState>) {
+        if let Ok(fuel) = store.get_fuel() {
+            self.consumed += self.remaining.saturating_sub(fuel);
+            self.remaining = fuel;
+        }
+    }
+
+    fn refuel(&mut self, store: &mut Store<HostState>, fuel: u64) {
+        self.settle(store);
+        if store.set_fuel(fuel).is_ok() {
+            self.remaining = fuel;
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+/// Target of the `tracing` events carrying guest output.
+pub const GUEST_OUTPUT_TARGET: &str = "cellforce_wasm::guest";
+
+/// A limit on the captured output of a batch that keeps it small enough to log, for
+/// `GuestStdio::Capture::max_captured_bytes`.
+pub const DEFAULT_MAX_CAPTURED_BYTES: usize = 1 << 20;
+
+static NEXT_BATCH_ID: AtomicU64 = AtomicU64::new(0);
+
+/// What a guest wrote to stdout and stderr.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GuestOutput {
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl GuestOutput {
+    pub(crate) fn append(&mut self, other: &GuestOutput) {
+        self.stdout.push_str(&other.stdout);
+        self.stderr.push_str(&other.stderr);
+    }
+}
+
+/// In-memory buffers backing the stdout and stderr of an instance.
+#[derive(Clone)]
+pub(crate) struct StdioPipes {
+    pub stdout: Arc<RwLock<CapturedBytes>>,
+    pub stderr: Arc<RwLock<CapturedBytes>>,
+}
+
+impl StdioPipes {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            stdout: Arc::new(RwLock::new(CapturedBytes::new(max_bytes))),
+            stderr: Arc::new(RwLock::new(CapturedBytes::new(max_bytes))),
+        }
+    }
+}
+
+/// What a guest wrote to one stream since the last flush, up to `max_bytes`. Writes past the
+/// limit succeed, but only their length is kept.
+pub(crate) struct CapturedBytes {
+    bytes: Vec<u8>,
+    max_bytes: usize,
+    dropped: usize,
+}
+
+impl CapturedBytes {
+    fn new(max_bytes: usize) -> Self {
+        Self {
+            bytes: vec![],
+            max_bytes,
+            dropped: 0,
+        }
+    }
+}
+
+impl Write for CapturedBytes {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let kept = buf.len().min(self.max_bytes.saturating_sub(self.bytes.len()));
+        self.bytes.extend_from_slice(&buf[..kept]);
+        self.dropped += buf.len() - kept;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Id of a new batch, unique within the process.
+pub(crate) fn next_batch_id() -> u64 {
+    NEXT_BATCH_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Drains the captured output of an instance, logging every line as a `tracing` event tagged
+/// with the UDF name and the batch id.
+pub(crate) struct StdioCapture {
+    pipes: StdioPipes,
+    /// Output since the stream began, kept when the caller asked for it.
+    output: Option<GuestOutput>,
+}
+
+impl StdioCapture {
+    pub fn new(pipes: StdioPipes, return_output: bool) -> Self {
+        Self {
+            pipes,
+            output: return_output.then(GuestOutput::default),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        if let Some(output) = &mut self.output {
+            *output = GuestOutput::default();
+        }
+    }
+
+    /// Output flushed since the stream began, if it is returned to the caller.
+    pub fn output(&self) -> Option<GuestOutput> {
+        self.output.clone()
+    }
+
+    /// Logs and drains what the guest wrote since the last flush, during batch `batch_id`.
+    /// Output past the limit of a stream is dropped, which a last event reports.
+    pub fn flush(&mut self, udf: &str, batch_id: u64) {
+        let (stdout, stdout_dropped) = take_lossy(&self.pipes.stdout);
+        let (stderr, stderr_dropped) = take_lossy(&self.pipes.stderr);
+        let flushed = GuestOutput { stdout, stderr };
+        for line in flushed.stdout.lines() {
+            tracing::info!(
+                target: GUEST_OUTPUT_TARGET,
+                udf,
+                batch_id,
+                stream = "stdout",
+                "{}",
+                line
+            );
+        }
+        for line in flushed.stderr.lines() {
+            tracing::warn!(
+                target: GUEST_OUTPUT_TARGET,
+                udf,
+                batch_id,
+                stream = "stderr",
+                "{}",
+                line
+            );
+        }
+        for (stream, dropped_bytes) in [("stdout", stdout_dropped), ("stderr", stderr_dropped)] {
+            if dropped_bytes > 0 {
+                tracing::warn!(
+                    target: GUEST_OUTPUT_TARGET,
+                    udf,
+                    batch_id,
+                    stream,
+                    dropped_bytes,
+                    "guest output truncated"
+                );
+            }
+        }
+        if let Some(output) = &mut self.output {
+            output.append(&flushed);
+        }
+    }
+}
+
+/// Drains a stream, returning its output and the number of bytes dropped past its limit.
+fn take_lossy(pipe: &RwLock<CapturedBytes>) -> (String, usize) {
+    let (bytes, dropped) = match pipe.write() {
+        Ok(mut captured) => (
+            std::mem::take(&mut captured.bytes),
+            std::mem::take(&mut captured.dropped),
+        ),
+        Err(_) => return (String::new(), 0),
+    };
+    (String::from_utf8_lossy(&bytes).into_owned(), dropped)
+}
@@ -0,0 +1,176 @@
+use std::sync::Arc;
+
+use tracing::Level;
+use wasi_common::WasiCtx;
+use wasmtime::{Caller, Extern, Linker};
+
+use crate::errors::WasmError;
+use crate::runner::limits::GuestLimiter;
+
+/// Import module under which the host functions available to guests are registered.
+pub const HOST_MODULE: &str = "cellforce_host";
+
+/// Target of the `tracing` events of `cellforce_host.log`.
+pub const GUEST_LOG_TARGET: &str = "cellforce_wasm::guest::log";
+
+/// Target of the `tracing` events of `cellforce_host.counter_add` and
+/// `cellforce_host.histogram_record`.
+pub const GUEST_METRICS_TARGET: &str = "cellforce_wasm::guest::metrics";
+
+/// Data stored alongside every guest instance.
+pub struct HostState {
+    pub wasi: WasiCtx,
+    /// Set by the guest through `cellforce_host.set_result_null` during a call.
+    pub(crate) result_null: bool,
+    pub(crate) limiter: GuestLimiter,
+    /// UDF name and batch id the events of the guest are tagged with.
+    pub(crate) udf: Arc<str>,
+    pub(crate) batch_id: u64,
+}
+
+impl HostState {
+    pub fn new(wasi: WasiCtx) -> Self {
+        Self {
+            wasi,
+            result_null: false,
+            limiter: GuestLimiter::default(),
+            udf: Arc::from(""),
+            batch_id: 0,
+        }
+    }
+}
+
+/// Registers WASI and the `cellforce_host` functions on `linker`.
+pub(crate) fn add_to_linker(linker: &mut Linker<HostState>) -> Result<(), WasmError> {
+    let link_error = |e: wasmtime::Error| WasmError::Link {
+        msg: format!("{:#}", e),
+    };
+    // async engines need the WASI implementation whose host calls can be awaited
+    match linker.engine().is_async() {
+        true => wasi_common::tokio::add_to_linker(linker, |s: &mut HostState| &mut s.wasi),
+        false => wasi_common::sync::add_to_linker(linker, |s: &mut HostState| &mut s.wasi),
+    }
+    .map_err(link_error)?;
+    // Marks the value returned by the current call as null.
+    linker
+        .func_wrap(HOST_MODULE, "set_result_null", |mut caller: Caller<'_, HostState>| {
+            caller.data_mut().result_null = true;
+        })
+        .map_err(link_error)?;
+    // Logs the UTF-8 message at `ptr..ptr + len` at `level`: 0 for trace, 1 debug, 2 info,
+    // 3 warn and 4 error.
+    linker
+        .func_wrap(HOST_MODULE, "log", host_log)
+        .map_err(link_error)?;
+    // Adds `value` to the counter named by the UTF-8 string at `ptr..ptr + len`.
+    linker
+        .func_wrap(
+            HOST_MODULE,
+            "counter_add",
+            |mut caller: Caller<'_, HostState>, ptr: i32, len: i32, value: i64| {
+                let name = read_metric_name(&mut caller, ptr, len)?;
+                let state = caller.data();
+                tracing::info!(
+                    target: GUEST_METRICS_TARGET,
+                    udf = %state.udf,
+                    batch_id = state.batch_id,
+                    metric = %name,
+                    kind = "counter",
+                    value
+                );
+                wasmtime::Result::<()>::Ok(())
+            },
+        )
+        .map_err(link_error)?;
+    // Records `value` in the histogram named by the UTF-8 string at `ptr..ptr + len`.
+    linker
+        .func_wrap(
+            HOST_MODULE,
+            "histogram_record",
+            |mut caller: Caller<'_, HostState>, ptr: i32, len: i32, value: f64| {
+                let name = read_metric_name(&mut caller, ptr, len)?;
+                let state = caller.data();
+                tracing::info!(
+                    target: GUEST_METRICS_TARGET,
+                    udf = %state.udf,
+                    batch_id = state.batch_id,
+                    metric = %name,
+                    kind = "histogram",
+                    value
+                );
+                wasmtime::Result::<()>::Ok(())
+            },
+        )
+        .map_err(link_error)?;
+    Ok(())
+}
+
+fn host_log(
+    mut caller: Caller<'_, HostState>,
+    level: i32,
+    ptr: i32,
+    len: i32,
+) -> wasmtime::Result<()> {
+    let level = match level {
+        0 => Level::TRACE,
+        1 => Level::DEBUG,
+        2 => Level::INFO,
+        3 => Level::WARN,
+        4 => Level::ERROR,
+        _ => return Err(wasmtime::Error::msg(format!("unknown log level {}", level))),
+    };
+    let message = read_guest_str(&mut caller, ptr, len)?;
+    let state = caller.data();
+    // the level of an event must be a constant
+    macro_rules! guest_event {
+        ($level:expr) => {
+            tracing::event!(
+                target: GUEST_LOG_TARGET,
+                $level,
+                udf = %state.udf,
+                batch_id = state.batch_id,
+                "{}",
+                message
+            )
+        };
+    }
+    match level {
+        Level::TRACE => guest_event!(Level::TRACE),
+        Level::DEBUG => guest_event!(Level::DEBUG),
+        Level::INFO => guest_event!(Level::INFO),
+        Level::WARN => guest_event!(Level::WARN),
+        _ => guest_event!(Level::ERROR),
+    }
+    Ok(())
+}
+
+fn read_metric_name(
+    caller: &mut Caller<'_, HostState>,
+    ptr: i32,
+    len: i32,
+) -> wasmtime::Result<String> {
+    let name = read_guest_str(caller, ptr, len)?;
+    if name.is_empty() {
+        return Err(wasmtime::Error::msg("metric names must not be empty"));
+    }
+    Ok(name)
+}
+
+/// Reads the string at `ptr..ptr + len` in the memory of the calling guest, replacing
+/// invalid UTF-8.
+fn read_guest_str(caller: &mut Caller<'_, HostState>, ptr: i32, len: i32) -> wasmtime::Result<String> {
+    let Some(Extern::Memory(memory)) = caller.get_export("memory") else {
+        return Err(wasmtime::Error::msg("the guest does not export its memory"));
+    };
+    let (start, len) = (ptr as u32 as usize, len as u32 as usize);
+    let bytes = memory
+        .data(&caller)
+        .get(start..start.saturating_add(len))
+        .ok_or_else(|| {
+            wasmtime::Error::msg(format!(
+                "string of {} bytes at {} is out of bounds",
+                len, start
+            ))
+        })?;
+    Ok(String::from_utf8_lossy(bytes).into_owned())
+}
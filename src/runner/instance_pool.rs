@@ -0,0 +1,400 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use wasi_common::WasiCtx;
+use wasmtime::{Engine, Instance, InstancePre, Linker, Module, Store};
+
+use crate::errors::WasmError;
+use crate::runner::binds::wasm_ops::{
+    guest_heap_used, guest_heap_used_async, resolve_heap_used_export,
+};
+use crate::runner::epoch::{self, Ticking};
+use crate::runner::fuel::{FuelBudget, FuelMeter, RunStats};
+use crate::runner::guest_output::{self, StdioCapture, StdioPipes};
+use crate::runner::host::{self, HostState};
+use crate::runner::limits::{GuestLimiter, ResourceLimits};
+use crate::runner::wasi::{self, GuestStdio, WasiPolicy};
+use crate::runner::options::WasmRunnerOptions;
+
+/// Fuel an async guest may consume before yielding back to the executor.
+pub const ASYNC_YIELD_FUEL: u64 = 10_000;
+
+/// What happens to a pooled instance once the `run()` call that borrowed it returns.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InstanceResetPolicy {
+    /// Keep the instance, including any guest state, for the next call.
+    Never,
+    /// Discard the instance if the call that borrowed it failed.
+    #[default]
+    OnError,
+    /// Discard the instance after every call.
+    Always,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InstancePoolOptions {
+    /// Maximum number of idle instances kept warm between calls.
+    pub pool_size: usize,
+    pub reset_policy: InstanceResetPolicy,
+    /// Retire an instance after it has served this many calls; `None` keeps it forever.
+    pub max_uses_per_instance: Option<usize>,
+}
+
+impl Default for InstancePoolOptions {
+    fn default() -> Self {
+        Self {
+            pool_size: 4,
+            reset_policy: InstanceResetPolicy::default(),
+            max_uses_per_instance: None,
+        }
+    }
+}
+
+pub(crate) struct PooledInstance {
+    pub store: Store<HostState>,
+    pub instance: Instance,
+    pub fuel: FuelMeter,
+    stdio: Option<StdioCapture>,
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    /// Keeps epochs advancing while the deadline is armed.
+    ticking: Option<Ticking>,
+    /// Guest heap usage when the instance was acquired, under leak checks.
+    heap_baseline: Option<u32>,
+    uses: usize,
+}
+
+impl PooledInstance {
+    /// Refuels the store and restarts the timeout for a batch of `rows` rows. Captured
+    /// output of the previous batch is logged.
+    pub fn begin_batch(&mut self, rows: usize) {
+        self.fuel.begin_batch(&mut self.store, rows);
+        self.flush_stdio();
+        self.store.data_mut().batch_id = guest_output::next_batch_id();
+        self.arm_deadline();
+    }
+
+    /// Refuels the store for a single row.
+    pub fn begin_row(&mut self) {
+        self.fuel.begin_row(&mut self.store);
+    }
+
+    /// Resources consumed since the instance was acquired.
+    pub fn stats(&mut self) -> RunStats {
+        self.flush_stdio();
+        RunStats {
+            output: self.stdio.as_ref().and_then(StdioCapture::output),
+            ..self.fuel.stats(&self.store)
+        }
+    }
+
+    fn begin_stream(&mut self) {
+        self.fuel.begin_stream(&mut self.store);
+        self.flush_stdio();
+        if let Some(stdio) = &mut self.stdio {
+            stdio.reset();
+        }
+        self.store.data_mut().batch_id = guest_output::next_batch_id();
+        self.arm_deadline();
+    }
+
+    /// Fails if the guest heap grew since the instance was acquired.
+    fn check_leaks(&mut self, heap_used: u32) -> Result<(), WasmError> {
+        match self.heap_baseline {
+            Some(baseline) if heap_used > baseline => Err(WasmError::MemoryLeak {
+                func: self.store.data().udf.to_string(),
+                bytes: heap_used - baseline,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    fn flush_stdio(&mut self) {
+        if let Some(stdio) = &mut self.stdio {
+            let state = self.store.data();
+            stdio.flush(&state.udf, state.batch_id);
+        }
+    }
+
+    fn arm_deadline(&mut self) {
+        if let Some(timeout) = self.timeout {
+            self.store.set_epoch_deadline(epoch::deadline_ticks(timeout));
+            self.deadline = Some(Instant::now() + timeout);
+            self.ticking.get_or_insert_with(Ticking::start);
+        }
+    }
+
+    /// Whether the host stopped the last call, by timeout, for lack of fuel or on a resource
+    /// limit. Such an instance may have been interrupted halfway through updating its state.
+    fn interrupted(&self) -> bool {
+        self.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+            || matches!(self.store.get_fuel(), Ok(0))
+            || self.store.data().limiter.exceeded()
+    }
+}
+
+/// Hands out instances of one module. Without pool options every `acquire` instantiates
+/// the module from scratch and every `release` drops it, which matches the unpooled
+/// behaviour of the runners.
+pub(crate) struct InstancePool {
+    engine: Engine,
+    instance_pre: InstancePre<HostState>,
+    options: Option<InstancePoolOptions>,
+    fuel: Option<FuelBudget>,
+    timeout: Option<Duration>,
+    limits: Option<ResourceLimits>,
+    wasi: WasiPolicy,
+    udf: Arc<str>,
+    check_leaks: bool,
+    idle: Mutex<Vec<PooledInstance>>,
+}
+
+impl InstancePool {
+    /// Links `module` against the host imports once, so that unresolved or mistyped
+    /// imports are reported here rather than on the first `acquire`. `udf` names the function
+    /// in the logs of captured guest output.
+    pub fn new(
+        engine: Engine,
+        module: &Module,
+        udf: &str,
+        options: &WasmRunnerOptions,
+    ) -> Result<Self, WasmError> {
+        if options.fuel.is_some() && Store::new(&engine, ()).get_fuel().is_err() {
+            return Err(WasmError::InvalidInput {
+                msg: "a fuel budget needs an engine with `Config::consume_fuel` enabled"
+                    .to_string(),
+            });
+        }
+        let mut linker = Linker::new(&engine);
+        host::add_to_linker(&mut linker)?;
+        let instance_pre = linker
+            .instantiate_pre(module)
+            .map_err(|e| WasmError::Link {
+                msg: format!("{:#}", e),
+            })?;
+        if options.timeout.is_some() {
+            epoch::register(&engine)?;
+        }
+        // likewise for a WASI policy naming directories that cannot be opened
+        wasi::build_ctx(&options.wasi, engine.is_async())?;
+        if options.check_leaks {
+            resolve_heap_used_export(module)?;
+        }
+        Ok(Self {
+            engine,
+            instance_pre,
+            options: options.instance_pool.clone(),
+            fuel: options.fuel,
+            timeout: options.timeout,
+            limits: options.limits.clone(),
+            wasi: options.wasi.clone(),
+            udf: Arc::from(udf),
+            check_leaks: options.check_leaks,
+            idle: Mutex::new(vec![]),
+        })
+    }
+
+    pub fn acquire(&self) -> Result<PooledInstance, WasmError> {
+        if self.engine.is_async() {
+            return Err(WasmError::InvalidInput {
+                msg: "the runner uses an async engine, call `run_async` instead".to_string(),
+            });
+        }
+        let mut pooled = match self.pop_idle() {
+            Some(pooled) => pooled,
+            None => self.instantiate()?,
+        };
+        pooled.begin_stream();
+        if self.check_leaks {
+            pooled.heap_baseline = Some(guest_heap_used(pooled.instance, &mut pooled.store)?);
+        }
+        Ok(pooled)
+    }
+
+    /// Like `acquire`, for async engines.
+    pub async fn acquire_async(&self) -> Result<PooledInstance, WasmError> {
+        if !self.engine.is_async() {
+            return Err(WasmError::InvalidInput {
+                msg: "the runner uses a synchronous engine, call `run` instead".to_string(),
+            });
+        }
+        let mut pooled = match self.pop_idle() {
+            Some(pooled) => pooled,
+            None => self.instantiate_async().await?,
+        };
+        pooled.begin_stream();
+        if self.check_leaks {
+            let heap_used = guest_heap_used_async(pooled.instance, &mut pooled.store).await?;
+            pooled.heap_baseline = Some(heap_used);
+        }
+        Ok(pooled)
+    }
+
+    fn pop_idle(&self) -> Option<PooledInstance> {
+        self.idle.lock().ok().and_then(|mut idle| idle.pop())
+    }
+
+    /// Returns an instance after use. Instances the host interrupted are always discarded.
+    pub fn release(&self, mut pooled: PooledInstance, succeeded: bool) {
+        pooled.flush_stdio();
+        if !succeeded && pooled.interrupted() {
+            return;
+        }
+        let Some(options) = &self.options else {
+            return;
+        };
+        pooled.uses += 1;
+        let retire = match options.reset_policy {
+            InstanceResetPolicy::Never => false,
+            InstanceResetPolicy::OnError => !succeeded,
+            InstanceResetPolicy::Always => true,
+        } || options
+            .max_uses_per_instance
+            .is_some_and(|max_uses| pooled.uses >= max_uses);
+        if retire {
+            return;
+        }
+        // idle instances run nothing, their deadline is armed again on the next acquire
+        pooled.ticking = None;
+        if let Ok(mut idle) = self.idle.lock() {
+            if idle.len() < options.pool_size {
+                idle.push(pooled);
+            }
+        }
+    }
+
+    /// Returns an instance after a use that produced `result`. Under leak checks, a
+    /// successful use that left the guest heap larger than it found it fails.
+    pub fn finish<R>(
+        &self,
+        mut pooled: PooledInstance,
+        result: Result<R, WasmError>,
+    ) -> Result<R, WasmError> {
+        let result = result.and_then(|value| {
+            if pooled.heap_baseline.is_some() {
+                let heap_used = guest_heap_used(pooled.instance, &mut pooled.store)?;
+                pooled.check_leaks(heap_used)?;
+            }
+            Ok(value)
+        });
+        self.release(pooled, result.is_ok());
+        result
+    }
+
+    /// Like `finish`, for async engines.
+    pub async fn finish_async<R>(
+        &self,
+        mut pooled: PooledInstance,
+        result: Result<R, WasmError>,
+    ) -> Result<R, WasmError> {
+        let result = match result {
+            Ok(value) if pooled.heap_baseline.is_some() => {
+                match guest_heap_used_async(pooled.instance, &mut pooled.store).await {
+                    Ok(heap_used) => pooled.check_leaks(heap_used).map(|()| value),
+                    Err(e) => Err(e),
+                }
+            }
+            result => result,
+        };
+        self.release(pooled, result.is_ok());
+        result
+    }
+
+    /// Borrows an instance for the duration of `f` and returns it to the pool afterwards.
+    pub fn with_instance<R>(
+        &self,
+        f: impl FnOnce(&mut PooledInstance) -> Result<R, WasmError>,
+    ) -> Result<R, WasmError> {
+        let mut pooled = self.acquire()?;
+        let result = f(&mut pooled);
+        self.finish(pooled, result)
+    }
+
+    fn instantiate(&self) -> Result<PooledInstance, WasmError> {
+        let (wasi, pipes) = wasi::build_ctx(&self.wasi, false)?;
+        let mut store = Store::new(&self.engine, self.host_state(wasi));
+        let ticking = self.arm_store(&mut store);
+        let instance = self
+            .instance_pre
+            .instantiate(&mut store)
+            .map_err(|e| WasmError::Instantiation {
+                msg: format!("{:#}", e),
+            })?;
+        Ok(PooledInstance {
+            store,
+            instance,
+            fuel: FuelMeter::new(self.fuel),
+            stdio: pipes.map(|pipes| self.capture(pipes)),
+            timeout: self.timeout,
+            deadline: None,
+            ticking,
+            heap_baseline: None,
+            uses: 0,
+        })
+    }
+
+    async fn instantiate_async(&self) -> Result<PooledInstance, WasmError> {
+        let (wasi, pipes) = wasi::build_ctx(&self.wasi, true)?;
+        let mut store = Store::new(&self.engine, self.host_state(wasi));
+        let ticking = self.arm_store(&mut store);
+        // with fuel metering enabled, long-running guests periodically yield to the executor
+        if store.get_fuel().is_ok() {
+            store
+                .fuel_async_yield_interval(Some(ASYNC_YIELD_FUEL))
+                .map_err(|e| WasmError::Instantiation {
+                    msg: format!("{:#}", e),
+                })?;
+        }
+        let instance = self
+            .instance_pre
+            .instantiate_async(&mut store)
+            .await
+            .map_err(|e| WasmError::Instantiation {
+                msg: format!("{:#}", e),
+            })?;
+        Ok(PooledInstance {
+            store,
+            instance,
+            fuel: FuelMeter::new(self.fuel),
+            stdio: pipes.map(|pipes| self.capture(pipes)),
+            timeout: self.timeout,
+            deadline: None,
+            ticking,
+            heap_baseline: None,
+            uses: 0,
+        })
+    }
+
+    fn capture(&self, pipes: StdioPipes) -> StdioCapture {
+        let return_output = matches!(
+            self.wasi.stdio,
+            GuestStdio::Capture {
+                return_output: true,
+                ..
+            }
+        );
+        StdioCapture::new(pipes, return_output)
+    }
+
+    fn host_state(&self, wasi: WasiCtx) -> HostState {
+        let mut state = HostState::new(wasi);
+        state.udf = Arc::clone(&self.udf);
+        state
+    }
+
+    /// Applies the resource limits. Instantiation runs the start function: give it the fuel
+    /// of a stream and the timeout. Without a timeout the deadline never fires, as stores
+    /// of engines with epoch interruption would otherwise trap on their first epoch check.
+    fn arm_store(&self, store: &mut Store<HostState>) -> Option<Ticking> {
+        if let Some(limits) = &self.limits {
+            store.data_mut().limiter = GuestLimiter::new(limits.clone());
+            store.limiter(|state| &mut state.limiter);
+        }
+        FuelMeter::new(self.fuel).begin_stream(store);
+        store.set_epoch_deadline(match self.timeout {
+            Some(timeout) => epoch::deadline_ticks(timeout),
+            None => epoch::NO_DEADLINE,
+        });
+        self.timeout.map(|_| Ticking::start())
+    }
+}
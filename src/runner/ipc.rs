@@ -0,0 +1,27 @@
+use arrow::compute::concat_batches;
+use arrow::ipc::reader::StreamReader;
+use arrow::ipc::writer::StreamWriter;
+use arrow::record_batch::RecordBatch;
+
+use crate::errors::WasmError;
+
+/// Serializes `batch` as an Arrow IPC stream.
+pub fn encode_ipc(batch: &RecordBatch) -> Result<Vec<u8>, WasmError> {
+    let mut stream_writer = StreamWriter::try_new(Vec::new(), &batch.schema())?;
+    stream_writer.write(batch)?;
+    stream_writer.finish()?;
+    Ok(stream_writer.into_inner()?)
+}
+
+/// Deserializes an Arrow IPC stream returned by `func` into a single batch.
+pub fn decode_ipc(func: &str, data: &[u8]) -> Result<RecordBatch, WasmError> {
+    let ipc_error = |e: arrow::error::ArrowError| WasmError::IpcDecode {
+        msg: format!("`{}`: {}", func, e),
+    };
+    let stream_reader = StreamReader::try_new(data, None).map_err(ipc_error)?;
+    let schema = stream_reader.schema();
+    let batches = stream_reader
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(ipc_error)?;
+    concat_batches(&schema, &batches).map_err(ipc_error)
+}
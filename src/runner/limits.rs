@@ -0,0 +1,101 @@
+use std::fmt;
+
+use wasmtime::{ResourceLimiter, DEFAULT_INSTANCE_LIMIT, DEFAULT_MEMORY_LIMIT, DEFAULT_TABLE_LIMIT};
+
+/// Caps on what a guest instance may allocate. Resources left at `None` are only bounded by
+/// the module's own declarations and wasmtime's defaults.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ResourceLimits {
+    /// Maximum size in bytes of each linear memory.
+    pub max_memory_bytes: Option<usize>,
+    /// Maximum number of elements of each table.
+    pub max_table_elements: Option<usize>,
+    /// Maximum number of instances, tables and memories in a store.
+    pub max_instances: Option<usize>,
+    pub max_tables: Option<usize>,
+    pub max_memories: Option<usize>,
+}
+
+/// Error a memory or table growth beyond the limits traps with.
+#[derive(Debug)]
+pub(crate) struct LimitExceeded(String);
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+/// Enforces [`ResourceLimits`] on a store. Growing past a limit traps rather than failing
+/// the `memory.grow`, so that the call reports a clear out-of-memory error instead of
+/// whatever the guest makes of a failed allocation.
+#[derive(Default)]
+pub(crate) struct GuestLimiter {
+    limits: ResourceLimits,
+    exceeded: bool,
+}
+
+impl GuestLimiter {
+    pub fn new(limits: ResourceLimits) -> Self {
+        Self {
+            limits,
+            exceeded: false,
+        }
+    }
+
+    /// Whether a growth was denied, leaving the guest interrupted halfway through.
+    pub fn exceeded(&self) -> bool {
+        self.exceeded
+    }
+
+    fn deny(&mut self, msg: String) -> wasmtime::Result<bool> {
+        self.exceeded = true;
+        Err(LimitExceeded(msg).into())
+    }
+}
+
+impl ResourceLimiter for GuestLimiter {
+    fn memory_growing(
+        &mut self,
+        _current: usize,
+        desired: usize,
+        maximum: Option<usize>,
+    ) -> wasmtime::Result<bool> {
+        match self.limits.max_memory_bytes {
+            Some(max) if desired > max => self.deny(format!(
+                "growing memory to {} bytes exceeds the limit of {} bytes",
+                desired, max
+            )),
+            _ => Ok(maximum.is_none_or(|maximum| desired <= maximum)),
+        }
+    }
+
+    fn table_growing(
+        &mut self,
+        _current: usize,
+        desired: usize,
+        maximum: Option<usize>,
+    ) -> wasmtime::Result<bool> {
+        match self.limits.max_table_elements {
+            Some(max) if desired > max => self.deny(format!(
+                "growing a table to {} elements exceeds the limit of {} elements",
+                desired, max
+            )),
+            _ => Ok(maximum.is_none_or(|maximum| desired <= maximum)),
+        }
+    }
+
+    fn instances(&self) -> usize {
+        self.limits.max_instances.unwrap_or(DEFAULT_INSTANCE_LIMIT)
+    }
+
+    fn tables(&self) -> usize {
+        self.limits.max_tables.unwrap_or(DEFAULT_TABLE_LIMIT)
+    }
+
+    fn memories(&self) -> usize {
+        self.limits.max_memories.unwrap_or(DEFAULT_MEMORY_LIMIT)
+    }
+}
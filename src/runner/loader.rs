@@ -1,9 +1,17 @@
 use std::sync::Arc;
 
+use arrow::datatypes::{DataType, Field};
+
 use crate::errors::WasmError;
+use crate::runner::aggregate_udf_runner::WasmAggregateUdfRunner;
 use crate::runner::datatypes::udf_type_to_arrow_type;
-use crate::runner::runner_base::WasmUdfRunner;
+use crate::runner::engine::{EngineOptions, PoolingOptions};
+use crate::runner::manifest::{self, UdfDeclaration, UdfKind, UdfManifest};
+use crate::runner::options::{NullHandling, WasmRunnerOptions};
+use crate::runner::runner_base::{AsyncWasmUdfRunner, WasmUdfRunner};
 use crate::runner::scalar_udf_runner::{WasmArrowScalarUdfRunner, WasmScalarUdfRunner};
+use crate::runner::table_udf_runner::WasmTableUdfRunner;
+use crate::runner::window_udf_runner::{WasmWindowUdfRunner, WindowFrame};
 
 #[derive(Clone, PartialEq)]
 pub struct WasmScalarUdfOptions {
@@ -11,45 +19,309 @@ pub struct WasmScalarUdfOptions {
     pub internal_name: String,
     pub input_types: Vec<String>,
     pub output_types: Vec<String>,
+    /// Column names of the outputs, one per entry of `output_types`. May be left empty for a
+    /// single output, which is then unnamed.
+    pub output_names: Vec<String>,
     pub arrow: bool,
+    /// Null semantics of the row-at-a-time runner; ignored when `arrow` is set.
+    pub null_handling: NullHandling,
+}
+
+#[derive(Clone, PartialEq)]
+pub struct WasmAggregateUdfOptions {
+    pub export_name: String,
+    /// Prefix of the guest's `_init`, `_update`, `_merge` and `_finalize` exports.
+    pub internal_name: String,
+    pub input_types: Vec<String>,
+    pub output_type: String,
+}
+
+#[derive(Clone, PartialEq)]
+pub struct WasmTableUdfOptions {
+    pub export_name: String,
+    pub internal_name: String,
+    pub input_types: Vec<String>,
+    pub output_types: Vec<String>,
+    /// Column names of the outputs, one per entry of `output_types`.
+    pub output_names: Vec<String>,
+    pub null_handling: NullHandling,
+}
+
+#[derive(Clone, PartialEq)]
+pub struct WasmWindowUdfOptions {
+    pub export_name: String,
+    pub internal_name: String,
+    pub input_types: Vec<String>,
+    pub output_type: String,
+    pub frame: WindowFrame,
+    pub null_handling: NullHandling,
 }
 
-pub struct WasmUdfRunnerLoader {}
+/// Runner of a UDF declared by a module manifest.
+#[derive(Clone)]
+pub enum LoadedUdfRunner {
+    Scalar(Arc<dyn WasmUdfRunner + Sync + Send>),
+    Aggregate(Arc<WasmAggregateUdfRunner>),
+    Table(Arc<WasmTableUdfRunner>),
+    Window(Arc<WasmWindowUdfRunner>),
+}
+
+#[derive(Clone)]
+pub struct LoadedUdf {
+    pub declaration: UdfDeclaration,
+    pub runner: LoadedUdfRunner,
+}
+
+#[derive(Default)]
+pub struct WasmUdfRunnerLoader {
+    runner_options: WasmRunnerOptions,
+}
 
 impl WasmUdfRunnerLoader {
     pub fn new() -> Self {
-        Self {}
+        Self::default()
+    }
+
+    /// Applies `runner_options` to every runner created by this loader.
+    pub fn with_runner_options(mut self, runner_options: WasmRunnerOptions) -> Self {
+        self.runner_options = runner_options;
+        self
+    }
+
+    /// Builds the engines of every runner created by this loader with `engine_options`.
+    pub fn with_engine_options(mut self, engine_options: EngineOptions) -> Self {
+        self.runner_options.engine = engine_options;
+        self
+    }
+
+    /// Allocates the instances of every runner created by this loader from a pool sized for
+    /// `concurrency` instances alive at once, with copy-on-write memory images, so that
+    /// runners without an instance pool get a fresh instance for every batch cheaply.
+    pub fn with_pooling_allocator(mut self, concurrency: u32) -> Self {
+        self.runner_options.engine.pooling = Some(PoolingOptions::for_concurrency(concurrency));
+        self
+    }
+
+    /// Compiles `wasm_data` into a precompiled artifact, for [`Self::trust_artifact`] of a
+    /// loader with the same runner options.
+    pub fn precompile(&self, wasm_data: &[u8]) -> Result<Vec<u8>, WasmError> {
+        self.runner_options.module_cache()?.precompile(wasm_data)
+    }
+
+    /// Loads an artifact produced by [`Self::precompile`], after which the synchronous
+    /// `load_*` functions accept it in place of the wasm bytes. Without this, they reject
+    /// precompiled artifacts with `WasmError::InvalidInput`. The async loaders need
+    /// artifacts of an async engine, so they reject these still.
+    ///
+    /// # Safety
+    ///
+    /// The artifact is loaded as native code without validation: it must come from a trusted
+    /// source, as with `ModuleCache::deserialize_trusted`.
+    pub unsafe fn trust_artifact(&self, artifact: &[u8]) -> Result<(), WasmError> {
+        let cache = self.runner_options.module_cache()?;
+        // SAFETY: forwarded to the caller
+        unsafe { cache.deserialize_trusted(artifact) }.map(|_| ())
+    }
+
+    /// Reads the manifest embedded in `wasm_data`: the JSON of its `cellforce_manifest`
+    /// custom section or, without one, the JSON returned by its `cellforce_manifest` export.
+    /// The export runs without WASI access and with a small fuel and time budget, whatever
+    /// the runner options; precompiled artifacts carry no section and are not run, so their
+    /// manifest must be read from the wasm module.
+    pub fn read_manifest(&self, wasm_data: &[u8]) -> Result<UdfManifest, WasmError> {
+        manifest::read_manifest(wasm_data, &self.runner_options)
+    }
+
+    /// Creates a runner for every UDF declared by the manifest of `wasm_data`, in manifest
+    /// order. Scalar UDFs get synchronous runners; for async ones, pass the
+    /// `scalar_options()` of a declaration to `load_scalar_udf_runner_async`.
+    pub fn load_manifest_udfs(&self, wasm_data: &[u8]) -> Result<Vec<LoadedUdf>, WasmError> {
+        self.read_manifest(wasm_data)?
+            .udfs
+            .into_iter()
+            .map(|declaration| {
+                let runner = match declaration.kind {
+                    UdfKind::Scalar => LoadedUdfRunner::Scalar(
+                        self.load_scalar_udf_runner(&declaration.scalar_options()?, wasm_data)?,
+                    ),
+                    UdfKind::Aggregate => LoadedUdfRunner::Aggregate(
+                        self.load_aggregate_udf_runner(
+                            &declaration.aggregate_options()?,
+                            wasm_data,
+                        )?,
+                    ),
+                    UdfKind::Table => LoadedUdfRunner::Table(
+                        self.load_table_udf_runner(&declaration.table_options()?, wasm_data)?,
+                    ),
+                    UdfKind::Window => LoadedUdfRunner::Window(
+                        self.load_window_udf_runner(&declaration.window_options()?, wasm_data)?,
+                    ),
+                };
+                Ok(LoadedUdf {
+                    declaration,
+                    runner,
+                })
+            })
+            .collect()
     }
 
     pub fn load_scalar_udf_runner(
+        &self,
         spec: &WasmScalarUdfOptions,
         wasm_data: &[u8],
     ) -> Result<Arc<dyn WasmUdfRunner + Sync + Send>, WasmError> {
-        let input_types = spec
-            .input_types
-            .iter()
-            .map(|t| udf_type_to_arrow_type(t))
-            .collect();
-        let output_type = udf_type_to_arrow_type(&spec.output_types[0]);
+        let input_types = spec_input_types(&spec.input_types)?;
+        let outputs = spec_outputs(&spec.export_name, &spec.output_types, &spec.output_names)?;
+        let runner_options = WasmRunnerOptions {
+            null_handling: spec.null_handling,
+            ..self.runner_options.clone()
+        };
         match spec.arrow {
             true => Ok(Arc::new(
-                WasmArrowScalarUdfRunner::new_from_raw(
+                WasmArrowScalarUdfRunner::new_from_raw_with_outputs(
                     spec.internal_name.clone(),
                     input_types,
-                    output_type,
-                    &wasm_data,
-                )
-                .unwrap(),
+                    outputs,
+                    wasm_data,
+                    runner_options,
+                )?,
             )),
             false => Ok(Arc::new(
-                WasmScalarUdfRunner::new_from_raw(
+                WasmScalarUdfRunner::new_from_raw_with_outputs(
                     spec.internal_name.clone(),
                     input_types,
-                    output_type,
-                    &wasm_data,
-                )
-                .unwrap(),
+                    outputs,
+                    wasm_data,
+                    runner_options,
+                )?,
             )),
         }
     }
+
+    /// Like `load_scalar_udf_runner`, but compiles the module for an async engine so the
+    /// runner is driven with `run_async`.
+    pub fn load_scalar_udf_runner_async(
+        &self,
+        spec: &WasmScalarUdfOptions,
+        wasm_data: &[u8],
+    ) -> Result<Arc<dyn AsyncWasmUdfRunner + Sync + Send>, WasmError> {
+        let input_types = spec_input_types(&spec.input_types)?;
+        let outputs = spec_outputs(&spec.export_name, &spec.output_types, &spec.output_names)?;
+        let runner_options = WasmRunnerOptions {
+            null_handling: spec.null_handling,
+            async_support: true,
+            ..self.runner_options.clone()
+        };
+        match spec.arrow {
+            true => Ok(Arc::new(
+                WasmArrowScalarUdfRunner::new_from_raw_with_outputs(
+                    spec.internal_name.clone(),
+                    input_types,
+                    outputs,
+                    wasm_data,
+                    runner_options,
+                )?,
+            )),
+            false => Ok(Arc::new(
+                WasmScalarUdfRunner::new_from_raw_with_outputs(
+                    spec.internal_name.clone(),
+                    input_types,
+                    outputs,
+                    wasm_data,
+                    runner_options,
+                )?,
+            )),
+        }
+    }
+
+    pub fn load_aggregate_udf_runner(
+        &self,
+        spec: &WasmAggregateUdfOptions,
+        wasm_data: &[u8],
+    ) -> Result<Arc<WasmAggregateUdfRunner>, WasmError> {
+        let input_types = spec_input_types(&spec.input_types)?;
+        let output_type = udf_type_to_arrow_type(&spec.output_type)?;
+        Ok(Arc::new(WasmAggregateUdfRunner::new_from_raw_with_options(
+            spec.internal_name.clone(),
+            input_types,
+            output_type,
+            wasm_data,
+            self.runner_options.clone(),
+        )?))
+    }
+
+    pub fn load_table_udf_runner(
+        &self,
+        spec: &WasmTableUdfOptions,
+        wasm_data: &[u8],
+    ) -> Result<Arc<WasmTableUdfRunner>, WasmError> {
+        let input_types = spec_input_types(&spec.input_types)?;
+        let outputs = spec_outputs(&spec.export_name, &spec.output_types, &spec.output_names)?;
+        let runner_options = WasmRunnerOptions {
+            null_handling: spec.null_handling,
+            ..self.runner_options.clone()
+        };
+        Ok(Arc::new(WasmTableUdfRunner::new_from_raw_with_options(
+            spec.internal_name.clone(),
+            input_types,
+            outputs,
+            wasm_data,
+            runner_options,
+        )?))
+    }
+
+    pub fn load_window_udf_runner(
+        &self,
+        spec: &WasmWindowUdfOptions,
+        wasm_data: &[u8],
+    ) -> Result<Arc<WasmWindowUdfRunner>, WasmError> {
+        let input_types = spec_input_types(&spec.input_types)?;
+        let output_type = udf_type_to_arrow_type(&spec.output_type)?;
+        let runner_options = WasmRunnerOptions {
+            null_handling: spec.null_handling,
+            ..self.runner_options.clone()
+        };
+        Ok(Arc::new(WasmWindowUdfRunner::new_from_raw_with_options(
+            spec.internal_name.clone(),
+            input_types,
+            output_type,
+            spec.frame.clone(),
+            wasm_data,
+            runner_options,
+        )?))
+    }
+}
+
+fn spec_input_types(input_types: &[String]) -> Result<Vec<DataType>, WasmError> {
+    input_types
+        .iter()
+        .map(|t| udf_type_to_arrow_type(t))
+        .collect()
+}
+
+/// Pairs declared output types with their names; a single output may be left unnamed.
+fn spec_outputs(
+    export_name: &str,
+    output_types: &[String],
+    output_names: &[String],
+) -> Result<Vec<Field>, WasmError> {
+    let output_names = match output_names.len() {
+        0 if output_types.len() == 1 => vec![String::new()],
+        n if n == output_types.len() => output_names.to_vec(),
+        _ => {
+            return Err(WasmError::InvalidInput {
+                msg: format!(
+                    "udf `{}` declares {} output types but {} output names",
+                    export_name,
+                    output_types.len(),
+                    output_names.len()
+                ),
+            })
+        }
+    };
+    output_names
+        .into_iter()
+        .zip(output_types)
+        .map(|(name, t)| Ok(Field::new(name, udf_type_to_arrow_type(t)?, true)))
+        .collect()
 }
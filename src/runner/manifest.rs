@@ -0,0 +1,301 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use wasmparser::{Parser, Payload};
+use wasmtime::{Config, Engine, Store, ValType};
+
+use crate::errors::WasmError;
+use crate::runner::binds::wasm_ops::{
+    get_guest_memory, read_guest_buffer, with_guest_buffers, GuestExports,
+};
+use crate::runner::fuel::FuelBudget;
+use crate::runner::instance_pool::InstancePool;
+use crate::runner::loader::{
+    WasmAggregateUdfOptions, WasmScalarUdfOptions, WasmTableUdfOptions, WasmWindowUdfOptions,
+};
+use crate::runner::module_cache::ModuleCache;
+use crate::runner::options::{NullHandling, WasmRunnerOptions};
+use crate::runner::wasi::WasiPolicy;
+use crate::runner::window_udf_runner::WindowFrame;
+
+/// Name of the custom section holding the JSON manifest of a module.
+pub const MANIFEST_SECTION: &str = "cellforce_manifest";
+
+/// Name of the `() -> i64` export returning the JSON manifest of a module as a packed
+/// `(len << 32) | ptr` buffer, for modules without a manifest section.
+pub const MANIFEST_EXPORT: &str = "cellforce_manifest";
+
+/// The UDFs a module declares.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct UdfManifest {
+    pub udfs: Vec<UdfDeclaration>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UdfKind {
+    Scalar,
+    Aggregate,
+    Table,
+    Window,
+}
+
+impl UdfKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UdfKind::Scalar => "scalar",
+            UdfKind::Aggregate => "aggregate",
+            UdfKind::Table => "table",
+            UdfKind::Window => "window",
+        }
+    }
+}
+
+/// How arguments and results cross into the guest.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UdfAbi {
+    /// One call per row, with scalar and packed buffer arguments.
+    #[default]
+    Row,
+    /// One call per batch, exchanging Arrow IPC streams. Scalar UDFs only.
+    Arrow,
+}
+
+/// One UDF of a manifest. Types are the names accepted by
+/// [`udf_type_to_arrow_type`](crate::runner::datatypes::udf_type_to_arrow_type).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct UdfDeclaration {
+    /// Name the UDF is registered under.
+    pub name: String,
+    /// Guest export implementing the UDF, or prefix of the exports of an aggregate;
+    /// defaults to `name`.
+    #[serde(default)]
+    pub export: Option<String>,
+    pub kind: UdfKind,
+    pub input_types: Vec<String>,
+    /// A single type for aggregate and window UDFs.
+    pub output_types: Vec<String>,
+    #[serde(default)]
+    pub output_names: Vec<String>,
+    #[serde(default)]
+    pub abi: UdfAbi,
+    #[serde(default)]
+    pub null_handling: NullHandling,
+    /// Whether the UDF returns the same results for the same arguments; the runners do not
+    /// rely on it, it is reported for planners.
+    #[serde(default = "deterministic_by_default")]
+    pub deterministic: bool,
+    /// Frame of a window UDF, `WindowFrame::default()` when omitted.
+    #[serde(default)]
+    pub frame: Option<WindowFrame>,
+}
+
+fn deterministic_by_default() -> bool {
+    true
+}
+
+impl UdfManifest {
+    pub fn from_json(json: &[u8]) -> Result<Self, WasmError> {
+        let manifest: Self = serde_json::from_slice(json)
+            .map_err(|e| WasmError::InvalidManifest { msg: e.to_string() })?;
+        manifest.validate()?;
+        Ok(manifest)
+    }
+
+    fn validate(&self) -> Result<(), WasmError> {
+        let mut names = HashSet::new();
+        for udf in &self.udfs {
+            if !names.insert(udf.name.as_str()) {
+                return Err(udf.invalid("is declared twice"));
+            }
+            let single_output = matches!(udf.kind, UdfKind::Aggregate | UdfKind::Window);
+            if single_output && udf.output_types.len() != 1 {
+                return Err(udf.invalid("must declare exactly one output type"));
+            }
+            if udf.abi == UdfAbi::Arrow && udf.kind != UdfKind::Scalar {
+                return Err(udf.invalid("uses the arrow abi, which only scalar udfs support"));
+            }
+            if udf.frame.is_some() && udf.kind != UdfKind::Window {
+                return Err(udf.invalid("declares a frame but is not a window udf"));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl UdfDeclaration {
+    /// Guest export implementing the UDF.
+    pub fn export(&self) -> &str {
+        self.export.as_deref().unwrap_or(&self.name)
+    }
+
+    pub fn scalar_options(&self) -> Result<WasmScalarUdfOptions, WasmError> {
+        self.expect_kind(UdfKind::Scalar)?;
+        Ok(WasmScalarUdfOptions {
+            export_name: self.name.clone(),
+            internal_name: self.export().to_string(),
+            input_types: self.input_types.clone(),
+            output_types: self.output_types.clone(),
+            output_names: self.output_names.clone(),
+            arrow: self.abi == UdfAbi::Arrow,
+            null_handling: self.null_handling,
+        })
+    }
+
+    pub fn aggregate_options(&self) -> Result<WasmAggregateUdfOptions, WasmError> {
+        self.expect_kind(UdfKind::Aggregate)?;
+        Ok(WasmAggregateUdfOptions {
+            export_name: self.name.clone(),
+            internal_name: self.export().to_string(),
+            input_types: self.input_types.clone(),
+            output_type: self.single_output_type()?,
+        })
+    }
+
+    pub fn table_options(&self) -> Result<WasmTableUdfOptions, WasmError> {
+        self.expect_kind(UdfKind::Table)?;
+        Ok(WasmTableUdfOptions {
+            export_name: self.name.clone(),
+            internal_name: self.export().to_string(),
+            input_types: self.input_types.clone(),
+            output_types: self.output_types.clone(),
+            output_names: self.output_names.clone(),
+            null_handling: self.null_handling,
+        })
+    }
+
+    pub fn window_options(&self) -> Result<WasmWindowUdfOptions, WasmError> {
+        self.expect_kind(UdfKind::Window)?;
+        Ok(WasmWindowUdfOptions {
+            export_name: self.name.clone(),
+            internal_name: self.export().to_string(),
+            input_types: self.input_types.clone(),
+            output_type: self.single_output_type()?,
+            frame: self.frame.clone().unwrap_or_default(),
+            null_handling: self.null_handling,
+        })
+    }
+
+    fn expect_kind(&self, kind: UdfKind) -> Result<(), WasmError> {
+        match self.kind == kind {
+            true => Ok(()),
+            false => Err(self.invalid(&format!("is not a {} udf", kind.as_str()))),
+        }
+    }
+
+    fn single_output_type(&self) -> Result<String, WasmError> {
+        match self.output_types.as_slice() {
+            [output_type] => Ok(output_type.clone()),
+            _ => Err(self.invalid("must declare exactly one output type")),
+        }
+    }
+
+    fn invalid(&self, msg: &str) -> WasmError {
+        WasmError::InvalidManifest {
+            msg: format!("udf `{}` {}", self.name, msg),
+        }
+    }
+}
+
+/// Fuel and wall-clock budget of the manifest export, which only has to return a buffer.
+const MANIFEST_FUEL: u64 = 10_000_000;
+const MANIFEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Reads the manifest of `wasm_data` from its manifest section or, failing that, by calling
+/// its manifest export in a fresh instance. The export runs before any UDF of the module is
+/// trusted, so it gets an empty WASI context and a small fuel and time budget instead of the
+/// loader's settings.
+///
+/// The export runs on the engine of the loader's module cache when that engine is
+/// synchronous and meters fuel, so that the UDFs loaded afterwards reuse the compiled module.
+/// Other engines cannot bound the export: it then runs on a fuel-metering engine of its own,
+/// whose module is dropped along with it.
+pub(crate) fn read_manifest(
+    wasm_data: &[u8],
+    options: &WasmRunnerOptions,
+) -> Result<UdfManifest, WasmError> {
+    if let Some(json) = custom_section(wasm_data, MANIFEST_SECTION) {
+        return UdfManifest::from_json(json);
+    }
+    let cache = options.module_cache()?;
+    if cache.engine().detect_precompiled(wasm_data).is_some() {
+        return Err(WasmError::InvalidManifest {
+            msg: format!(
+                "precompiled artifacts have no `{}` custom section, read the manifest from \
+                 the wasm module instead",
+                MANIFEST_SECTION
+            ),
+        });
+    }
+    let meters_fuel = Store::new(cache.engine(), ()).get_fuel().is_ok();
+    let (cache, timeout) = match !cache.engine().is_async() && meters_fuel {
+        // a timeout in the options means the engine has epoch interruption
+        true => (cache, options.timeout.map(|t| t.min(MANIFEST_TIMEOUT))),
+        false => (sandbox_cache(options)?, Some(MANIFEST_TIMEOUT)),
+    };
+    let options = WasmRunnerOptions {
+        async_support: false,
+        instance_pool: None,
+        check_leaks: false,
+        wasi: WasiPolicy::default(),
+        fuel: Some(FuelBudget::PerBatch(MANIFEST_FUEL)),
+        timeout,
+        module_cache: Some(cache),
+        ..options.clone()
+    };
+    let (engine, module) = options.compile(wasm_data)?;
+    if module.get_export(MANIFEST_EXPORT).is_none() {
+        return Err(WasmError::InvalidManifest {
+            msg: format!(
+                "module has neither a `{}` custom section nor a `{}` export",
+                MANIFEST_SECTION, MANIFEST_EXPORT
+            ),
+        });
+    }
+    GuestExports::resolve(&module, MANIFEST_EXPORT, &[], &[ValType::I64])?;
+    let pool = InstancePool::new(engine, &module, MANIFEST_EXPORT, &options)?;
+    let json = pool.with_instance(|pooled| {
+        pooled.begin_batch(1);
+        let instance = pooled.instance;
+        let func = instance
+            .get_typed_func::<(), i64>(&mut pooled.store, MANIFEST_EXPORT)
+            .map_err(|e| WasmError::from_guest_call(MANIFEST_EXPORT, e))?;
+        let memory = get_guest_memory(instance, &mut pooled.store)?;
+        with_guest_buffers(instance, &mut pooled.store, |store, buffers| {
+            let packed = func
+                .call(&mut *store, ())
+                .map_err(|e| WasmError::from_guest_call(MANIFEST_EXPORT, e))?;
+            buffers.push(packed);
+            read_guest_buffer(store, memory, MANIFEST_EXPORT, packed)
+        })
+    })?;
+    UdfManifest::from_json(&json)
+}
+
+/// A cache of its own, on an engine metering fuel and time, for manifest exports that the
+/// loader's engine cannot bound.
+fn sandbox_cache(options: &WasmRunnerOptions) -> Result<ModuleCache, WasmError> {
+    let mut config = Config::new();
+    options.engine.apply(&mut config);
+    config.consume_fuel(true).epoch_interruption(true);
+    let engine = Engine::new(&config).map_err(|e| WasmError::ModuleCompile {
+        msg: format!("{:#}", e),
+    })?;
+    Ok(ModuleCache::new(engine))
+}
+
+/// Returns the contents of the first custom section named `name` of a wasm module, `None`
+/// when there is none or `wasm_data` is not a well-formed module.
+fn custom_section<'a>(wasm_data: &'a [u8], name: &str) -> Option<&'a [u8]> {
+    for payload in Parser::new(0).parse_all(wasm_data) {
+        match payload.ok()? {
+            Payload::CustomSection(section) if section.name() == name => {
+                return Some(section.data());
+            }
+            _ => {}
+        }
+    }
+    None
+}
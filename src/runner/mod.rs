@@ -1,5 +1,23 @@
+
 pub mod runner_base;
 pub mod loader;
 pub mod datatypes;
 pub mod scalar_udf_runner;
-pub mod binds;
\ No newline at end of file
+pub mod aggregate_udf_runner;
+pub mod table_udf_runner;
+pub mod window_udf_runner;
+pub mod binds;
+pub mod instance_pool;
+pub mod options;
+pub mod ipc;
+pub mod host;
+pub mod stream;
+pub mod fuel;
+pub mod epoch;
+pub mod limits;
+pub mod wasi;
+pub mod guest_output;
+pub mod module_cache;
+pub mod engine;
+pub mod manifest;
+pub(crate) mod row_abi;
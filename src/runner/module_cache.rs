@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use sha2::{Digest, Sha256};
+use wasmtime::{Engine, Module};
+
+use crate::errors::WasmError;
+
+/// Modules compiled for one engine, keyed by the SHA-256 of their bytes, so that runners
+/// created from the same wasm share a single compilation. Clones share the cache.
+///
+/// With an artifact directory, compiled modules are also stored on disk as precompiled
+/// artifacts, which later processes load instead of compiling again. Precompiled artifacts
+/// are native code, so outside that directory they are only loaded through the unsafe
+/// [`ModuleCache::deserialize_trusted`].
+#[derive(Clone)]
+pub struct ModuleCache {
+    engine: Engine,
+    artifact_dir: Option<PathBuf>,
+    modules: Arc<Mutex<HashMap<[u8; 32], Module>>>,
+}
+
+impl ModuleCache {
+    pub fn new(engine: Engine) -> Self {
+        Self {
+            engine,
+            artifact_dir: None,
+            modules: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Keeps precompiled artifacts in `dir`, created on first write. An artifact is named
+    /// after the hash of the wasm bytes and of the engine configuration, and is checked
+    /// against its `.sha256` digest before use; a missing, corrupt, stale or incompatible
+    /// artifact is rebuilt from the wasm bytes. The digest only detects corruption: the
+    /// directory must be writable by trusted processes alone.
+    pub fn with_artifact_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.artifact_dir = Some(dir.into());
+        self
+    }
+
+    pub fn engine(&self) -> &Engine {
+        &self.engine
+    }
+
+    pub fn artifact_dir(&self) -> Option<&Path> {
+        self.artifact_dir.as_deref()
+    }
+
+    /// Returns the module compiled from `wasm_data`, compiling it on first use.
+    ///
+    /// Precompiled artifacts are rejected with `WasmError::InvalidInput`, unless they were
+    /// loaded into this cache with [`ModuleCache::deserialize_trusted`] before.
+    pub fn compile(&self, wasm_data: &[u8]) -> Result<Module, WasmError> {
+        let key: [u8; 32] = Sha256::digest(wasm_data).into();
+        if let Some(module) = self.lock()?.get(&key) {
+            return Ok(module.clone());
+        }
+        if self.engine.detect_precompiled(wasm_data).is_some() {
+            return Err(WasmError::InvalidInput {
+                msg: "the wasm bytes are a precompiled artifact, which must be loaded with \
+                      `deserialize_trusted` first"
+                    .to_string(),
+            });
+        }
+        // compile without holding the lock; a concurrent compilation of the same bytes
+        // merely wastes work
+        let module = match &self.artifact_dir {
+            Some(dir) => self.load_or_compile(dir, &key, wasm_data)?,
+            None => self.compile_source(wasm_data)?,
+        };
+        Ok(self.lock()?.entry(key).or_insert(module).clone())
+    }
+
+    /// Loads an artifact produced by [`ModuleCache::precompile`] of a cache whose engine has
+    /// the same configuration, and caches it under its bytes, so that [`ModuleCache::compile`]
+    /// of the same bytes returns it from then on.
+    ///
+    /// # Safety
+    ///
+    /// The artifact is loaded as native code without validation: it must come from a trusted
+    /// source, as with [`Module::deserialize`].
+    pub unsafe fn deserialize_trusted(&self, artifact: &[u8]) -> Result<Module, WasmError> {
+        let key: [u8; 32] = Sha256::digest(artifact).into();
+        if let Some(module) = self.lock()?.get(&key) {
+            return Ok(module.clone());
+        }
+        // SAFETY: forwarded to the caller
+        let module = unsafe { self.deserialize(artifact) }?;
+        Ok(self.lock()?.entry(key).or_insert(module).clone())
+    }
+
+    /// Compiles `wasm_data` into an artifact that [`ModuleCache::deserialize_trusted`] of a
+    /// cache whose engine has the same configuration loads without compiling.
+    pub fn precompile(&self, wasm_data: &[u8]) -> Result<Vec<u8>, WasmError> {
+        self.compile(wasm_data)?
+            .serialize()
+            .map_err(|e| WasmError::ModuleCompile {
+                msg: format!("{:#}", e),
+            })
+    }
+
+    /// Number of distinct modules compiled so far.
+    pub fn len(&self) -> usize {
+        self.modules.lock().map(|modules| modules.len()).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forgets the compiled modules. Runners keep the modules they were created with, and
+    /// artifacts on disk are left in place.
+    pub fn clear(&self) {
+        if let Ok(mut modules) = self.modules.lock() {
+            modules.clear();
+        }
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, HashMap<[u8; 32], Module>>, WasmError> {
+        self.modules
+            .lock()
+            .map_err(|_| WasmError::from("module cache is poisoned"))
+    }
+
+    fn compile_source(&self, wasm_data: &[u8]) -> Result<Module, WasmError> {
+        Module::from_binary(&self.engine, wasm_data).map_err(|e| WasmError::ModuleCompile {
+            msg: format!("{:#}", e),
+        })
+    }
+
+    /// # Safety
+    ///
+    /// `artifact` must come from a trusted source; wasmtime only rejects artifacts of other
+    /// engines.
+    unsafe fn deserialize(&self, artifact: &[u8]) -> Result<Module, WasmError> {
+        unsafe { Module::deserialize(&self.engine, artifact) }.map_err(|e| {
+            WasmError::ModuleCompile {
+                msg: format!("incompatible precompiled module: {:#}", e),
+            }
+        })
+    }
+
+    fn load_or_compile(
+        &self,
+        dir: &Path,
+        key: &[u8; 32],
+        wasm_data: &[u8],
+    ) -> Result<Module, WasmError> {
+        let mut engine_hash = Sha256Hasher(Sha256::new());
+        self.engine.precompile_compatibility_hash().hash(&mut engine_hash);
+        let name = format!("{}-{}", hex(key), hex(&engine_hash.0.finalize()));
+        let artifact_path = dir.join(format!("{}.cwasm", name));
+        let digest_path = dir.join(format!("{}.sha256", name));
+        match self.load_artifact(&artifact_path, &digest_path) {
+            Ok(Some(module)) => return Ok(module),
+            Ok(None) => {}
+            Err(e) => tracing::warn!(
+                path = %artifact_path.display(),
+                "recompiling module, cannot use its precompiled artifact: {}",
+                e
+            ),
+        }
+        let module = self.compile_source(wasm_data)?;
+        if let Err(e) = store_artifact(&module, &artifact_path, &digest_path) {
+            tracing::warn!(
+                path = %artifact_path.display(),
+                "failed to store precompiled module: {}",
+                e
+            );
+        }
+        Ok(module)
+    }
+
+    /// Loads the artifact at `artifact_path`, `None` if there is none yet.
+    fn load_artifact(
+        &self,
+        artifact_path: &Path,
+        digest_path: &Path,
+    ) -> Result<Option<Module>, WasmError> {
+        let artifact = match std::fs::read(artifact_path) {
+            Ok(artifact) => artifact,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(WasmError::from(e.to_string())),
+        };
+        let digest = std::fs::read_to_string(digest_path).unwrap_or_default();
+        if digest.trim() != hex(&Sha256::digest(&artifact)) {
+            return Err(WasmError::from("digest mismatch"));
+        }
+        // SAFETY: the artifact directory is trusted, see `with_artifact_dir`
+        unsafe { self.deserialize(&artifact) }.map(Some)
+    }
+}
+
+/// Feeds a `Hash` value into SHA-256, for names that stay stable across processes and
+/// toolchains.
+struct Sha256Hasher(Sha256);
+
+impl Hasher for Sha256Hasher {
+    fn finish(&self) -> u64 {
+        unreachable!("read the digest from the inner hasher")
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+}
+
+/// Writes the artifact and its digest, each through a temporary file renamed into place so
+/// that readers never see a partial file.
+fn store_artifact(
+    module: &Module,
+    artifact_path: &Path,
+    digest_path: &Path,
+) -> Result<(), WasmError> {
+    let artifact = module.serialize().map_err(|e| WasmError::ModuleCompile {
+        msg: format!("{:#}", e),
+    })?;
+    if let Some(dir) = artifact_path.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| WasmError::from(e.to_string()))?;
+    }
+    write_atomic(artifact_path, &artifact)?;
+    write_atomic(digest_path, hex(&Sha256::digest(&artifact)).as_bytes())
+}
+
+/// Suffix of the temporary files of `write_atomic`, unique within the process.
+static NEXT_TMP_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Writes `data` to a temporary file renamed to `path`, so that readers never see a partial
+/// file. The temporary name is unique across processes and threads.
+fn write_atomic(path: &Path, data: &[u8]) -> Result<(), WasmError> {
+    let tmp_id = NEXT_TMP_ID.fetch_add(1, Ordering::Relaxed);
+    let tmp_path = path.with_extension(format!("tmp{}-{}", std::process::id(), tmp_id));
+    std::fs::write(&tmp_path, data)
+        .and_then(|()| std::fs::rename(&tmp_path, path))
+        .map_err(|e| {
+            let _ = std::fs::remove_file(&tmp_path);
+            WasmError::from(e.to_string())
+        })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+impl fmt::Debug for ModuleCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ModuleCache")
+            .field("artifact_dir", &self.artifact_dir)
+            .field("modules", &self.len())
+            .finish()
+    }
+}
+
+/// Caches are equal when they are clones of one another.
+impl PartialEq for ModuleCache {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.modules, &other.modules) && self.artifact_dir == other.artifact_dir
+    }
+}
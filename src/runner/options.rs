@@ -0,0 +1,136 @@
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use wasmtime::{Config, Engine, Module};
+
+use crate::errors::WasmError;
+use crate::runner::engine::EngineOptions;
+use crate::runner::fuel::FuelBudget;
+use crate::runner::instance_pool::InstancePoolOptions;
+use crate::runner::limits::ResourceLimits;
+use crate::runner::module_cache::ModuleCache;
+use crate::runner::wasi::WasiPolicy;
+
+/// How the row-at-a-time runner treats null arguments.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NullHandling {
+    /// Produce a null result for any row with a null argument, without calling the guest.
+    #[default]
+    ReturnNullOnNullInput,
+    /// Call the guest for every row, passing an `i32` null flag after each argument.
+    CalledOnNullInput,
+}
+
+/// Execution settings shared by the runners.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WasmRunnerOptions {
+    /// Reuse warm instances across `run()` calls instead of instantiating the module for
+    /// every batch.
+    pub instance_pool: Option<InstancePoolOptions>,
+    pub null_handling: NullHandling,
+    /// Build the engine of runners compiled from raw wasm with async support, for use
+    /// through `AsyncWasmUdfRunner::run_async`. Fuel metering is enabled so that guests
+    /// yield to the executor every `ASYNC_YIELD_FUEL` units.
+    pub async_support: bool,
+    /// Bounds the fuel a guest may burn. Runners compiled from raw wasm enable fuel metering
+    /// on their engine when set; engines passed in must have `Config::consume_fuel` on.
+    pub fuel: Option<FuelBudget>,
+    /// Wall-clock budget of every batch, enforced through epoch interruption: a guest still
+    /// running when it expires fails with `WasmError::Timeout`. Runners compiled from raw
+    /// wasm enable epoch interruption on their engine when set; engines passed in must have
+    /// `Config::epoch_interruption` on.
+    pub timeout: Option<Duration>,
+    /// Caps on guest memory and tables; exceeding one fails the call with
+    /// `WasmError::OutOfMemory`.
+    pub limits: Option<ResourceLimits>,
+    /// WASI access granted to the guest, none by default.
+    pub wasi: WasiPolicy,
+    /// Fail runs that leave the guest heap larger than they found it with
+    /// `WasmError::MemoryLeak`. The guest must report its heap usage through a
+    /// `wasm_heap_used() -> i32` export. Meant for tests of guests and of the runners.
+    pub check_leaks: bool,
+    /// Engine and compiled modules for runners created from raw wasm bytes. By default they
+    /// share a process-wide cache per combination of `async_support`, `fuel`, `timeout` and
+    /// `engine`, so that loading several UDFs from one module compiles it once; see
+    /// [`WasmRunnerOptions::clear_shared_caches`] to release them. A cache passed in must
+    /// have an engine with the features these options need.
+    pub module_cache: Option<ModuleCache>,
+    /// Directory in which the shared caches keep precompiled artifacts, so that other
+    /// processes load modules instead of compiling them; see
+    /// [`ModuleCache::with_artifact_dir`]. Ignored when `module_cache` is set.
+    pub artifact_dir: Option<PathBuf>,
+    /// Compiler, wasm features and instance allocation of the engines built for runners
+    /// created from raw wasm bytes. Ignored when `module_cache` is set.
+    pub engine: EngineOptions,
+}
+
+/// Engine features and artifact directory of a shared cache.
+#[derive(Clone, PartialEq, Eq)]
+struct SharedCacheKey {
+    async_support: bool,
+    consume_fuel: bool,
+    epoch_interruption: bool,
+    artifact_dir: Option<PathBuf>,
+    engine: EngineOptions,
+}
+
+/// Process-wide caches, one per combination of engine features and artifact directory,
+/// kept until [`WasmRunnerOptions::clear_shared_caches`].
+static SHARED_CACHES: Mutex<Vec<(SharedCacheKey, ModuleCache)>> = Mutex::new(Vec::new());
+
+impl WasmRunnerOptions {
+    /// Compiles `wasm_data` for runners created from raw wasm bytes, through the module
+    /// cache.
+    pub(crate) fn compile(&self, wasm_data: &[u8]) -> Result<(Engine, Module), WasmError> {
+        let cache = self.module_cache()?;
+        Ok((cache.engine().clone(), cache.compile(wasm_data)?))
+    }
+
+    /// Cache through which runners created from raw wasm bytes compile their module: the
+    /// one passed in, or else the shared cache of these options, created on first use.
+    pub fn module_cache(&self) -> Result<ModuleCache, WasmError> {
+        if let Some(cache) = &self.module_cache {
+            return Ok(cache.clone());
+        }
+        let key = SharedCacheKey {
+            async_support: self.async_support,
+            consume_fuel: self.async_support || self.fuel.is_some(),
+            epoch_interruption: self.timeout.is_some(),
+            artifact_dir: self.artifact_dir.clone(),
+            engine: self.engine.clone(),
+        };
+        let mut caches = SHARED_CACHES
+            .lock()
+            .map_err(|_| WasmError::from("module cache registry is poisoned"))?;
+        if let Some((_, cache)) = caches.iter().find(|(cached, _)| *cached == key) {
+            return Ok(cache.clone());
+        }
+        let mut config = Config::new();
+        key.engine.apply(&mut config);
+        config
+            .async_support(key.async_support)
+            .consume_fuel(key.consume_fuel)
+            .epoch_interruption(key.epoch_interruption);
+        let engine = Engine::new(&config).map_err(|e| WasmError::ModuleCompile {
+            msg: format!("{:#}", e),
+        })?;
+        let cache = match &key.artifact_dir {
+            Some(dir) => ModuleCache::new(engine).with_artifact_dir(dir),
+            None => ModuleCache::new(engine),
+        };
+        caches.push((key, cache.clone()));
+        Ok(cache)
+    }
+
+    /// Drops the shared caches along with their engines, so that their modules are freed
+    /// once no runner uses them. Later runners get new caches and compile their modules
+    /// again, or load them from the artifact directory.
+    pub fn clear_shared_caches() {
+        if let Ok(mut caches) = SHARED_CACHES.lock() {
+            caches.clear();
+        }
+    }
+}
@@ -0,0 +1,562 @@
+use std::ffi::CString;
+use std::sync::Arc;
+
+use arrow::array::{
+    new_empty_array, new_null_array, Array, ArrayRef, AsArray, BinaryArray, BooleanArray,
+    LargeBinaryArray, LargeStringArray, PrimitiveArray, RecordBatch, StringArray, StringViewArray,
+};
+use arrow::compute::concat;
+use arrow::datatypes::{
+    i256, ArrowPrimitiveType, DataType, Date32Type, Date64Type, Decimal128Type, Decimal256Type,
+    DurationMicrosecondType, DurationMillisecondType, DurationNanosecondType, DurationSecondType,
+    Field, Fields, Float32Type, Float64Type, Int16Type, Int32Type, Int64Type, Int8Type,
+    IntervalDayTime, IntervalDayTimeType, IntervalMonthDayNano, IntervalMonthDayNanoType,
+    IntervalUnit, IntervalYearMonthType, Schema, TimeUnit, TimestampMicrosecondType,
+    TimestampMillisecondType, TimestampNanosecondType, TimestampSecondType, UInt16Type, UInt32Type,
+    UInt64Type, UInt8Type,
+};
+use wasmtime::{AsContextMut, Instance, Memory, Val};
+
+use crate::errors::WasmError;
+use crate::runner::binds::wasm_ops::{
+    read_guest_buffer, write_guest_buffer, write_guest_buffer_async,
+};
+use crate::runner::datatypes::arrow_type_to_wasm_type;
+use crate::runner::ipc::{decode_ipc, encode_ipc};
+use crate::runner::options::NullHandling;
+use crate::runner::runner_base::conform_result_batch;
+
+/// Argument or result of the row-at-a-time ABI, with variable-length values held on the
+/// host rather than in guest memory.
+pub(crate) enum RowValue {
+    Val(Val),
+    /// Bytes exchanged as a packed `(len << 32) | ptr` guest buffer.
+    Buffer(Vec<u8>),
+}
+
+/// Converts the value at `row` of `array` into the argument passed to `func`. Null slots are
+/// passed as the zero value of the ABI type.
+fn row_arg(func: &str, array: &ArrayRef, row: usize) -> Result<RowValue, WasmError> {
+    let data_type = array.data_type();
+    if array.is_null(row) {
+        let wasm_type = arrow_type_to_wasm_type(data_type)?;
+        return Val::default_for_ty(&wasm_type)
+            .map(RowValue::Val)
+            .ok_or_else(|| WasmError::UnsupportedType {
+                data_type: data_type.to_string(),
+            });
+    }
+    let val = match data_type {
+        DataType::Boolean => Val::I32(array.as_boolean().value(row) as i32),
+        DataType::Int8 => Val::I32(array.as_primitive::<Int8Type>().value(row) as i32),
+        DataType::Int16 => Val::I32(array.as_primitive::<Int16Type>().value(row) as i32),
+        DataType::Int32 => Val::I32(array.as_primitive::<Int32Type>().value(row)),
+        DataType::Int64 => Val::I64(array.as_primitive::<Int64Type>().value(row)),
+        DataType::UInt8 => Val::I32(array.as_primitive::<UInt8Type>().value(row) as i32),
+        DataType::UInt16 => Val::I32(array.as_primitive::<UInt16Type>().value(row) as i32),
+        DataType::UInt32 => Val::I32(array.as_primitive::<UInt32Type>().value(row) as i32),
+        DataType::UInt64 => Val::I64(array.as_primitive::<UInt64Type>().value(row) as i64),
+        DataType::Float32 => Val::F32(array.as_primitive::<Float32Type>().value(row).to_bits()),
+        DataType::Float64 => Val::F64(array.as_primitive::<Float64Type>().value(row).to_bits()),
+        DataType::Date32 => Val::I32(array.as_primitive::<Date32Type>().value(row)),
+        DataType::Date64 => Val::I64(array.as_primitive::<Date64Type>().value(row)),
+        DataType::Timestamp(TimeUnit::Second, _) => {
+            Val::I64(array.as_primitive::<TimestampSecondType>().value(row))
+        }
+        DataType::Timestamp(TimeUnit::Millisecond, _) => {
+            Val::I64(array.as_primitive::<TimestampMillisecondType>().value(row))
+        }
+        DataType::Timestamp(TimeUnit::Microsecond, _) => {
+            Val::I64(array.as_primitive::<TimestampMicrosecondType>().value(row))
+        }
+        DataType::Timestamp(TimeUnit::Nanosecond, _) => {
+            Val::I64(array.as_primitive::<TimestampNanosecondType>().value(row))
+        }
+        DataType::Duration(TimeUnit::Second) => {
+            Val::I64(array.as_primitive::<DurationSecondType>().value(row))
+        }
+        DataType::Duration(TimeUnit::Millisecond) => {
+            Val::I64(array.as_primitive::<DurationMillisecondType>().value(row))
+        }
+        DataType::Duration(TimeUnit::Microsecond) => {
+            Val::I64(array.as_primitive::<DurationMicrosecondType>().value(row))
+        }
+        DataType::Duration(TimeUnit::Nanosecond) => {
+            Val::I64(array.as_primitive::<DurationNanosecondType>().value(row))
+        }
+        DataType::Interval(IntervalUnit::YearMonth) => {
+            Val::I32(array.as_primitive::<IntervalYearMonthType>().value(row))
+        }
+        DataType::Interval(IntervalUnit::DayTime) => {
+            let value = array.as_primitive::<IntervalDayTimeType>().value(row);
+            Val::I64(((value.days as i64) << 32) | value.milliseconds as u32 as i64)
+        }
+        DataType::Interval(IntervalUnit::MonthDayNano) => {
+            let value = array.as_primitive::<IntervalMonthDayNanoType>().value(row);
+            let mut bytes = Vec::with_capacity(16);
+            bytes.extend_from_slice(&value.months.to_le_bytes());
+            bytes.extend_from_slice(&value.days.to_le_bytes());
+            bytes.extend_from_slice(&value.nanoseconds.to_le_bytes());
+            return Ok(RowValue::Buffer(bytes));
+        }
+        DataType::Decimal128(_, _) => {
+            let value = array.as_primitive::<Decimal128Type>().value(row);
+            return Ok(RowValue::Buffer(value.to_le_bytes().to_vec()));
+        }
+        DataType::Decimal256(_, _) => {
+            let value = array.as_primitive::<Decimal256Type>().value(row);
+            return Ok(RowValue::Buffer(value.to_le_bytes().to_vec()));
+        }
+        DataType::Utf8 => return encode_str(func, array.as_string::<i32>().value(row)),
+        DataType::LargeUtf8 => return encode_str(func, array.as_string::<i64>().value(row)),
+        DataType::Utf8View => return encode_str(func, array.as_string_view().value(row)),
+        DataType::Binary => {
+            return Ok(RowValue::Buffer(
+                array.as_binary::<i32>().value(row).to_vec(),
+            ))
+        }
+        DataType::LargeBinary => {
+            return Ok(RowValue::Buffer(
+                array.as_binary::<i64>().value(row).to_vec(),
+            ))
+        }
+        DataType::List(_) | DataType::LargeList(_) | DataType::Struct(_) | DataType::Map(_, _) => {
+            let value = RecordBatch::try_new(
+                Arc::new(Schema::new(vec![Field::new("", data_type.clone(), true)])),
+                vec![array.slice(row, 1)],
+            )?;
+            return Ok(RowValue::Buffer(encode_ipc(&value)?));
+        }
+        _ => {
+            return Err(WasmError::UnsupportedType {
+                data_type: data_type.to_string(),
+            })
+        }
+    };
+    Ok(RowValue::Val(val))
+}
+
+fn encode_str(func: &str, value: &str) -> Result<RowValue, WasmError> {
+    let cstring = CString::new(value).map_err(|e| WasmError::InvalidInput {
+        msg: format!("string argument of `{}`: {}", func, e),
+    })?;
+    Ok(RowValue::Buffer(cstring.into_bytes_with_nul()))
+}
+
+/// Converts `row` of `columns` into the arguments passed to `func`, each followed by its
+/// null flag when the UDF is called on null input. Variable-length values are copied into
+/// guest memory, and their buffers are added to `buffers`, to be freed after the call.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn encode_row_args<T>(
+    instance: Instance,
+    mut store: impl AsContextMut<Data = T>,
+    memory: Memory,
+    func: &str,
+    columns: &[ArrayRef],
+    row: usize,
+    null_handling: NullHandling,
+    buffers: &mut Vec<i64>,
+) -> Result<Vec<Val>, WasmError> {
+    let mut input_vals = vec![];
+    for array in columns {
+        input_vals.push(encode_row_arg(
+            instance, &mut store, memory, func, array, row, buffers,
+        )?);
+        if null_handling == NullHandling::CalledOnNullInput {
+            input_vals.push(Val::I32(array.is_null(row) as i32));
+        }
+    }
+    Ok(input_vals)
+}
+
+/// Like [`encode_row_args`], for instances of async engines.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn encode_row_args_async<T: Send>(
+    instance: Instance,
+    mut store: impl AsContextMut<Data = T>,
+    memory: Memory,
+    func: &str,
+    columns: &[ArrayRef],
+    row: usize,
+    null_handling: NullHandling,
+    buffers: &mut Vec<i64>,
+) -> Result<Vec<Val>, WasmError> {
+    let mut input_vals = vec![];
+    for array in columns {
+        input_vals.push(
+            encode_row_arg_async(instance, &mut store, memory, func, array, row, buffers).await?,
+        );
+        if null_handling == NullHandling::CalledOnNullInput {
+            input_vals.push(Val::I32(array.is_null(row) as i32));
+        }
+    }
+    Ok(input_vals)
+}
+
+/// Converts the value at `row` of `array` into the argument passed to `func`, copying
+/// variable-length values into guest memory. The guest buffers are added to `buffers`, to be
+/// freed after the call.
+fn encode_row_arg<T>(
+    instance: Instance,
+    store: impl AsContextMut<Data = T>,
+    memory: Memory,
+    func: &str,
+    array: &ArrayRef,
+    row: usize,
+    buffers: &mut Vec<i64>,
+) -> Result<Val, WasmError> {
+    match row_arg(func, array, row)? {
+        RowValue::Val(val) => Ok(val),
+        RowValue::Buffer(bytes) => {
+            let packed = write_guest_buffer(instance, store, memory, &bytes)?;
+            buffers.push(packed);
+            Ok(Val::I64(packed))
+        }
+    }
+}
+
+/// Like [`encode_row_arg`], for instances of async engines.
+async fn encode_row_arg_async<T: Send>(
+    instance: Instance,
+    store: impl AsContextMut<Data = T>,
+    memory: Memory,
+    func: &str,
+    array: &ArrayRef,
+    row: usize,
+    buffers: &mut Vec<i64>,
+) -> Result<Val, WasmError> {
+    match row_arg(func, array, row)? {
+        RowValue::Val(val) => Ok(val),
+        RowValue::Buffer(bytes) => {
+            let packed = write_guest_buffer_async(instance, store, memory, &bytes).await?;
+            buffers.push(packed);
+            Ok(Val::I64(packed))
+        }
+    }
+}
+
+/// Whether values of `output_type` are returned as packed `(len << 32) | ptr` buffers.
+fn is_buffer_type(output_type: &DataType) -> bool {
+    matches!(
+        output_type,
+        DataType::Interval(IntervalUnit::MonthDayNano)
+            | DataType::Decimal128(_, _)
+            | DataType::Decimal256(_, _)
+            | DataType::Utf8
+            | DataType::LargeUtf8
+            | DataType::Utf8View
+            | DataType::Binary
+            | DataType::LargeBinary
+            | DataType::List(_)
+            | DataType::LargeList(_)
+            | DataType::Struct(_)
+            | DataType::Map(_, _)
+    )
+}
+
+/// Takes the value `func` returned for `output_type`, copying a returned buffer to the host
+/// right away. The buffer is added to `buffers`, to be freed along with the arguments of the
+/// call: the guest may hand back one of its arguments, or reuse their memory for the result,
+/// so the result must be read before any of them is freed.
+pub(crate) fn take_row_result<T>(
+    store: impl AsContextMut<Data = T>,
+    memory: Memory,
+    func: &str,
+    output_type: &DataType,
+    val: Val,
+    buffers: &mut Vec<i64>,
+) -> Result<RowValue, WasmError> {
+    if !is_buffer_type(output_type) {
+        return Ok(RowValue::Val(val));
+    }
+    let packed = val.i64().ok_or_else(|| {
+        WasmError::invalid_result(
+            func,
+            format!("unexpected {:?} for output type {}", val, output_type),
+        )
+    })?;
+    buffers.push(packed);
+    Ok(RowValue::Buffer(read_guest_buffer(
+        store, memory, func, packed,
+    )?))
+}
+
+/// Builds the output column from the values taken by [`take_row_result`], `None` marking a
+/// null row.
+pub(crate) fn decode_row_results(
+    func: &str,
+    output_type: &DataType,
+    result_vals: Vec<Option<RowValue>>,
+) -> Result<ArrayRef, WasmError> {
+    let array: ArrayRef = match output_type {
+        DataType::Boolean => {
+            let values =
+                decode_values(func, output_type, result_vals, |v| v.i32().map(|v| v != 0))?;
+            Arc::new(BooleanArray::from(values))
+        }
+        DataType::Int8 => decode_primitive::<Int8Type>(func, output_type, result_vals, |v| {
+            v.i32().map(|v| v as i8)
+        })?,
+        DataType::Int16 => decode_primitive::<Int16Type>(func, output_type, result_vals, |v| {
+            v.i32().map(|v| v as i16)
+        })?,
+        DataType::Int32 => {
+            decode_primitive::<Int32Type>(func, output_type, result_vals, |v| v.i32())?
+        }
+        DataType::Int64 => {
+            decode_primitive::<Int64Type>(func, output_type, result_vals, |v| v.i64())?
+        }
+        DataType::UInt8 => decode_primitive::<UInt8Type>(func, output_type, result_vals, |v| {
+            v.i32().map(|v| v as u8)
+        })?,
+        DataType::UInt16 => decode_primitive::<UInt16Type>(func, output_type, result_vals, |v| {
+            v.i32().map(|v| v as u16)
+        })?,
+        DataType::UInt32 => decode_primitive::<UInt32Type>(func, output_type, result_vals, |v| {
+            v.i32().map(|v| v as u32)
+        })?,
+        DataType::UInt64 => decode_primitive::<UInt64Type>(func, output_type, result_vals, |v| {
+            v.i64().map(|v| v as u64)
+        })?,
+        DataType::Float32 => {
+            decode_primitive::<Float32Type>(func, output_type, result_vals, |v| v.f32())?
+        }
+        DataType::Float64 => {
+            decode_primitive::<Float64Type>(func, output_type, result_vals, |v| v.f64())?
+        }
+        DataType::Date32 => {
+            decode_primitive::<Date32Type>(func, output_type, result_vals, |v| v.i32())?
+        }
+        DataType::Date64 => {
+            decode_primitive::<Date64Type>(func, output_type, result_vals, |v| v.i64())?
+        }
+        DataType::Timestamp(TimeUnit::Second, _) => {
+            decode_primitive::<TimestampSecondType>(func, output_type, result_vals, |v| v.i64())?
+        }
+        DataType::Timestamp(TimeUnit::Millisecond, _) => {
+            decode_primitive::<TimestampMillisecondType>(func, output_type, result_vals, |v| {
+                v.i64()
+            })?
+        }
+        DataType::Timestamp(TimeUnit::Microsecond, _) => {
+            decode_primitive::<TimestampMicrosecondType>(func, output_type, result_vals, |v| {
+                v.i64()
+            })?
+        }
+        DataType::Timestamp(TimeUnit::Nanosecond, _) => {
+            decode_primitive::<TimestampNanosecondType>(func, output_type, result_vals, |v| {
+                v.i64()
+            })?
+        }
+        DataType::Duration(TimeUnit::Second) => {
+            decode_primitive::<DurationSecondType>(func, output_type, result_vals, |v| v.i64())?
+        }
+        DataType::Duration(TimeUnit::Millisecond) => {
+            decode_primitive::<DurationMillisecondType>(func, output_type, result_vals, |v| {
+                v.i64()
+            })?
+        }
+        DataType::Duration(TimeUnit::Microsecond) => {
+            decode_primitive::<DurationMicrosecondType>(func, output_type, result_vals, |v| {
+                v.i64()
+            })?
+        }
+        DataType::Duration(TimeUnit::Nanosecond) => {
+            decode_primitive::<DurationNanosecondType>(func, output_type, result_vals, |v| v.i64())?
+        }
+        DataType::Interval(IntervalUnit::YearMonth) => {
+            decode_primitive::<IntervalYearMonthType>(func, output_type, result_vals, |v| v.i32())?
+        }
+        DataType::Interval(IntervalUnit::DayTime) => {
+            decode_primitive::<IntervalDayTimeType>(func, output_type, result_vals, |v| {
+                v.i64()
+                    .map(|v| IntervalDayTime::new((v >> 32) as i32, v as i32))
+            })?
+        }
+        DataType::Interval(IntervalUnit::MonthDayNano) => {
+            let values = decode_fixed_buffers::<16>(func, output_type, result_vals)?
+                .into_iter()
+                .map(|bytes| {
+                    bytes.map(|b| {
+                        IntervalMonthDayNano::new(
+                            i32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+                            i32::from_le_bytes([b[4], b[5], b[6], b[7]]),
+                            i64::from_le_bytes([
+                                b[8], b[9], b[10], b[11], b[12], b[13], b[14], b[15],
+                            ]),
+                        )
+                    })
+                });
+            Arc::new(PrimitiveArray::<IntervalMonthDayNanoType>::from_iter(
+                values,
+            ))
+        }
+        DataType::Decimal128(_, _) => {
+            let values = decode_fixed_buffers::<16>(func, output_type, result_vals)?
+                .into_iter()
+                .map(|bytes| bytes.map(i128::from_le_bytes));
+            Arc::new(
+                PrimitiveArray::<Decimal128Type>::from_iter(values)
+                    .with_data_type(output_type.clone()),
+            )
+        }
+        DataType::Decimal256(_, _) => {
+            let values = decode_fixed_buffers::<32>(func, output_type, result_vals)?
+                .into_iter()
+                .map(|bytes| bytes.map(i256::from_le_bytes));
+            Arc::new(
+                PrimitiveArray::<Decimal256Type>::from_iter(values)
+                    .with_data_type(output_type.clone()),
+            )
+        }
+        DataType::Utf8 | DataType::LargeUtf8 | DataType::Utf8View => {
+            let values = decode_buffers(func, output_type, result_vals)?
+                .into_iter()
+                .map(|value| value.map(String::from_utf8).transpose())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| WasmError::invalid_result(func, e.to_string()))?;
+            match output_type {
+                DataType::Utf8 => Arc::new(StringArray::from_iter(values)),
+                DataType::LargeUtf8 => Arc::new(LargeStringArray::from_iter(values)),
+                _ => Arc::new(StringViewArray::from_iter(values)),
+            }
+        }
+        DataType::Binary => {
+            let values = decode_buffers(func, output_type, result_vals)?;
+            Arc::new(BinaryArray::from_iter(values))
+        }
+        DataType::LargeBinary => {
+            let values = decode_buffers(func, output_type, result_vals)?;
+            Arc::new(LargeBinaryArray::from_iter(values))
+        }
+        DataType::List(_) | DataType::LargeList(_) | DataType::Struct(_) | DataType::Map(_, _) => {
+            let values = decode_buffers(func, output_type, result_vals)?;
+            if values.is_empty() {
+                return Ok(new_empty_array(output_type));
+            }
+            let rows = values
+                .into_iter()
+                .map(|bytes| match bytes {
+                    Some(bytes) => decode_nested_value(func, output_type, &bytes),
+                    None => Ok(new_null_array(output_type, 1)),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            concat(&rows.iter().map(|row| row.as_ref()).collect::<Vec<_>>())?
+        }
+        _ => {
+            return Err(WasmError::UnsupportedType {
+                data_type: output_type.to_string(),
+            })
+        }
+    };
+    Ok(array)
+}
+
+/// Decodes a nested value returned as an IPC stream holding a single one-row column.
+fn decode_nested_value(
+    func: &str,
+    output_type: &DataType,
+    bytes: &[u8],
+) -> Result<ArrayRef, WasmError> {
+    let batch = decode_ipc(func, bytes)?;
+    let batch = conform_result_batch(
+        func,
+        &batch,
+        &Fields::from(vec![Field::new("", output_type.clone(), true)]),
+    )?;
+    if batch.num_rows() != 1 {
+        return Err(WasmError::invalid_result(
+            func,
+            format!(
+                "expected a single {} value, got {} rows",
+                output_type,
+                batch.num_rows()
+            ),
+        ));
+    }
+    Ok(batch.column(0).clone())
+}
+
+/// Converts each returned value with `convert`, failing on values of the wrong wasm type.
+fn decode_values<N>(
+    func: &str,
+    output_type: &DataType,
+    result_vals: Vec<Option<RowValue>>,
+    convert: impl Fn(&Val) -> Option<N>,
+) -> Result<Vec<Option<N>>, WasmError> {
+    result_vals
+        .into_iter()
+        .map(|result_val| match result_val {
+            Some(RowValue::Val(val)) => convert(&val).map(Some).ok_or_else(|| {
+                WasmError::invalid_result(
+                    func,
+                    format!("unexpected {:?} for output type {}", val, output_type),
+                )
+            }),
+            Some(RowValue::Buffer(_)) => Err(WasmError::invalid_result(
+                func,
+                format!("unexpected buffer for output type {}", output_type),
+            )),
+            None => Ok(None),
+        })
+        .collect()
+}
+
+fn decode_primitive<P: ArrowPrimitiveType>(
+    func: &str,
+    output_type: &DataType,
+    result_vals: Vec<Option<RowValue>>,
+    convert: impl Fn(&Val) -> Option<P::Native>,
+) -> Result<ArrayRef, WasmError> {
+    let values = decode_values(func, output_type, result_vals, convert)?;
+    Ok(Arc::new(
+        PrimitiveArray::<P>::from_iter(values).with_data_type(output_type.clone()),
+    ))
+}
+
+/// Bytes of the buffers returned by `func`.
+fn decode_buffers(
+    func: &str,
+    output_type: &DataType,
+    result_vals: Vec<Option<RowValue>>,
+) -> Result<Vec<Option<Vec<u8>>>, WasmError> {
+    result_vals
+        .into_iter()
+        .map(|result_val| match result_val {
+            Some(RowValue::Buffer(bytes)) => Ok(Some(bytes)),
+            Some(RowValue::Val(val)) => Err(WasmError::invalid_result(
+                func,
+                format!("unexpected {:?} for output type {}", val, output_type),
+            )),
+            None => Ok(None),
+        })
+        .collect()
+}
+
+/// Like `decode_buffers`, for values whose encoding has a fixed width of `N` bytes.
+fn decode_fixed_buffers<const N: usize>(
+    func: &str,
+    output_type: &DataType,
+    result_vals: Vec<Option<RowValue>>,
+) -> Result<Vec<Option<[u8; N]>>, WasmError> {
+    decode_buffers(func, output_type, result_vals)?
+        .into_iter()
+        .map(|bytes| {
+            bytes
+                .map(|bytes| {
+                    <[u8; N]>::try_from(bytes).map_err(|bytes| {
+                        WasmError::invalid_result(
+                            func,
+                            format!(
+                                "expected {} bytes for output type {}, got {}",
+                                N,
+                                output_type,
+                                bytes.len()
+                            ),
+                        )
+                    })
+                })
+                .transpose()
+        })
+        .collect()
+}
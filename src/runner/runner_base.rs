@@ -1,6 +1,157 @@
-use arrow_array::RecordBatch;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
 use crate::errors::WasmError;
+use crate::runner::datatypes::{cast_to_declared_type, matches_declared_type};
+use crate::runner::fuel::RunStats;
+use crate::runner::stream::{RecordBatchIter, RecordBatchStatsIter};
+use arrow_array::RecordBatch;
+use arrow_schema::{DataType, Field, Fields, Schema};
+use itertools::Itertools;
 
 pub trait WasmUdfRunner {
     fn run(&self, batch: &RecordBatch) -> Result<RecordBatch, WasmError>;
-}
\ No newline at end of file
+
+    /// Like `run`, also reporting the resources the call consumed. Runners that do not
+    /// meter anything report zeroes.
+    fn run_with_stats(&self, batch: &RecordBatch) -> Result<(RecordBatch, RunStats), WasmError> {
+        Ok((self.run(batch)?, RunStats::default()))
+    }
+
+    /// Runs every batch of `batches`, lazily: an input batch is only pulled when the
+    /// corresponding output is requested. Runners that support it keep a single instance
+    /// for the whole stream; by default each batch goes through `run`.
+    fn run_stream<'a>(&'a self, batches: RecordBatchIter<'a>) -> RecordBatchIter<'a> {
+        Box::new(
+            self.run_stream_with_stats(batches)
+                .map(|result| result.map(|(batch, _)| batch)),
+        )
+    }
+
+    /// Like `run_stream`, also reporting with each output batch the resources consumed so
+    /// far. Runners keeping a single instance for the stream report the consumption of the
+    /// whole stream up to that batch, which is what a `PerStream` fuel budget limits; by
+    /// default each batch goes through `run_with_stats` and reports its own.
+    fn run_stream_with_stats<'a>(
+        &'a self,
+        batches: RecordBatchIter<'a>,
+    ) -> RecordBatchStatsIter<'a> {
+        Box::new(batches.map(move |batch| self.run_with_stats(&batch?)))
+    }
+}
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Async counterpart of [`WasmUdfRunner`]. Runners built on an async engine
+/// (`Config::async_support`) call the guest with `call_async`, so a long-running guest
+/// yields to the executor instead of blocking it. Runners on a synchronous engine
+/// return `InvalidInput` from `run_async`, and runners on an async engine from `run`.
+pub trait AsyncWasmUdfRunner: Sync {
+    fn run_async_with_stats<'a>(
+        &'a self,
+        batch: &'a RecordBatch,
+    ) -> BoxFuture<'a, Result<(RecordBatch, RunStats), WasmError>>;
+
+    fn run_async<'a>(
+        &'a self,
+        batch: &'a RecordBatch,
+    ) -> BoxFuture<'a, Result<RecordBatch, WasmError>> {
+        Box::pin(async move { Ok(self.run_async_with_stats(batch).await?.0) })
+    }
+}
+
+/// Checks that the columns of `batch` have the types the UDF was declared with, and relabels
+/// them with those types. As for results, field names inside lists and maps may differ, and a
+/// nested field may be non-nullable where the declared one is nullable.
+pub(crate) fn check_input_types(
+    batch: &RecordBatch,
+    input_types: &[DataType],
+) -> Result<RecordBatch, WasmError> {
+    let schema = batch.schema();
+    let actual_types = schema.fields().iter().map(|f| f.data_type());
+    if schema.fields().len() != input_types.len()
+        || !input_types
+            .iter()
+            .zip(actual_types)
+            .all(|(declared, actual)| matches_declared_type(declared, actual))
+    {
+        return Err(WasmError::InvalidInput {
+            msg: format!(
+                "expected columns of type [{}], got [{}]",
+                input_types.iter().join(", "),
+                schema.fields().iter().map(|f| f.data_type()).join(", ")
+            ),
+        });
+    }
+    if schema.fields().iter().map(|f| f.data_type()).eq(input_types) {
+        return Ok(batch.clone());
+    }
+    let fields = schema
+        .fields()
+        .iter()
+        .zip(input_types)
+        .map(|(field, declared)| field.as_ref().clone().with_data_type(declared.clone()))
+        .collect::<Vec<_>>();
+    let columns = batch
+        .columns()
+        .iter()
+        .zip(input_types)
+        .map(|(array, declared)| cast_to_declared_type(array, declared))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(RecordBatch::try_new(Arc::new(Schema::new(fields)), columns)?)
+}
+
+/// Checks the batch returned by `func` against the declared outputs and relabels its
+/// columns with their names and types.
+pub(crate) fn conform_result_batch(
+    func: &str,
+    batch: &RecordBatch,
+    outputs: &Fields,
+) -> Result<RecordBatch, WasmError> {
+    let schema = batch.schema();
+    let actual_types = schema.fields().iter().map(|f| f.data_type());
+    if schema.fields().len() != outputs.len()
+        || !outputs
+            .iter()
+            .zip(actual_types)
+            .all(|(declared, actual)| matches_declared_type(declared.data_type(), actual))
+    {
+        return Err(WasmError::invalid_result(
+            func,
+            format!(
+                "expected columns of type [{}], got [{}]",
+                outputs.iter().map(|f| f.data_type()).join(", "),
+                schema.fields().iter().map(|f| f.data_type()).join(", ")
+            ),
+        ));
+    }
+    let columns = batch
+        .columns()
+        .iter()
+        .zip(outputs)
+        .map(|(column, output)| cast_to_declared_type(column, output.data_type()))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(RecordBatch::try_new(
+        Arc::new(Schema::new(outputs.clone())),
+        columns,
+    )?)
+}
+
+/// Makes every declared output nullable, rejecting an empty or ambiguous output list.
+pub(crate) fn check_outputs(func: &str, outputs: Vec<Field>) -> Result<Fields, WasmError> {
+    if outputs.is_empty() {
+        return Err(WasmError::InvalidInput {
+            msg: format!("udf `{}` declares no output", func),
+        });
+    }
+    if let Some(name) = outputs.iter().map(|f| f.name()).duplicates().next() {
+        return Err(WasmError::InvalidInput {
+            msg: format!("udf `{}` declares output `{}` more than once", func, name),
+        });
+    }
+    Ok(outputs
+        .into_iter()
+        .map(|f| f.with_nullable(true))
+        .collect())
+}
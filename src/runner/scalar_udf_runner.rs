@@ -1,44 +1,39 @@
-#[macro_use]
-use anyhow;
-use std::cell::RefCell;
-use wasmtime::Module;
-use wasmtime::Store;
-use wasmtime::*;
-use wasmtime::{AsContextMut, TypedFunc};
-use wasmtime::{Caller, Instance};
-use wasmtime::{Engine, Val};
-use wasmtime::{Extern, Func};
-use wasmtime::Linker;
-use wasmtime_wasi::{WasiCtx};
-use wasi_common::sync::WasiCtxBuilder;
-
-use arrow::array::{Float32Array, Int64Array, StringBuilder};
-use std::ffi::CString;
-use std::num::TryFromIntError;
 use std::sync::Arc;
 
-use arrow::array::{
-    Array, ArrayRef, Float64Array, Int32Array, StringArray, StructArray, TimestampSecondArray,
-    UInt64Array,
-};
-use arrow::compute::{cast, concat_batches};
-use arrow::datatypes::{DataType, Field, Schema, SchemaRef, TimeUnit};
-use arrow::error::ArrowError;
-use arrow::ffi::{FFI_ArrowArray, FFI_ArrowSchema};
-use arrow::ipc::reader::StreamReader;
-use arrow::ipc::writer::StreamWriter;
+use wasmtime::{Engine, Func, Instance, Memory, Module, Store, Val, ValType};
+
+use arrow::array::{Array, ArrayRef, AsArray};
+use arrow::compute::{concat_batches, is_null, nullif};
+use arrow::datatypes::{DataType, Field, Fields, Schema};
 use arrow::record_batch::RecordBatch;
-use arrow::util::pretty::print_batches;
 use crate::errors::WasmError;
-use crate::runner::binds::wasm_ops::{wrapper_wasm_allocate};
-use crate::runner::runner_base::WasmUdfRunner;
+use crate::runner::binds::wasm_ops::{
+    get_func, get_guest_memory, read_guest_buffer, with_guest_buffers, with_guest_buffers_async,
+    write_guest_buffer, write_guest_buffer_async, GuestExports,
+};
+use crate::runner::datatypes::arrow_type_to_wasm_type;
+use crate::runner::fuel::RunStats;
+use crate::runner::host::HostState;
+use crate::runner::instance_pool::{InstancePool, PooledInstance};
+use crate::runner::ipc::{decode_ipc, encode_ipc};
+use crate::runner::options::{NullHandling, WasmRunnerOptions};
+use crate::runner::row_abi::{
+    decode_row_results, encode_row_args, encode_row_args_async, take_row_result, RowValue,
+};
+use crate::runner::stream::{
+    RecordBatchIter, RecordBatchStatsIter, StreamHooks, WasmBatchStream,
+};
+use crate::runner::runner_base::{
+    check_input_types, check_outputs, conform_result_batch, AsyncWasmUdfRunner, BoxFuture,
+    WasmUdfRunner,
+};
 
 pub struct WasmArrowScalarUdfRunner {
-    engine: Engine,
-    module: Module,
-    func: String,
+    pool: InstancePool,
+    exports: GuestExports,
+    hooks: StreamHooks,
     input_types: Vec<DataType>,
-    output_type: DataType,
+    outputs: Fields,
 }
 
 impl WasmArrowScalarUdfRunner {
@@ -49,12 +44,54 @@ impl WasmArrowScalarUdfRunner {
         input_types: Vec<DataType>,
         output_type: DataType,
     ) -> Result<Self, WasmError> {
-        Ok(Self {
+        Self::new_with_options(
             engine,
             module,
             func,
             input_types,
             output_type,
+            WasmRunnerOptions::default(),
+        )
+    }
+
+    pub fn new_with_options(
+        engine: Engine,
+        module: Module,
+        func: String,
+        input_types: Vec<DataType>,
+        output_type: DataType,
+        options: WasmRunnerOptions,
+    ) -> Result<Self, WasmError> {
+        Self::new_with_outputs(
+            engine,
+            module,
+            func,
+            input_types,
+            vec![Field::new("", output_type, true)],
+            options,
+        )
+    }
+
+    /// Creates a runner for a UDF returning one column per entry of `outputs`; the guest
+    /// returns them as a single IPC batch with that many columns.
+    pub fn new_with_outputs(
+        engine: Engine,
+        module: Module,
+        func: String,
+        input_types: Vec<DataType>,
+        outputs: Vec<Field>,
+        options: WasmRunnerOptions,
+    ) -> Result<Self, WasmError> {
+        let outputs = check_outputs(&func, outputs)?;
+        // every argument and the result travel as a packed `(len << 32) | ptr` IPC buffer
+        let params = vec![ValType::I64; input_types.len()];
+        let exports = GuestExports::resolve(&module, &func, &params, &[ValType::I64])?;
+        Ok(Self {
+            pool: InstancePool::new(engine, &module, &func, &options)?,
+            exports,
+            hooks: StreamHooks::resolve(&module, &func)?,
+            input_types,
+            outputs,
         })
     }
 
@@ -64,134 +101,199 @@ impl WasmArrowScalarUdfRunner {
         output_type: DataType,
         wasm_data: &[u8],
     ) -> Result<Self, WasmError> {
-        let engine = Engine::default();
-        let module = Module::from_binary(&engine, wasm_data).unwrap();
-        Ok(Self {
-            engine,
-            module,
+        Self::new_from_raw_with_options(
             func,
             input_types,
             output_type,
+            wasm_data,
+            WasmRunnerOptions::default(),
+        )
+    }
+
+    pub fn new_from_raw_with_options(
+        func: String,
+        input_types: Vec<DataType>,
+        output_type: DataType,
+        wasm_data: &[u8],
+        options: WasmRunnerOptions,
+    ) -> Result<Self, WasmError> {
+        Self::new_from_raw_with_outputs(
+            func,
+            input_types,
+            vec![Field::new("", output_type, true)],
+            wasm_data,
+            options,
+        )
+    }
+
+    pub fn new_from_raw_with_outputs(
+        func: String,
+        input_types: Vec<DataType>,
+        outputs: Vec<Field>,
+        wasm_data: &[u8],
+        options: WasmRunnerOptions,
+    ) -> Result<Self, WasmError> {
+        let (engine, module) = options.compile(wasm_data)?;
+        Self::new_with_outputs(engine, module, func, input_types, outputs, options)
+    }
+
+    pub fn input_types(&self) -> &[DataType] {
+        &self.input_types
+    }
+
+    pub fn outputs(&self) -> &Fields {
+        &self.outputs
+    }
+
+    fn run_on_instance(
+        &self,
+        pooled: &mut PooledInstance,
+        batch: &RecordBatch,
+    ) -> Result<RecordBatch, WasmError> {
+        let batch = &check_input_types(batch, &self.input_types)?;
+        pooled.begin_batch(batch.num_rows());
+        let PooledInstance {
+            store, instance, ..
+        } = pooled;
+        let instance = *instance;
+        let func = &self.exports.func;
+
+        let func_def = get_func(instance, &mut *store, func)?;
+        let memory = get_guest_memory(instance, &mut *store)?;
+
+        let result_arrow_ipc = with_guest_buffers(instance, store, |store, buffers| {
+            self.call_guest(instance, store, memory, func_def, batch, buffers)
+        })?;
+        let result_batch = decode_ipc(func, &result_arrow_ipc)?;
+        conform_result_batch(func, &result_batch, &self.outputs)
+    }
+
+    /// Passes the columns of `batch` to the guest and copies the returned IPC stream to the
+    /// host. The guest buffers of the arguments and of the result are added to `buffers`.
+    fn call_guest(
+        &self,
+        instance: Instance,
+        store: &mut Store<HostState>,
+        memory: Memory,
+        func_def: Func,
+        batch: &RecordBatch,
+        buffers: &mut Vec<i64>,
+    ) -> Result<Vec<u8>, WasmError> {
+        let func = &self.exports.func;
+        for serialized_data in encode_columns(batch)? {
+            buffers.push(write_guest_buffer(instance, &mut *store, memory, &serialized_data)?);
+        }
+        let input_vals = buffers.iter().map(|&ptr| Val::I64(ptr)).collect::<Vec<_>>();
+
+        let mut tmp_result_vals = vec![Val::I64(0)];
+        func_def
+            .call(&mut *store, input_vals.as_slice(), &mut tmp_result_vals)
+            .map_err(|e| WasmError::from_guest_call(func, e))?;
+
+        let result_ptr = result_buffer(func, &tmp_result_vals)?;
+        buffers.push(result_ptr);
+        read_guest_buffer(&mut *store, memory, func, result_ptr)
+    }
+
+    /// Like `run_on_instance`, for instances of async engines.
+    async fn run_on_instance_async(
+        &self,
+        pooled: &mut PooledInstance,
+        batch: &RecordBatch,
+    ) -> Result<RecordBatch, WasmError> {
+        let batch = &check_input_types(batch, &self.input_types)?;
+        pooled.begin_batch(batch.num_rows());
+        let PooledInstance {
+            store, instance, ..
+        } = pooled;
+        let instance = *instance;
+        let func = &self.exports.func;
+
+        let func_def = get_func(instance, &mut *store, func)?;
+        let memory = get_guest_memory(instance, &mut *store)?;
+
+        let result_arrow_ipc = with_guest_buffers_async(instance, store, async |store, buffers| {
+            self.call_guest_async(instance, store, memory, func_def, batch, buffers)
+                .await
         })
+        .await?;
+        let result_batch = decode_ipc(func, &result_arrow_ipc)?;
+        conform_result_batch(func, &result_batch, &self.outputs)
     }
-}
 
-impl WasmUdfRunner for WasmArrowScalarUdfRunner {
-    fn run(&self, batch: &RecordBatch) -> Result<RecordBatch, WasmError> {
-        let mut linker = Linker::new(&self.engine);
-        wasi_common::sync::add_to_linker(&mut linker, |s| s).unwrap();
-        let wasi = WasiCtxBuilder::new()
-            .inherit_stdio()
-            .inherit_args().unwrap()
-            .build();
-        let mut store = Store::new(&self.engine, wasi);
-
-
-        linker.module(&mut store, "", &self.module).unwrap();
-        let instance: Instance = linker
-            .instantiate(&mut store, &self.module).unwrap();
-
-        let func_def = instance
-            .get_func(&mut store, &self.func)
-            .expect(format!("`{}` was not an exported function", &self.func).as_str());
-
-        let memory = instance
-            .get_memory(&mut store, "memory")
-            .ok_or(WasmError::GeneralError { msg: format!(
-                "failed to find `memory` export"
-            )}).unwrap();
-
-        let schema = batch.schema();
-        let mut input_vals = vec![];
-        for i in 0..batch.num_columns() {
-            let field = schema.field(i);
-            let array = batch.column(i);
-            let col_batch = RecordBatch::try_new(
-                Arc::new(Schema::new(vec![field.clone()])),
-                vec![array.clone()],
-            )
-            .unwrap();
-
-            let buffer: Vec<u8> = Vec::new();
-            let mut stream_writer = StreamWriter::try_new(buffer, &col_batch.schema()).unwrap();
-            stream_writer
-                .write(&col_batch).unwrap();
-            stream_writer.finish().unwrap();
-            let serialized_data = stream_writer
-                .into_inner().unwrap();
-            let serialized_data_size = serialized_data.len();
-
-            let offset_data: u32 =
-                wrapper_wasm_allocate(instance, &mut store, serialized_data_size as u32).unwrap() as u32;
-            memory
-                .write(
-                    &mut store,
-                    offset_data.try_into().unwrap(),
-                    serialized_data.as_slice(),
-                ).unwrap();
-
-            let ptr = ((serialized_data_size as u64) << 32) + offset_data as u64;
-            input_vals.push(Val::I64(ptr as i64));
+    /// Like `call_guest`, for instances of async engines.
+    async fn call_guest_async(
+        &self,
+        instance: Instance,
+        store: &mut Store<HostState>,
+        memory: Memory,
+        func_def: Func,
+        batch: &RecordBatch,
+        buffers: &mut Vec<i64>,
+    ) -> Result<Vec<u8>, WasmError> {
+        let func = &self.exports.func;
+        for serialized_data in encode_columns(batch)? {
+            buffers.push(
+                write_guest_buffer_async(instance, &mut *store, memory, &serialized_data).await?,
+            );
         }
+        let input_vals = buffers.iter().map(|&ptr| Val::I64(ptr)).collect::<Vec<_>>();
 
         let mut tmp_result_vals = vec![Val::I64(0)];
-        let mut_store = &mut store;
         func_def
-            .call(mut_store, input_vals.as_slice(), &mut tmp_result_vals).unwrap();
-        let result_val = match tmp_result_vals.get(0) {
-            Some(v) => v.clone(),
-            None => return Err(WasmError::GeneralError {
-                msg: "hello".to_string()
-            }),
-            _ => return Err(WasmError::GeneralError {
-                msg: "hello".to_string()
-            }),
-        };
+            .call_async(&mut *store, input_vals.as_slice(), &mut tmp_result_vals)
+            .await
+            .map_err(|e| WasmError::from_guest_call(func, e))?;
 
-        if let Val::I64(result_ptr) = result_val {
-            let result_size = (result_ptr >> 32) as u32;
-            let result_offset = (result_ptr & 0xffffffff) as u32;
-
-            if result_size == 0 {
-                return Err(WasmError::GeneralError { msg: format!(
-                    "Error: No valid answer received from function"
-                )});
-            } else {
-                let memory = instance
-                    .get_memory(&mut store, "memory")
-                    .ok_or(anyhow::format_err!("failed to find `memory` export"))
-                    .unwrap();
-
-                let mut result_arrow_ipc: Vec<u8> = vec![0; result_size as usize];
-                let mut result_arrow_ipc_buffer = result_arrow_ipc.as_mut_slice();
-                memory
-                    .read(&store, result_offset as usize, &mut result_arrow_ipc_buffer).unwrap();
-
-                let stream_reader = StreamReader::try_new(result_arrow_ipc.as_slice(), None).unwrap();
-
-                let mut batches = vec![];
-                for item in stream_reader {
-                    batches.push(item.unwrap());
-                }
+        let result_ptr = result_buffer(func, &tmp_result_vals)?;
+        buffers.push(result_ptr);
+        read_guest_buffer(&mut *store, memory, func, result_ptr)
+    }
+}
 
-                let result_batch = concat_batches(&batches[0].schema(), &batches).unwrap();
+impl WasmUdfRunner for WasmArrowScalarUdfRunner {
+    fn run(&self, batch: &RecordBatch) -> Result<RecordBatch, WasmError> {
+        Ok(self.run_with_stats(batch)?.0)
+    }
 
-                return Ok(result_batch);
+    /// A batch that does not fit in the guest's memory is run in halves.
+    fn run_with_stats(&self, batch: &RecordBatch) -> Result<(RecordBatch, RunStats), WasmError> {
+        let result = self.pool.with_instance(|pooled| {
+            let result = self.run_on_instance(pooled, batch)?;
+            Ok((result, pooled.stats()))
+        });
+        match result {
+            Err(WasmError::OutOfMemory { .. }) if batch.num_rows() > 1 => {
+                let (first, second) = split_batch(batch);
+                concat_results(self.run_with_stats(&first)?, self.run_with_stats(&second)?)
             }
-        } else {
-            return Err(WasmError::GeneralError { msg: format!(
-                "Error: No valid answer received from function"
-            )});
+            result => result,
         }
     }
+
+    fn run_stream_with_stats<'a>(
+        &'a self,
+        batches: RecordBatchIter<'a>,
+    ) -> RecordBatchStatsIter<'a> {
+        Box::new(WasmBatchStream::new(
+            &self.pool,
+            &self.hooks,
+            |pooled, batch| self.run_on_instance(pooled, batch),
+            batches,
+        ))
+    }
 }
 
 pub struct WasmScalarUdfRunner {
-    engine: Engine,
-    module: Module,
-    func: String,
+    pool: InstancePool,
+    exports: GuestExports,
+    hooks: StreamHooks,
     input_types: Vec<DataType>,
-    output_type: DataType,
+    outputs: Fields,
+    /// Type of the value returned per row: the single output's type, or a struct of all
+    /// outputs when there are several.
+    result_type: DataType,
+    null_handling: NullHandling,
 }
 
 impl WasmScalarUdfRunner {
@@ -202,12 +304,66 @@ impl WasmScalarUdfRunner {
         input_types: Vec<DataType>,
         output_type: DataType,
     ) -> Result<Self, WasmError> {
-        Ok(Self {
+        Self::new_with_options(
             engine,
             module,
             func,
             input_types,
             output_type,
+            WasmRunnerOptions::default(),
+        )
+    }
+
+    pub fn new_with_options(
+        engine: Engine,
+        module: Module,
+        func: String,
+        input_types: Vec<DataType>,
+        output_type: DataType,
+        options: WasmRunnerOptions,
+    ) -> Result<Self, WasmError> {
+        Self::new_with_outputs(
+            engine,
+            module,
+            func,
+            input_types,
+            vec![Field::new("", output_type, true)],
+            options,
+        )
+    }
+
+    /// Creates a runner for a UDF returning one column per entry of `outputs`; with several
+    /// outputs the guest returns a struct holding one field per output for each row.
+    pub fn new_with_outputs(
+        engine: Engine,
+        module: Module,
+        func: String,
+        input_types: Vec<DataType>,
+        outputs: Vec<Field>,
+        options: WasmRunnerOptions,
+    ) -> Result<Self, WasmError> {
+        let outputs = check_outputs(&func, outputs)?;
+        let result_type = match outputs.len() {
+            1 => outputs[0].data_type().clone(),
+            _ => DataType::Struct(outputs.clone()),
+        };
+        let mut params = vec![];
+        for input_type in &input_types {
+            params.push(arrow_type_to_wasm_type(input_type)?);
+            if options.null_handling == NullHandling::CalledOnNullInput {
+                params.push(ValType::I32);
+            }
+        }
+        let result = arrow_type_to_wasm_type(&result_type)?;
+        let exports = GuestExports::resolve(&module, &func, &params, &[result])?;
+        Ok(Self {
+            pool: InstancePool::new(engine, &module, &func, &options)?,
+            exports,
+            hooks: StreamHooks::resolve(&module, &func)?,
+            input_types,
+            outputs,
+            result_type,
+            null_handling: options.null_handling,
         })
     }
 
@@ -217,231 +373,352 @@ impl WasmScalarUdfRunner {
         output_type: DataType,
         wasm_data: &[u8],
     ) -> Result<Self, WasmError> {
-        let engine = Engine::default();
-        let module = Module::from_binary(&engine, wasm_data).unwrap();
-        Ok(Self {
-            engine,
-            module,
+        Self::new_from_raw_with_options(
             func,
             input_types,
             output_type,
-        })
+            wasm_data,
+            WasmRunnerOptions::default(),
+        )
     }
-}
 
-impl WasmUdfRunner for WasmScalarUdfRunner {
-    fn run(&self, batch: &RecordBatch) -> Result<RecordBatch, WasmError> {
-        let mut linker = Linker::new(&self.engine);
-        wasi_common::sync::add_to_linker(&mut linker, |s| s).unwrap();
-        let wasi = WasiCtxBuilder::new()
-            .inherit_stdio()
-            .inherit_args().unwrap()
-            .build();
-        let mut store = Store::new(&self.engine, wasi);
-
-        linker.module(&mut store, "", &self.module).unwrap();
-        let instance: Instance = linker
-            .instantiate(&mut store, &self.module).unwrap();
-        let func_def = instance
-            .get_func(&mut store, &self.func)
-            .expect(format!("`{}` was not an exported function", &self.func).as_str());
-
-        let mut input_arrow_types = vec![];
-        let schema = batch.schema();
-        for field in schema.fields() {
-            input_arrow_types.push(field.data_type());
-        }
+    pub fn new_from_raw_with_options(
+        func: String,
+        input_types: Vec<DataType>,
+        output_type: DataType,
+        wasm_data: &[u8],
+        options: WasmRunnerOptions,
+    ) -> Result<Self, WasmError> {
+        Self::new_from_raw_with_outputs(
+            func,
+            input_types,
+            vec![Field::new("", output_type, true)],
+            wasm_data,
+            options,
+        )
+    }
+
+    pub fn new_from_raw_with_outputs(
+        func: String,
+        input_types: Vec<DataType>,
+        outputs: Vec<Field>,
+        wasm_data: &[u8],
+        options: WasmRunnerOptions,
+    ) -> Result<Self, WasmError> {
+        let (engine, module) = options.compile(wasm_data)?;
+        Self::new_with_outputs(engine, module, func, input_types, outputs, options)
+    }
+
+    pub fn input_types(&self) -> &[DataType] {
+        &self.input_types
+    }
+
+    pub fn outputs(&self) -> &Fields {
+        &self.outputs
+    }
+
+    fn run_on_instance(
+        &self,
+        pooled: &mut PooledInstance,
+        batch: &RecordBatch,
+    ) -> Result<RecordBatch, WasmError> {
+        let batch = &check_input_types(batch, &self.input_types)?;
+        pooled.begin_batch(batch.num_rows());
+        let PooledInstance {
+            store,
+            instance,
+            fuel,
+            ..
+        } = pooled;
+        let instance = *instance;
+        let func = &self.exports.func;
+
+        let func_def = get_func(instance, &mut *store, func)?;
+        let memory = get_guest_memory(instance, &mut *store)?;
 
         let mut result_vals = vec![];
         for row_indice in 0..batch.num_rows() {
-            let mut input_vals = vec![];
-            for (col_indice, arrow_type) in input_arrow_types.clone().into_iter().enumerate() {
-                let array = batch.column(col_indice);
-                let val = match arrow_type {
-                    DataType::Utf8 => {
-                        let array = array.as_any().downcast_ref::<StringArray>().unwrap();
-                        let val = array.value(row_indice);
-                        let param_name_cstring: CString = CString::new(val).unwrap();
-                        let param_name_cstring_as_bytes: &[u8] =
-                            param_name_cstring.to_bytes_with_nul();
-                        let size = param_name_cstring_as_bytes.len() as u32;
-                        let offset: u32 =
-                            wrapper_wasm_allocate(instance, &mut store, size).unwrap() as u32;
-                        let memory = instance
-                            .get_memory(&mut store, "memory")
-                            .ok_or(anyhow::format_err!("failed to find `memory` export"))
-                            .unwrap();
-                        memory
-                            .write(
-                                &mut store,
-                                offset.try_into().unwrap(),
-                                param_name_cstring_as_bytes,
-                            )
-                            .unwrap();
-                        let ptr = ((size as u64) << 32) + offset as u64;
-                        Val::I64(ptr as i64)
-                    }
-                    DataType::Int32 => {
-                        let array = array.as_any().downcast_ref::<Int32Array>().unwrap();
-                        let val = array.value(row_indice);
-                        Val::I32(val)
-                    }
-                    DataType::Int64 => {
-                        let array = array.as_any().downcast_ref::<Int64Array>().unwrap();
-                        let val = array.value(row_indice);
-                        Val::I64(val)
-                    }
-                    DataType::Float32 => {
-                        let array = array.as_any().downcast_ref::<Float32Array>().unwrap();
-                        let val = array.value(row_indice);
-                        Val::F32(val.to_bits())
-                    }
-                    DataType::Float64 => {
-                        let array = array.as_any().downcast_ref::<Float64Array>().unwrap();
-                        let val = array.value(row_indice);
-                        Val::F64(val.to_bits())
-                    }
-                    _ => unimplemented!(),
-                };
-                input_vals.push(val)
+            if self.skips_row(batch, row_indice) {
+                result_vals.push(None);
+                continue;
             }
-            let mut tmp_result_vals = vec![Val::I64(0)];
-            let mut_store = &mut store;
-            func_def
-                .call(mut_store, input_vals.as_slice(), &mut tmp_result_vals).unwrap();
-            let result_ptr = match tmp_result_vals.get(0) {
-                Some(v) => v.clone(),
-                None => return Err("hello".into()),
-                _ => return Err("hello".into()),
-            };
-            result_vals.push(result_ptr);
+            fuel.begin_row(store);
+            result_vals.push(with_guest_buffers(instance, store, |store, buffers| {
+                self.call_row(
+                    instance, store, memory, func_def, batch, row_indice, buffers,
+                )
+            })?);
         }
 
-        let batch = match self.output_type {
-            DataType::Utf8 => {
-                let mut result_vecs = vec![];
-                for result_val in result_vals {
-                    if let Val::I64(result_ptr) = result_val {
-                        let result_size = (result_ptr >> 32) as u32;
-                        let result_offset = (result_ptr & 0xffffffff) as u32;
-
-                        if result_size == 0 {
-                            return Err(format!(
-                                "Error: No valid answer received from function"
-                            ).into());
-                        } else {
-                            let mut result_offset_position = result_offset.clone();
-
-                            let memory = instance
-                                .get_memory(&mut store, "memory")
-                                .ok_or(anyhow::format_err!("failed to find `memory` export"))
-                                .unwrap();
-
-                            let mut result_arrow_ipc: Vec<u8> = vec![0; result_size as usize];
-                            let mut result_arrow_ipc_buffer = result_arrow_ipc.as_mut_slice();
-
-                            memory
-                                .read(
-                                    &store,
-                                    result_offset_position.try_into().unwrap(),
-                                    &mut result_arrow_ipc_buffer,
-                                )
-                                .unwrap();
-                            result_vecs.push(result_arrow_ipc);
-                        }
-                    } else {
-                        return Err(format!(
-                            "Error: No valid answer received from function"
-                        ).into());
-                    }
-                }
-                let mut result_values = vec![];
-                for result_vec in result_vecs {
-                    let result_str = String::from_utf8(result_vec.to_vec()).unwrap();
-                    result_values.push(result_str);
-                }
-                let schema = Schema::new(vec![Field::new("", DataType::Utf8, true)]);
-                let result_values = StringArray::from(result_values);
-                let result_batch = RecordBatch::try_new(Arc::new(schema.clone()), vec![Arc::new(result_values)])
-                        .unwrap();
-                result_batch
-            }
-            DataType::Int32 => {
-                let mut result_values = vec![];
-                for result_val in result_vals {
-                    if let Val::I32(value) = result_val {
-                        result_values.push(value);
-                    } else {
-                        return Err(format!(
-                            "Error: No valid answer received from function"
-                        ).into());
-                    }
-                }
-                let schema = Schema::new(vec![Field::new("", DataType::Int32, true)]);
-                let result_values = Int32Array::from(result_values);
-                let result_batch =
-                    RecordBatch::try_new(Arc::new(schema.clone()), vec![Arc::new(result_values)])
-                        .unwrap();
-                result_batch
-            }
-            DataType::Int64 => {
-                let mut result_values = vec![];
-                for result_val in result_vals {
-                    if let Val::I64(value) = result_val {
-                        result_values.push(value);
-                    } else {
-                        return Err(format!(
-                            "Error: No valid answer received from function"
-                        ).into());
-                    }
-                }
-                let schema = Schema::new(vec![Field::new("", DataType::Int64, true)]);
-                let result_values = Int64Array::from(result_values);
-                let result_batch =
-                    RecordBatch::try_new(Arc::new(schema.clone()), vec![Arc::new(result_values)])
-                        .unwrap();
-                result_batch
-            }
-            DataType::Float32 => {
-                let mut result_values = vec![];
-                for result_val in result_vals {
-                    if let Val::F32(value) = result_val {
-                        result_values.push(f32::from_bits(value));
-                    } else {
-                        return Err(format!(
-                            "Error: No valid answer received from function"
-                        ).into());
-                    }
-                }
-                let schema = Schema::new(vec![Field::new("", DataType::Float32, true)]);
-                let result_values = Float32Array::from(result_values);
-                let result_batch =
-                    RecordBatch::try_new(Arc::new(schema.clone()), vec![Arc::new(result_values)])
-                        .unwrap();
-                result_batch
-            }
-            DataType::Float64 => {
-                let mut result_values = vec![];
-                for result_val in result_vals {
-                    if let Val::F64(value) = result_val {
-                        result_values.push(f64::from_bits(value));
-                    } else {
-                        return Err(format!(
-                            "Error: No valid answer received from function"
-                        ).into());
-                    }
-                }
-                let schema = Schema::new(vec![Field::new("", DataType::Float64, true)]);
-                let result_values = Float64Array::from(result_values);
-                let result_batch =
-                    RecordBatch::try_new(Arc::new(schema.clone()), vec![Arc::new(result_values)])
-                        .unwrap();
-                result_batch
+        let result_values = decode_row_results(func, &self.result_type, result_vals)?;
+        self.result_batch(result_values)
+    }
+
+    /// Calls the guest for `row`, copying its result to the host. The guest buffers of the
+    /// arguments and of the result are added to `buffers`.
+    #[allow(clippy::too_many_arguments)]
+    fn call_row(
+        &self,
+        instance: Instance,
+        store: &mut Store<HostState>,
+        memory: Memory,
+        func_def: Func,
+        batch: &RecordBatch,
+        row: usize,
+        buffers: &mut Vec<i64>,
+    ) -> Result<Option<RowValue>, WasmError> {
+        let func = &self.exports.func;
+        let input_vals = encode_row_args(
+            instance,
+            &mut *store,
+            memory,
+            func,
+            batch.columns(),
+            row,
+            self.null_handling,
+            buffers,
+        )?;
+        store.data_mut().result_null = false;
+        let mut tmp_result_vals = vec![Val::I64(0)];
+        func_def
+            .call(&mut *store, input_vals.as_slice(), &mut tmp_result_vals)
+            .map_err(|e| WasmError::from_guest_call(func, e))?;
+        row_result(func, store.data(), &tmp_result_vals)?
+            .map(|val| take_row_result(&mut *store, memory, func, &self.result_type, val, buffers))
+            .transpose()
+    }
+
+    /// Like `run_on_instance`, for instances of async engines.
+    async fn run_on_instance_async(
+        &self,
+        pooled: &mut PooledInstance,
+        batch: &RecordBatch,
+    ) -> Result<RecordBatch, WasmError> {
+        let batch = &check_input_types(batch, &self.input_types)?;
+        pooled.begin_batch(batch.num_rows());
+        let PooledInstance {
+            store,
+            instance,
+            fuel,
+            ..
+        } = pooled;
+        let instance = *instance;
+        let func = &self.exports.func;
+
+        let func_def = get_func(instance, &mut *store, func)?;
+        let memory = get_guest_memory(instance, &mut *store)?;
+
+        let mut result_vals = vec![];
+        for row_indice in 0..batch.num_rows() {
+            if self.skips_row(batch, row_indice) {
+                result_vals.push(None);
+                continue;
             }
+            fuel.begin_row(store);
+            let result = with_guest_buffers_async(instance, store, async |store, buffers| {
+                self.call_row_async(
+                    instance, store, memory, func_def, batch, row_indice, buffers,
+                )
+                .await
+            })
+            .await?;
+            result_vals.push(result);
+        }
+
+        let result_values = decode_row_results(func, &self.result_type, result_vals)?;
+        self.result_batch(result_values)
+    }
+
+    /// Like `call_row`, for instances of async engines.
+    #[allow(clippy::too_many_arguments)]
+    async fn call_row_async(
+        &self,
+        instance: Instance,
+        store: &mut Store<HostState>,
+        memory: Memory,
+        func_def: Func,
+        batch: &RecordBatch,
+        row: usize,
+        buffers: &mut Vec<i64>,
+    ) -> Result<Option<RowValue>, WasmError> {
+        let func = &self.exports.func;
+        let input_vals = encode_row_args_async(
+            instance,
+            &mut *store,
+            memory,
+            func,
+            batch.columns(),
+            row,
+            self.null_handling,
+            buffers,
+        )
+        .await?;
+        store.data_mut().result_null = false;
+        let mut tmp_result_vals = vec![Val::I64(0)];
+        func_def
+            .call_async(&mut *store, input_vals.as_slice(), &mut tmp_result_vals)
+            .await
+            .map_err(|e| WasmError::from_guest_call(func, e))?;
+        row_result(func, store.data(), &tmp_result_vals)?
+            .map(|val| take_row_result(&mut *store, memory, func, &self.result_type, val, buffers))
+            .transpose()
+    }
+
+    /// Whether `row` produces a null without calling the guest.
+    fn skips_row(&self, batch: &RecordBatch, row: usize) -> bool {
+        self.null_handling == NullHandling::ReturnNullOnNullInput
+            && batch.columns().iter().any(|array| array.is_null(row))
+    }
+
+    /// Splits the per-row results into one column per output.
+    fn result_batch(&self, result_values: ArrayRef) -> Result<RecordBatch, WasmError> {
+        let columns = match self.outputs.len() {
+            1 => vec![result_values],
             _ => {
-                unimplemented!()
+                // a null struct row makes every output of that row null
+                let results = result_values.as_struct();
+                let null_rows = is_null(results)?;
+                results
+                    .columns()
+                    .iter()
+                    .map(|column| nullif(column, &null_rows))
+                    .collect::<Result<Vec<_>, _>>()?
             }
         };
-        Ok(batch)
+        let schema = Schema::new(self.outputs.clone());
+        Ok(RecordBatch::try_new(Arc::new(schema), columns)?)
     }
 }
+
+impl AsyncWasmUdfRunner for WasmArrowScalarUdfRunner {
+    fn run_async_with_stats<'a>(
+        &'a self,
+        batch: &'a RecordBatch,
+    ) -> BoxFuture<'a, Result<(RecordBatch, RunStats), WasmError>> {
+        Box::pin(async move {
+            let mut pooled = self.pool.acquire_async().await?;
+            let result = self.run_on_instance_async(&mut pooled, batch).await;
+            let stats = pooled.stats();
+            let result = self.pool.finish_async(pooled, result).await;
+            match result {
+                Err(WasmError::OutOfMemory { .. }) if batch.num_rows() > 1 => {
+                    let (first, second) = split_batch(batch);
+                    let first = self.run_async_with_stats(&first).await?;
+                    let second = self.run_async_with_stats(&second).await?;
+                    concat_results(first, second)
+                }
+                result => Ok((result?, stats)),
+            }
+        })
+    }
+}
+
+impl WasmUdfRunner for WasmScalarUdfRunner {
+    fn run(&self, batch: &RecordBatch) -> Result<RecordBatch, WasmError> {
+        Ok(self.run_with_stats(batch)?.0)
+    }
+
+    /// Each row's buffers are freed before the next row runs, so a batch needs no more guest
+    /// memory than its largest row and is never split.
+    fn run_with_stats(&self, batch: &RecordBatch) -> Result<(RecordBatch, RunStats), WasmError> {
+        self.pool.with_instance(|pooled| {
+            let result = self.run_on_instance(pooled, batch)?;
+            Ok((result, pooled.stats()))
+        })
+    }
+
+    fn run_stream_with_stats<'a>(
+        &'a self,
+        batches: RecordBatchIter<'a>,
+    ) -> RecordBatchStatsIter<'a> {
+        Box::new(WasmBatchStream::new(
+            &self.pool,
+            &self.hooks,
+            |pooled, batch| self.run_on_instance(pooled, batch),
+            batches,
+        ))
+    }
+}
+
+impl AsyncWasmUdfRunner for WasmScalarUdfRunner {
+    fn run_async_with_stats<'a>(
+        &'a self,
+        batch: &'a RecordBatch,
+    ) -> BoxFuture<'a, Result<(RecordBatch, RunStats), WasmError>> {
+        Box::pin(async move {
+            let mut pooled = self.pool.acquire_async().await?;
+            let result = self.run_on_instance_async(&mut pooled, batch).await;
+            let stats = pooled.stats();
+            let result = self.pool.finish_async(pooled, result).await;
+            Ok((result?, stats))
+        })
+    }
+}
+
+/// Serializes every column of `batch` into its own IPC stream.
+fn encode_columns(batch: &RecordBatch) -> Result<Vec<Vec<u8>>, WasmError> {
+    let schema = batch.schema();
+    schema
+        .fields()
+        .iter()
+        .zip(batch.columns())
+        .map(|(field, array)| {
+            let col_batch = RecordBatch::try_new(
+                Arc::new(Schema::new(vec![field.clone()])),
+                vec![array.clone()],
+            )?;
+            encode_ipc(&col_batch)
+        })
+        .collect()
+}
+
+/// Extracts the packed result buffer of an arrow UDF call, rejecting empty results.
+fn result_buffer(func: &str, result_vals: &[Val]) -> Result<i64, WasmError> {
+    let Some(Val::I64(result_ptr)) = result_vals.first() else {
+        return Err(WasmError::invalid_result(func, "expected an i64 result"));
+    };
+    if (*result_ptr as u64) >> 32 == 0 {
+        return Err(WasmError::invalid_result(func, "empty result buffer"));
+    }
+    Ok(*result_ptr)
+}
+
+/// The value returned by a row call, `None` if the guest marked it null.
+fn row_result(
+    func: &str,
+    host_state: &HostState,
+    result_vals: &[Val],
+) -> Result<Option<Val>, WasmError> {
+    match result_vals.first() {
+        Some(_) if host_state.result_null => Ok(None),
+        Some(v) => Ok(Some(*v)),
+        None => Err(WasmError::invalid_result(func, "missing result value")),
+    }
+}
+
+/// Halves of a batch too large for the guest's memory.
+fn split_batch(batch: &RecordBatch) -> (RecordBatch, RecordBatch) {
+    let half = batch.num_rows() / 2;
+    (
+        batch.slice(0, half),
+        batch.slice(half, batch.num_rows() - half),
+    )
+}
+
+fn concat_results(
+    (first, first_stats): (RecordBatch, RunStats),
+    (second, second_stats): (RecordBatch, RunStats),
+) -> Result<(RecordBatch, RunStats), WasmError> {
+    let output = match (first_stats.output, second_stats.output) {
+        (Some(mut first), Some(second)) => {
+            first.append(&second);
+            Some(first)
+        }
+        (first, second) => first.or(second),
+    };
+    let stats = RunStats {
+        fuel_consumed: first_stats.fuel_consumed + second_stats.fuel_consumed,
+        output,
+    };
+    Ok((concat_batches(&first.schema(), [&first, &second])?, stats))
+}
@@ -0,0 +1,158 @@
+use wasmtime::{ExternType, Module};
+
+use arrow::record_batch::RecordBatch;
+use crate::errors::WasmError;
+use crate::runner::binds::wasm_ops::get_func;
+use crate::runner::fuel::RunStats;
+use crate::runner::instance_pool::{InstancePool, PooledInstance};
+
+/// Batches flowing into or out of [`WasmUdfRunner::run_stream`].
+///
+/// [`WasmUdfRunner::run_stream`]: crate::runner::runner_base::WasmUdfRunner::run_stream
+pub type RecordBatchIter<'a> = Box<dyn Iterator<Item = Result<RecordBatch, WasmError>> + 'a>;
+
+/// Output of [`WasmUdfRunner::run_stream_with_stats`].
+///
+/// [`WasmUdfRunner::run_stream_with_stats`]: crate::runner::runner_base::WasmUdfRunner::run_stream_with_stats
+pub type RecordBatchStatsIter<'a> =
+    Box<dyn Iterator<Item = Result<(RecordBatch, RunStats), WasmError>> + 'a>;
+
+/// Optional guest exports `{func}_open()` and `{func}_close()`, called when a stream starts
+/// and ends on an instance.
+#[derive(Clone, Debug, Default)]
+pub struct StreamHooks {
+    pub open: Option<String>,
+    pub close: Option<String>,
+}
+
+impl StreamHooks {
+    /// Looks up the hooks of `func`, checking that they take and return nothing.
+    pub fn resolve(module: &Module, func: &str) -> Result<Self, WasmError> {
+        let resolve_hook = |name: String| match module.get_export(&name) {
+            None => Ok(None),
+            Some(ExternType::Func(func_type))
+                if func_type.params().len() == 0 && func_type.results().len() == 0 =>
+            {
+                Ok(Some(name))
+            }
+            Some(ExternType::Func(func_type)) => Err(WasmError::SignatureMismatch {
+                name,
+                expected: "() -> ()".to_string(),
+                actual: func_type.to_string(),
+            }),
+            Some(_) => Err(WasmError::MissingExport {
+                name,
+                kind: "function".to_string(),
+            }),
+        };
+        Ok(Self {
+            open: resolve_hook(format!("{}_open", func))?,
+            close: resolve_hook(format!("{}_close", func))?,
+        })
+    }
+}
+
+type RunOnInstance<'a> =
+    Box<dyn Fn(&mut PooledInstance, &RecordBatch) -> Result<RecordBatch, WasmError> + 'a>;
+
+/// Output of a runner's `run_stream_with_stats`: pulls one input batch per output batch and
+/// runs every batch on the same instance, so guest state survives from one batch to the
+/// next. Each batch comes with the resources the stream consumed up to and including it.
+pub struct WasmBatchStream<'a> {
+    pool: &'a InstancePool,
+    hooks: &'a StreamHooks,
+    run: RunOnInstance<'a>,
+    input: RecordBatchIter<'a>,
+    pooled: Option<PooledInstance>,
+    done: bool,
+}
+
+impl<'a> WasmBatchStream<'a> {
+    pub(crate) fn new(
+        pool: &'a InstancePool,
+        hooks: &'a StreamHooks,
+        run: impl Fn(&mut PooledInstance, &RecordBatch) -> Result<RecordBatch, WasmError> + 'a,
+        input: RecordBatchIter<'a>,
+    ) -> Self {
+        Self {
+            pool,
+            hooks,
+            run: Box::new(run),
+            input,
+            pooled: None,
+            done: false,
+        }
+    }
+
+    /// Takes the instance of the stream, acquiring it and calling the `open` hook on first
+    /// use.
+    fn take_instance(&mut self) -> Result<PooledInstance, WasmError> {
+        if let Some(pooled) = self.pooled.take() {
+            return Ok(pooled);
+        }
+        let mut pooled = self.pool.acquire()?;
+        if let Err(e) = call_hook(&mut pooled, self.hooks.open.as_deref()) {
+            self.pool.release(pooled, false);
+            return Err(e);
+        }
+        Ok(pooled)
+    }
+
+    /// Calls the `close` hook and hands the instance back to the pool.
+    fn finish(&mut self) -> Result<(), WasmError> {
+        self.done = true;
+        let Some(mut pooled) = self.pooled.take() else {
+            return Ok(());
+        };
+        let result = call_hook(&mut pooled, self.hooks.close.as_deref());
+        self.pool.finish(pooled, result)
+    }
+}
+
+impl Iterator for WasmBatchStream<'_> {
+    type Item = Result<(RecordBatch, RunStats), WasmError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let batch = match self.input.next() {
+            Some(Ok(batch)) => batch,
+            Some(Err(e)) => {
+                // the input failed, not the guest: still close the stream cleanly
+                let _ = self.finish();
+                return Some(Err(e));
+            }
+            None => return self.finish().err().map(Err),
+        };
+        let result = self.take_instance().and_then(|mut pooled| {
+            let result = (self.run)(&mut pooled, &batch).map(|result| (result, pooled.stats()));
+            self.pooled = Some(pooled);
+            result
+        });
+        if result.is_err() {
+            // skip the `close` hook, the instance may be left in any state
+            self.done = true;
+            if let Some(pooled) = self.pooled.take() {
+                self.pool.release(pooled, false);
+            }
+        }
+        Some(result)
+    }
+}
+
+impl Drop for WasmBatchStream<'_> {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+fn call_hook(pooled: &mut PooledInstance, hook: Option<&str>) -> Result<(), WasmError> {
+    let Some(hook) = hook else {
+        return Ok(());
+    };
+    let func_def = get_func(pooled.instance, &mut pooled.store, hook)?;
+    func_def
+        .call(&mut pooled.store, &[], &mut [])
+        .map_err(|e| WasmError::from_guest_call(hook, e))
+}
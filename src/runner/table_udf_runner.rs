@@ -0,0 +1,310 @@
+use std::sync::Arc;
+
+use wasmtime::{Engine, Func, Instance, Memory, Module, Store, Val, ValType};
+
+use arrow::array::{Array, ArrayRef, UInt32Array};
+use arrow::compute::concat_batches;
+use arrow::datatypes::{DataType, Field, Fields, Schema, SchemaRef};
+use arrow::record_batch::RecordBatch;
+use crate::errors::WasmError;
+use crate::runner::binds::wasm_ops::{
+    get_func, get_guest_memory, read_guest_buffer, with_guest_buffers, GuestExports,
+};
+use crate::runner::datatypes::arrow_type_to_wasm_type;
+use crate::runner::fuel::RunStats;
+use crate::runner::host::HostState;
+use crate::runner::instance_pool::{InstancePool, PooledInstance};
+use crate::runner::ipc::decode_ipc;
+use crate::runner::options::{NullHandling, WasmRunnerOptions};
+use crate::runner::row_abi::encode_row_args;
+use crate::runner::runner_base::{
+    check_input_types, check_outputs, conform_result_batch, WasmUdfRunner,
+};
+
+/// Name of the column holding, for every output row, the index of the input row that
+/// produced it.
+pub const PARENT_ROW_COLUMN: &str = "parent_row";
+
+/// Runs a table-valued UDF: the guest is called once per input row, with the arguments of
+/// the row-at-a-time ABI, and returns a packed `(len << 32) | ptr` Arrow IPC stream holding
+/// any number of rows of the declared outputs. An empty buffer or a call to
+/// `set_result_null` produces no rows.
+pub struct WasmTableUdfRunner {
+    pool: InstancePool,
+    exports: GuestExports,
+    input_types: Vec<DataType>,
+    outputs: Fields,
+    schema: SchemaRef,
+    null_handling: NullHandling,
+}
+
+impl WasmTableUdfRunner {
+    pub fn new(
+        engine: Engine,
+        module: Module,
+        func: String,
+        input_types: Vec<DataType>,
+        outputs: Vec<Field>,
+    ) -> Result<Self, WasmError> {
+        Self::new_with_options(
+            engine,
+            module,
+            func,
+            input_types,
+            outputs,
+            WasmRunnerOptions::default(),
+        )
+    }
+
+    pub fn new_with_options(
+        engine: Engine,
+        module: Module,
+        func: String,
+        input_types: Vec<DataType>,
+        outputs: Vec<Field>,
+        options: WasmRunnerOptions,
+    ) -> Result<Self, WasmError> {
+        let outputs = check_outputs(&func, outputs)?;
+        if outputs.find(PARENT_ROW_COLUMN).is_some() {
+            return Err(WasmError::InvalidInput {
+                msg: format!("udf `{}` output `{}` is reserved", func, PARENT_ROW_COLUMN),
+            });
+        }
+        let mut params = vec![];
+        for input_type in &input_types {
+            params.push(arrow_type_to_wasm_type(input_type)?);
+            if options.null_handling == NullHandling::CalledOnNullInput {
+                params.push(ValType::I32);
+            }
+        }
+        let exports = GuestExports::resolve(&module, &func, &params, &[ValType::I64])?;
+        let schema = Schema::new(
+            std::iter::once(Arc::new(Field::new(PARENT_ROW_COLUMN, DataType::UInt32, false)))
+                .chain(outputs.iter().cloned())
+                .collect::<Fields>(),
+        );
+        Ok(Self {
+            pool: InstancePool::new(engine, &module, &func, &options)?,
+            exports,
+            input_types,
+            outputs,
+            schema: Arc::new(schema),
+            null_handling: options.null_handling,
+        })
+    }
+
+    pub fn new_from_raw(
+        func: String,
+        input_types: Vec<DataType>,
+        outputs: Vec<Field>,
+        wasm_data: &[u8],
+    ) -> Result<Self, WasmError> {
+        Self::new_from_raw_with_options(
+            func,
+            input_types,
+            outputs,
+            wasm_data,
+            WasmRunnerOptions::default(),
+        )
+    }
+
+    pub fn new_from_raw_with_options(
+        func: String,
+        input_types: Vec<DataType>,
+        outputs: Vec<Field>,
+        wasm_data: &[u8],
+        options: WasmRunnerOptions,
+    ) -> Result<Self, WasmError> {
+        let (engine, module) = options.compile(wasm_data)?;
+        Self::new_with_options(engine, module, func, input_types, outputs, options)
+    }
+
+    pub fn input_types(&self) -> &[DataType] {
+        &self.input_types
+    }
+
+    pub fn outputs(&self) -> &Fields {
+        &self.outputs
+    }
+
+    /// Schema of the produced batches: the `parent_row` column followed by the outputs.
+    pub fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    /// Lazily calls the guest for each row of `batch`, yielding one batch per input row that
+    /// produced rows.
+    pub fn run_iter(&self, batch: &RecordBatch) -> Result<WasmTableUdfBatches<'_>, WasmError> {
+        let batch = &check_input_types(batch, &self.input_types)?;
+        Ok(WasmTableUdfBatches {
+            runner: self,
+            batch: batch.clone(),
+            next_row: 0,
+            pooled: None,
+            stats: RunStats::default(),
+        })
+    }
+
+    fn run_row(
+        &self,
+        pooled: &mut PooledInstance,
+        batch: &RecordBatch,
+        row: usize,
+    ) -> Result<Option<RecordBatch>, WasmError> {
+        let has_null = batch.columns().iter().any(|array| array.is_null(row));
+        if has_null && self.null_handling == NullHandling::ReturnNullOnNullInput {
+            return Ok(None);
+        }
+        pooled.begin_row();
+        let PooledInstance {
+            store, instance, ..
+        } = pooled;
+        let instance = *instance;
+        let func = &self.exports.func;
+
+        let func_def = get_func(instance, &mut *store, func)?;
+        let memory = get_guest_memory(instance, &mut *store)?;
+
+        let result_arrow_ipc = with_guest_buffers(instance, store, |store, buffers| {
+            self.call_row(instance, store, memory, func_def, batch, row, buffers)
+        })?;
+        if result_arrow_ipc.is_empty() {
+            return Ok(None);
+        }
+        let result_batch = decode_ipc(func, &result_arrow_ipc)?;
+        let result_batch = conform_result_batch(func, &result_batch, &self.outputs)?;
+        if result_batch.num_rows() == 0 {
+            return Ok(None);
+        }
+        let parent_row = u32::try_from(row).map_err(|_| WasmError::InvalidInput {
+            msg: format!("row {} does not fit in the u32 `parent_row` column", row),
+        })?;
+        let parent_row: ArrayRef =
+            Arc::new(UInt32Array::from(vec![parent_row; result_batch.num_rows()]));
+        let columns = std::iter::once(parent_row)
+            .chain(result_batch.columns().iter().cloned())
+            .collect();
+        Ok(Some(RecordBatch::try_new(self.schema.clone(), columns)?))
+    }
+
+    /// Calls the guest for `row` and copies the returned IPC stream to the host, empty if the
+    /// guest produced no rows or marked the result null. The guest buffers of the arguments
+    /// and of the result are added to `buffers`.
+    #[allow(clippy::too_many_arguments)]
+    fn call_row(
+        &self,
+        instance: Instance,
+        store: &mut Store<HostState>,
+        memory: Memory,
+        func_def: Func,
+        batch: &RecordBatch,
+        row: usize,
+        buffers: &mut Vec<i64>,
+    ) -> Result<Vec<u8>, WasmError> {
+        let func = &self.exports.func;
+        let input_vals = encode_row_args(
+            instance,
+            &mut *store,
+            memory,
+            func,
+            batch.columns(),
+            row,
+            self.null_handling,
+            buffers,
+        )?;
+        store.data_mut().result_null = false;
+        let mut tmp_result_vals = vec![Val::I64(0)];
+        func_def
+            .call(&mut *store, input_vals.as_slice(), &mut tmp_result_vals)
+            .map_err(|e| WasmError::from_guest_call(func, e))?;
+        let Some(Val::I64(result_ptr)) = tmp_result_vals.first() else {
+            return Err(WasmError::invalid_result(func, "expected an i64 result"));
+        };
+        // a buffer returned along with a null result is freed all the same
+        buffers.push(*result_ptr);
+        if store.data().result_null || (*result_ptr as u64) >> 32 == 0 {
+            return Ok(vec![]);
+        }
+        read_guest_buffer(&mut *store, memory, func, *result_ptr)
+    }
+}
+
+impl WasmUdfRunner for WasmTableUdfRunner {
+    /// Collects every produced row into a single batch.
+    fn run(&self, batch: &RecordBatch) -> Result<RecordBatch, WasmError> {
+        Ok(self.run_with_stats(batch)?.0)
+    }
+
+    fn run_with_stats(&self, batch: &RecordBatch) -> Result<(RecordBatch, RunStats), WasmError> {
+        let mut results = self.run_iter(batch)?;
+        let batches = results.by_ref().collect::<Result<Vec<_>, _>>()?;
+        Ok((concat_batches(&self.schema, &batches)?, results.stats()))
+    }
+}
+
+/// Batches produced by [`WasmTableUdfRunner::run_iter`]. The instance running the guest is
+/// held until the iterator is exhausted or dropped.
+pub struct WasmTableUdfBatches<'a> {
+    runner: &'a WasmTableUdfRunner,
+    batch: RecordBatch,
+    next_row: usize,
+    pooled: Option<PooledInstance>,
+    stats: RunStats,
+}
+
+impl WasmTableUdfBatches<'_> {
+    /// Resources consumed by the rows processed so far.
+    pub fn stats(&self) -> RunStats {
+        self.stats.clone()
+    }
+}
+
+impl Iterator for WasmTableUdfBatches<'_> {
+    type Item = Result<RecordBatch, WasmError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.next_row < self.batch.num_rows() {
+            let row = self.next_row;
+            self.next_row += 1;
+            let mut pooled = match self.pooled.take() {
+                Some(pooled) => pooled,
+                None => match self.runner.pool.acquire() {
+                    // the whole input batch shares the timeout and a per-batch fuel budget
+                    Ok(mut pooled) => {
+                        pooled.begin_batch(self.batch.num_rows());
+                        pooled
+                    }
+                    Err(e) => {
+                        self.next_row = self.batch.num_rows();
+                        return Some(Err(e));
+                    }
+                },
+            };
+            let result = self.runner.run_row(&mut pooled, &self.batch, row);
+            self.stats = pooled.stats();
+            match result {
+                Ok(result) => {
+                    self.pooled = Some(pooled);
+                    if let Some(result) = result {
+                        return Some(Ok(result));
+                    }
+                }
+                Err(e) => {
+                    self.runner.pool.release(pooled, false);
+                    self.next_row = self.batch.num_rows();
+                    return Some(Err(e));
+                }
+            }
+        }
+        let pooled = self.pooled.take()?;
+        self.runner.pool.finish(pooled, Ok(())).err().map(Err)
+    }
+}
+
+impl Drop for WasmTableUdfBatches<'_> {
+    fn drop(&mut self) {
+        if let Some(pooled) = self.pooled.take() {
+            self.runner.pool.release(pooled, true);
+        }
+    }
+}
@@ -0,0 +1,304 @@
+use std::any::Any;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use cap_std::time::{Duration, Instant, SystemTime};
+use wasi_common::dir::{OpenResult, ReaddirCursor, ReaddirEntity};
+use wasi_common::file::{FdFlags, Filestat, OFlags};
+use wasi_common::pipe::WritePipe;
+use wasi_common::{
+    Error, ErrorExt, SystemTimeSpec, Table, WasiClocks, WasiCtx, WasiDir, WasiMonotonicClock,
+    WasiSystemClock,
+};
+
+use crate::errors::WasmError;
+use crate::runner::guest_output::StdioPipes;
+
+/// What a guest may see of the host through WASI. The default grants no ambient
+/// authority: no arguments, environment, files or stdio.
+///
+/// Guests never get network access: WASI preview 1 can only use preopened sockets, which are
+/// never granted.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WasiPolicy {
+    /// Arguments seen by the guest, starting with the program name.
+    pub args: Vec<String>,
+    /// Environment variables set for the guest.
+    pub env: Vec<(String, String)>,
+    /// Names of host environment variables passed through when set.
+    pub inherit_env: Vec<String>,
+    /// Host directories the guest may read, but not modify.
+    pub preopened_dirs: Vec<PreopenedDir>,
+    /// Where the guest's stdout and stderr go.
+    pub stdio: GuestStdio,
+    /// Freeze the clocks at the Unix epoch and draw random bytes from a generator seeded with
+    /// this value, so that runs are reproducible.
+    pub deterministic_seed: Option<u64>,
+}
+
+/// Destination of a guest's stdout and stderr.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GuestStdio {
+    /// Drop everything the guest writes.
+    #[default]
+    Discard,
+    /// Connect the guest's stdout and stderr to the host's.
+    Inherit,
+    /// Capture the output of every batch and log each line as a `tracing` event under
+    /// [`GUEST_OUTPUT_TARGET`](crate::runner::guest_output::GUEST_OUTPUT_TARGET), tagged with
+    /// the UDF name and the batch id. With `return_output`, the output is also returned in
+    /// the `RunStats` of the run. At most `max_captured_bytes` of stdout, and as many of
+    /// stderr, are kept per batch; the rest is dropped and reported by a last event. See
+    /// [`DEFAULT_MAX_CAPTURED_BYTES`](crate::runner::guest_output::DEFAULT_MAX_CAPTURED_BYTES).
+    Capture {
+        return_output: bool,
+        max_captured_bytes: usize,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PreopenedDir {
+    pub host_path: PathBuf,
+    /// Path under which the guest sees the directory.
+    pub guest_path: String,
+}
+
+/// Builds the WASI context of a new instance, for the async flavour of WASI when `is_async`,
+/// along with the buffers its output is captured into, if any.
+pub(crate) fn build_ctx(
+    policy: &WasiPolicy,
+    is_async: bool,
+) -> Result<(WasiCtx, Option<StdioPipes>), WasmError> {
+    let error = |msg: String| WasmError::Instantiation { msg };
+    let (random, clocks) = match policy.deterministic_seed {
+        Some(seed) => (deterministic_random(seed), deterministic_clocks()),
+        None => (
+            wasi_common::sync::random_ctx(),
+            wasi_common::sync::clocks_ctx(),
+        ),
+    };
+    let sched = match is_async {
+        true => wasi_common::tokio::sched::sched_ctx(),
+        false => wasi_common::sync::sched_ctx(),
+    };
+    let mut ctx = WasiCtx::new(random, clocks, sched, Table::new());
+
+    for arg in &policy.args {
+        ctx.push_arg(arg).map_err(|e| error(e.to_string()))?;
+    }
+    let inherited = policy
+        .inherit_env
+        .iter()
+        .filter_map(|name| Some((name.clone(), std::env::var(name).ok()?)));
+    for (name, value) in inherited.chain(policy.env.iter().cloned()) {
+        ctx.push_env(&name, &value).map_err(|e| error(e.to_string()))?;
+    }
+    for preopen in &policy.preopened_dirs {
+        let dir = open_dir(&preopen.host_path, is_async).map_err(|e| {
+            error(format!(
+                "failed to preopen `{}`: {}",
+                preopen.host_path.display(),
+                e
+            ))
+        })?;
+        ctx.push_preopened_dir(Box::new(ReadOnlyDir(dir)), &preopen.guest_path)
+            .map_err(|e| error(e.to_string()))?;
+    }
+    let pipes = match policy.stdio {
+        GuestStdio::Discard => None,
+        GuestStdio::Inherit => {
+            match is_async {
+                true => {
+                    ctx.set_stdout(Box::new(wasi_common::tokio::stdio::stdout()));
+                    ctx.set_stderr(Box::new(wasi_common::tokio::stdio::stderr()));
+                }
+                false => {
+                    ctx.set_stdout(Box::new(wasi_common::sync::stdio::stdout()));
+                    ctx.set_stderr(Box::new(wasi_common::sync::stdio::stderr()));
+                }
+            }
+            None
+        }
+        GuestStdio::Capture {
+            max_captured_bytes, ..
+        } => {
+            let pipes = StdioPipes::new(max_captured_bytes);
+            ctx.set_stdout(Box::new(WritePipe::from_shared(Arc::clone(&pipes.stdout))));
+            ctx.set_stderr(Box::new(WritePipe::from_shared(Arc::clone(&pipes.stderr))));
+            Some(pipes)
+        }
+    };
+    Ok((ctx, pipes))
+}
+
+fn open_dir(path: &Path, is_async: bool) -> std::io::Result<Box<dyn WasiDir>> {
+    let dir = cap_std::fs::Dir::open_ambient_dir(path, cap_std::ambient_authority())?;
+    Ok(match is_async {
+        true => Box::new(wasi_common::tokio::Dir::from_cap_std(dir)),
+        false => Box::new(wasi_common::sync::dir::Dir::from_cap_std(dir)),
+    })
+}
+
+fn deterministic_random(seed: u64) -> Box<dyn wasi_common::RngCore + Send + Sync> {
+    Box::new(SplitMix64(seed))
+}
+
+/// The splitmix64 generator: reproducible from its seed, with a period of 2^64 outputs.
+struct SplitMix64(u64);
+
+impl wasi_common::RngCore for SplitMix64 {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            chunk.copy_from_slice(&self.next_u64().to_le_bytes()[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), cap_rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+fn deterministic_clocks() -> WasiClocks {
+    WasiClocks::new()
+        .with_system(FrozenSystemClock)
+        .with_monotonic(FrozenMonotonicClock(Instant::from_std(
+            std::time::Instant::now(),
+        )))
+}
+
+struct FrozenSystemClock;
+
+impl WasiSystemClock for FrozenSystemClock {
+    fn resolution(&self) -> Duration {
+        Duration::from_nanos(1)
+    }
+
+    fn now(&self, _precision: Duration) -> SystemTime {
+        SystemTime::from_std(std::time::UNIX_EPOCH)
+    }
+}
+
+struct FrozenMonotonicClock(Instant);
+
+impl WasiMonotonicClock for FrozenMonotonicClock {
+    fn resolution(&self) -> Duration {
+        Duration::from_nanos(1)
+    }
+
+    fn now(&self, _precision: Duration) -> Instant {
+        self.0
+    }
+}
+
+/// Forwards the reads of a preopened directory and denies everything else.
+struct ReadOnlyDir(Box<dyn WasiDir>);
+
+#[wasmtime_wasi::async_trait]
+impl WasiDir for ReadOnlyDir {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn open_file(
+        &self,
+        symlink_follow: bool,
+        path: &str,
+        oflags: OFlags,
+        read: bool,
+        write: bool,
+        fdflags: FdFlags,
+    ) -> Result<OpenResult, Error> {
+        let mutating = OFlags::CREATE | OFlags::EXCLUSIVE | OFlags::TRUNCATE;
+        if write || oflags.intersects(mutating) || fdflags.contains(FdFlags::APPEND) {
+            return Err(Error::perm());
+        }
+        let opened = self
+            .0
+            .open_file(symlink_follow, path, oflags, read, false, fdflags)
+            .await?;
+        Ok(match opened {
+            OpenResult::Dir(dir) => OpenResult::Dir(Box::new(ReadOnlyDir(dir))),
+            file => file,
+        })
+    }
+
+    async fn create_dir(&self, _path: &str) -> Result<(), Error> {
+        Err(Error::perm())
+    }
+
+    async fn readdir(
+        &self,
+        cursor: ReaddirCursor,
+    ) -> Result<Box<dyn Iterator<Item = Result<ReaddirEntity, Error>> + Send>, Error> {
+        self.0.readdir(cursor).await
+    }
+
+    async fn symlink(&self, _old_path: &str, _new_path: &str) -> Result<(), Error> {
+        Err(Error::perm())
+    }
+
+    async fn remove_dir(&self, _path: &str) -> Result<(), Error> {
+        Err(Error::perm())
+    }
+
+    async fn unlink_file(&self, _path: &str) -> Result<(), Error> {
+        Err(Error::perm())
+    }
+
+    async fn read_link(&self, path: &str) -> Result<PathBuf, Error> {
+        self.0.read_link(path).await
+    }
+
+    async fn get_filestat(&self) -> Result<Filestat, Error> {
+        self.0.get_filestat().await
+    }
+
+    async fn get_path_filestat(
+        &self,
+        path: &str,
+        follow_symlinks: bool,
+    ) -> Result<Filestat, Error> {
+        self.0.get_path_filestat(path, follow_symlinks).await
+    }
+
+    async fn rename(
+        &self,
+        _path: &str,
+        _dest_dir: &dyn WasiDir,
+        _dest_path: &str,
+    ) -> Result<(), Error> {
+        Err(Error::perm())
+    }
+
+    async fn hard_link(
+        &self,
+        _path: &str,
+        _target_dir: &dyn WasiDir,
+        _target_path: &str,
+    ) -> Result<(), Error> {
+        Err(Error::perm())
+    }
+
+    async fn set_times(
+        &self,
+        _path: &str,
+        _atime: Option<SystemTimeSpec>,
+        _mtime: Option<SystemTimeSpec>,
+        _follow_symlinks: bool,
+    ) -> Result<(), Error> {
+        Err(Error::perm())
+    }
+}
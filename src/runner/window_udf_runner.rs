@@ -0,0 +1,554 @@
+use std::ops::Range;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use wasmtime::{Engine, Func, Instance, Memory, Module, Store, Val, ValType};
+
+use arrow::array::{Array, ArrayRef, AsArray};
+use arrow::compute::cast;
+use arrow::datatypes::{DataType, Field, Fields, Int64Type, Schema};
+use arrow::record_batch::RecordBatch;
+use crate::errors::WasmError;
+use crate::runner::binds::wasm_ops::{
+    call_with_buffers, check_func_export, get_func, get_guest_memory, with_guest_buffers,
+    GuestExports,
+};
+use crate::runner::datatypes::arrow_type_to_wasm_type;
+use crate::runner::fuel::RunStats;
+use crate::runner::host::HostState;
+use crate::runner::instance_pool::{InstancePool, PooledInstance};
+use crate::runner::ipc::{decode_ipc, encode_ipc};
+use crate::runner::options::{NullHandling, WasmRunnerOptions};
+use crate::runner::row_abi::{decode_row_results, encode_row_args, take_row_result};
+use crate::runner::runner_base::{check_input_types, conform_result_batch, WasmUdfRunner};
+
+/// How the offsets of a [`WindowFrame`] are measured.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WindowFrameUnits {
+    /// Offsets count rows.
+    #[default]
+    Rows,
+    /// Offsets are distances between values of the order column; rows with equal values
+    /// (peers) are always in the same frames.
+    Range,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WindowFrameBound {
+    UnboundedPreceding,
+    Preceding(u64),
+    CurrentRow,
+    Following(u64),
+    UnboundedFollowing,
+}
+
+/// The rows each output row is computed over, relative to the row itself.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WindowFrame {
+    pub units: WindowFrameUnits,
+    pub start: WindowFrameBound,
+    pub end: WindowFrameBound,
+    /// Index of the input column the partition is ordered by, ascending. Required by
+    /// `Range` frames with offsets; without it every row of the partition is a peer.
+    pub order_by: Option<usize>,
+}
+
+impl Default for WindowFrame {
+    /// `RANGE BETWEEN UNBOUNDED PRECEDING AND CURRENT ROW`, the SQL default.
+    fn default() -> Self {
+        Self {
+            units: WindowFrameUnits::Range,
+            start: WindowFrameBound::UnboundedPreceding,
+            end: WindowFrameBound::CurrentRow,
+            order_by: None,
+        }
+    }
+}
+
+impl WindowFrame {
+    pub fn rows(start: WindowFrameBound, end: WindowFrameBound) -> Self {
+        Self {
+            units: WindowFrameUnits::Rows,
+            start,
+            end,
+            order_by: None,
+        }
+    }
+
+    pub fn range(start: WindowFrameBound, end: WindowFrameBound, order_by: usize) -> Self {
+        Self {
+            units: WindowFrameUnits::Range,
+            start,
+            end,
+            order_by: Some(order_by),
+        }
+    }
+
+    /// Computes the frame of every row of `partition` as a range of row indices. The
+    /// ranges never move backwards from one row to the next.
+    pub fn bounds(&self, partition: &RecordBatch) -> Result<Vec<Range<usize>>, WasmError> {
+        let num_rows = partition.num_rows();
+        let bounds = match self.units {
+            WindowFrameUnits::Rows => (0..num_rows)
+                .map(|row| {
+                    let start = rows_offset(self.start, row, num_rows, false);
+                    let end = rows_offset(self.end, row, num_rows, true);
+                    start.min(end)..end
+                })
+                .collect(),
+            WindowFrameUnits::Range => {
+                let keys = self.order_keys(partition)?;
+                (0..num_rows)
+                    .map(|row| {
+                        let start = range_offset(self.start, &keys, row, false);
+                        let end = range_offset(self.end, &keys, row, true);
+                        start.min(end)..end
+                    })
+                    .collect()
+            }
+        };
+        Ok(bounds)
+    }
+
+    /// Values of the order column as `i64`; all zero when the partition is unordered.
+    fn order_keys(&self, partition: &RecordBatch) -> Result<Vec<i64>, WasmError> {
+        let Some(order_by) = self.order_by else {
+            if matches!(
+                self.start,
+                WindowFrameBound::Preceding(_) | WindowFrameBound::Following(_)
+            ) || matches!(
+                self.end,
+                WindowFrameBound::Preceding(_) | WindowFrameBound::Following(_)
+            ) {
+                return Err(WasmError::InvalidInput {
+                    msg: "a RANGE frame with offsets needs an order column".to_string(),
+                });
+            }
+            return Ok(vec![0; partition.num_rows()]);
+        };
+        let column = partition
+            .columns()
+            .get(order_by)
+            .ok_or_else(|| WasmError::InvalidInput {
+                msg: format!(
+                    "order column {} is out of bounds for {} columns",
+                    order_by,
+                    partition.num_columns()
+                ),
+            })?;
+        let data_type = column.data_type();
+        if !(data_type.is_integer() || data_type.is_temporal()) {
+            return Err(WasmError::UnsupportedType {
+                data_type: format!("{} as a RANGE order column", data_type),
+            });
+        }
+        if column.null_count() > 0 {
+            return Err(WasmError::InvalidInput {
+                msg: "the order column of a RANGE frame contains nulls".to_string(),
+            });
+        }
+        let keys = cast(column, &DataType::Int64)?;
+        let keys = keys.as_primitive::<Int64Type>().values().to_vec();
+        if keys.windows(2).any(|w| w[0] > w[1]) {
+            return Err(WasmError::InvalidInput {
+                msg: "the partition is not sorted by its order column".to_string(),
+            });
+        }
+        Ok(keys)
+    }
+}
+
+/// Row index a ROWS bound resolves to for `row`; `end` bounds are exclusive.
+fn rows_offset(bound: WindowFrameBound, row: usize, num_rows: usize, end: bool) -> usize {
+    let inclusive = end as usize;
+    let offset = match bound {
+        WindowFrameBound::UnboundedPreceding => 0,
+        WindowFrameBound::Preceding(n) => {
+            (row + inclusive).saturating_sub(n.try_into().unwrap_or(usize::MAX))
+        }
+        WindowFrameBound::CurrentRow => row + inclusive,
+        WindowFrameBound::Following(n) => row
+            .saturating_add(n.try_into().unwrap_or(usize::MAX))
+            .saturating_add(inclusive),
+        WindowFrameBound::UnboundedFollowing => num_rows,
+    };
+    offset.min(num_rows)
+}
+
+/// Row index a RANGE bound resolves to for `row` over the sorted `keys`; `end` bounds are
+/// exclusive, so they extend past the last peer.
+fn range_offset(bound: WindowFrameBound, keys: &[i64], row: usize, end: bool) -> usize {
+    let target = match bound {
+        WindowFrameBound::UnboundedPreceding => return 0,
+        WindowFrameBound::UnboundedFollowing => return keys.len(),
+        WindowFrameBound::Preceding(n) => {
+            keys[row].saturating_sub(i64::try_from(n).unwrap_or(i64::MAX))
+        }
+        WindowFrameBound::CurrentRow => keys[row],
+        WindowFrameBound::Following(n) => {
+            keys[row].saturating_add(i64::try_from(n).unwrap_or(i64::MAX))
+        }
+    };
+    match end {
+        false => keys.partition_point(|key| *key < target),
+        true => keys.partition_point(|key| *key <= target),
+    }
+}
+
+/// Guest exports of the incremental window ABI.
+struct IncrementalExports {
+    open: String,
+    add: String,
+    retract: String,
+    evaluate: String,
+}
+
+/// Runs a window UDF `name` over one partition at a time, producing a value per row.
+///
+/// The guest either exports `{name}(partition, frames) -> result`, receiving the partition
+/// as a packed Arrow IPC stream of the input columns and the frames as a packed buffer of
+/// little-endian `(start: u32, end: u32)` row ranges (end exclusive), and returning an IPC
+/// stream with one value per row; or the incremental entry points
+///
+/// * `{name}_open()`, resetting the state at the start of a partition
+/// * `{name}_add(args...)` and `{name}_retract(args...)`, taking the arguments of a row
+///   entering or leaving the frame with the row-at-a-time ABI
+/// * `{name}_evaluate() -> value`, returning the value for the current frame like a
+///   row-at-a-time scalar UDF
+///
+/// which are used instead when present. With `ReturnNullOnNullInput`, rows with a null
+/// argument are never added to a frame.
+pub struct WasmWindowUdfRunner {
+    pool: InstancePool,
+    exports: GuestExports,
+    incremental: Option<IncrementalExports>,
+    input_types: Vec<DataType>,
+    output_type: DataType,
+    frame: WindowFrame,
+    null_handling: NullHandling,
+}
+
+impl WasmWindowUdfRunner {
+    pub fn new(
+        engine: Engine,
+        module: Module,
+        name: String,
+        input_types: Vec<DataType>,
+        output_type: DataType,
+        frame: WindowFrame,
+    ) -> Result<Self, WasmError> {
+        Self::new_with_options(
+            engine,
+            module,
+            name,
+            input_types,
+            output_type,
+            frame,
+            WasmRunnerOptions::default(),
+        )
+    }
+
+    pub fn new_with_options(
+        engine: Engine,
+        module: Module,
+        name: String,
+        input_types: Vec<DataType>,
+        output_type: DataType,
+        frame: WindowFrame,
+        options: WasmRunnerOptions,
+    ) -> Result<Self, WasmError> {
+        let open = format!("{}_open", name);
+        let (exports, incremental) = match module.get_export(&open) {
+            Some(_) => {
+                let mut params = vec![];
+                for input_type in &input_types {
+                    params.push(arrow_type_to_wasm_type(input_type)?);
+                    if options.null_handling == NullHandling::CalledOnNullInput {
+                        params.push(ValType::I32);
+                    }
+                }
+                let exports = GuestExports::resolve(&module, &open, &[], &[])?;
+                let incremental = IncrementalExports {
+                    open,
+                    add: format!("{}_add", name),
+                    retract: format!("{}_retract", name),
+                    evaluate: format!("{}_evaluate", name),
+                };
+                check_func_export(&module, &incremental.add, &params, &[])?;
+                check_func_export(&module, &incremental.retract, &params, &[])?;
+                let result = arrow_type_to_wasm_type(&output_type)?;
+                check_func_export(&module, &incremental.evaluate, &[], &[result])?;
+                (exports, Some(incremental))
+            }
+            None => {
+                let params = [ValType::I64, ValType::I64];
+                let exports = GuestExports::resolve(&module, &name, &params, &[ValType::I64])?;
+                (exports, None)
+            }
+        };
+        Ok(Self {
+            pool: InstancePool::new(engine, &module, &name, &options)?,
+            exports,
+            incremental,
+            input_types,
+            output_type,
+            frame,
+            null_handling: options.null_handling,
+        })
+    }
+
+    pub fn new_from_raw(
+        name: String,
+        input_types: Vec<DataType>,
+        output_type: DataType,
+        frame: WindowFrame,
+        wasm_data: &[u8],
+    ) -> Result<Self, WasmError> {
+        Self::new_from_raw_with_options(
+            name,
+            input_types,
+            output_type,
+            frame,
+            wasm_data,
+            WasmRunnerOptions::default(),
+        )
+    }
+
+    pub fn new_from_raw_with_options(
+        name: String,
+        input_types: Vec<DataType>,
+        output_type: DataType,
+        frame: WindowFrame,
+        wasm_data: &[u8],
+        options: WasmRunnerOptions,
+    ) -> Result<Self, WasmError> {
+        let (engine, module) = options.compile(wasm_data)?;
+        Self::new_with_options(
+            engine,
+            module,
+            name,
+            input_types,
+            output_type,
+            frame,
+            options,
+        )
+    }
+
+    pub fn input_types(&self) -> &[DataType] {
+        &self.input_types
+    }
+
+    pub fn output_type(&self) -> &DataType {
+        &self.output_type
+    }
+
+    pub fn frame(&self) -> &WindowFrame {
+        &self.frame
+    }
+
+    /// Whether the guest is driven through the incremental `_add`/`_retract` entry points.
+    pub fn is_incremental(&self) -> bool {
+        self.incremental.is_some()
+    }
+
+    /// Evaluates the UDF over `partition`, which must be sorted by the frame's order column.
+    pub fn run_partition(&self, partition: &RecordBatch) -> Result<ArrayRef, WasmError> {
+        Ok(self.run_partition_with_stats(partition)?.0)
+    }
+
+    /// Like `run_partition`, also reporting the resources the call consumed. The partition
+    /// counts as one batch of the fuel budget.
+    pub fn run_partition_with_stats(
+        &self,
+        partition: &RecordBatch,
+    ) -> Result<(ArrayRef, RunStats), WasmError> {
+        let partition = &check_input_types(partition, &self.input_types)?;
+        let bounds = self.frame.bounds(partition)?;
+        self.pool.with_instance(|pooled| {
+            pooled.begin_batch(partition.num_rows());
+            let result = match &self.incremental {
+                Some(incremental) => {
+                    self.run_incremental(pooled, incremental, partition, &bounds)?
+                }
+                None => self.run_bulk(pooled, partition, &bounds)?,
+            };
+            Ok((result, pooled.stats()))
+        })
+    }
+
+    fn run_bulk(
+        &self,
+        pooled: &mut PooledInstance,
+        partition: &RecordBatch,
+        bounds: &[Range<usize>],
+    ) -> Result<ArrayRef, WasmError> {
+        let PooledInstance {
+            store, instance, ..
+        } = pooled;
+        let instance = *instance;
+        let func = &self.exports.func;
+        let func_def = get_func(instance, &mut *store, func)?;
+        let memory = get_guest_memory(instance, &mut *store)?;
+
+        let frames = bounds
+            .iter()
+            .flat_map(|frame| [frame.start, frame.end])
+            .map(|bound| {
+                u32::try_from(bound).map_err(|_| WasmError::InvalidInput {
+                    msg: format!("frame bound {} does not fit in a u32", bound),
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .flat_map(u32::to_le_bytes)
+            .collect::<Vec<_>>();
+        let args = [encode_ipc(partition)?, frames];
+        let result_arrow_ipc = with_guest_buffers(instance, store, |store, buffers| {
+            call_with_buffers(instance, store, memory, func_def, func, &args, buffers)
+        })?;
+        let result_batch = decode_ipc(func, &result_arrow_ipc)?;
+        let outputs = Fields::from(vec![Field::new("", self.output_type.clone(), true)]);
+        let result_batch = conform_result_batch(func, &result_batch, &outputs)?;
+        if result_batch.num_rows() != partition.num_rows() {
+            return Err(WasmError::invalid_result(
+                func,
+                format!(
+                    "expected {} rows, got {}",
+                    partition.num_rows(),
+                    result_batch.num_rows()
+                ),
+            ));
+        }
+        Ok(result_batch.column(0).clone())
+    }
+
+    fn run_incremental(
+        &self,
+        pooled: &mut PooledInstance,
+        incremental: &IncrementalExports,
+        partition: &RecordBatch,
+        bounds: &[Range<usize>],
+    ) -> Result<ArrayRef, WasmError> {
+        let PooledInstance {
+            store, instance, ..
+        } = pooled;
+        let instance = *instance;
+        let memory = get_guest_memory(instance, &mut *store)?;
+        let open = get_func(instance, &mut *store, &incremental.open)?;
+        let add = get_func(instance, &mut *store, &incremental.add)?;
+        let retract = get_func(instance, &mut *store, &incremental.retract)?;
+        let evaluate = get_func(instance, &mut *store, &incremental.evaluate)?;
+
+        open.call(&mut *store, &[], &mut [])
+            .map_err(|e| WasmError::from_guest_call(&incremental.open, e))?;
+        let mut frame = 0..0;
+        let mut result_vals = vec![];
+        for bounds in bounds {
+            for row in frame.end.max(bounds.start)..bounds.end {
+                self.call_row(
+                    instance,
+                    store,
+                    memory,
+                    add,
+                    &incremental.add,
+                    partition,
+                    row,
+                )?;
+            }
+            for row in frame.start..bounds.start.min(frame.end) {
+                self.call_row(
+                    instance,
+                    store,
+                    memory,
+                    retract,
+                    &incremental.retract,
+                    partition,
+                    row,
+                )?;
+            }
+            frame = bounds.clone();
+
+            store.data_mut().result_null = false;
+            let mut tmp_result_vals = vec![Val::I64(0)];
+            evaluate
+                .call(&mut *store, &[], &mut tmp_result_vals)
+                .map_err(|e| WasmError::from_guest_call(&incremental.evaluate, e))?;
+            let result_val = match tmp_result_vals.first() {
+                Some(_) if store.data().result_null => None,
+                Some(v) => Some(*v),
+                None => {
+                    return Err(WasmError::invalid_result(
+                        &incremental.evaluate,
+                        "missing result value",
+                    ))
+                }
+            };
+            // each result is copied out and freed before the accumulator moves on
+            result_vals.push(with_guest_buffers(instance, store, |store, buffers| {
+                result_val
+                    .map(|val| {
+                        take_row_result(
+                            store,
+                            memory,
+                            &incremental.evaluate,
+                            &self.output_type,
+                            val,
+                            buffers,
+                        )
+                    })
+                    .transpose()
+            })?);
+        }
+        decode_row_results(&incremental.evaluate, &self.output_type, result_vals)
+    }
+
+    /// Passes the arguments of `row` to `func`, skipping rows with nulls when the UDF returns
+    /// null on null input.
+    #[allow(clippy::too_many_arguments)]
+    fn call_row(
+        &self,
+        instance: Instance,
+        store: &mut Store<HostState>,
+        memory: Memory,
+        func_def: Func,
+        func: &str,
+        partition: &RecordBatch,
+        row: usize,
+    ) -> Result<(), WasmError> {
+        let has_null = partition.columns().iter().any(|array| array.is_null(row));
+        if has_null && self.null_handling == NullHandling::ReturnNullOnNullInput {
+            return Ok(());
+        }
+        with_guest_buffers(instance, store, |store, buffers| {
+            let input_vals = encode_row_args(
+                instance,
+                &mut *store,
+                memory,
+                func,
+                partition.columns(),
+                row,
+                self.null_handling,
+                buffers,
+            )?;
+            func_def
+                .call(&mut *store, &input_vals, &mut [])
+                .map_err(|e| WasmError::from_guest_call(func, e))
+        })
+    }
+}
+
+impl WasmUdfRunner for WasmWindowUdfRunner {
+    /// Treats `batch` as a single partition.
+    fn run(&self, batch: &RecordBatch) -> Result<RecordBatch, WasmError> {
+        Ok(self.run_with_stats(batch)?.0)
+    }
+
+    fn run_with_stats(&self, batch: &RecordBatch) -> Result<(RecordBatch, RunStats), WasmError> {
+        let (result, stats) = self.run_partition_with_stats(batch)?;
+        let schema = Schema::new(vec![Field::new("", self.output_type.clone(), true)]);
+        Ok((RecordBatch::try_new(Arc::new(schema), vec![result])?, stats))
+    }
+}
@@ -0,0 +1,93 @@
+use cellforce_wasm_core::runner::loader::WasmScalarUdfOptions;
+use cellforce_wasm_core::runner::options::NullHandling;
+use std::path::PathBuf;
+
+/// The example UDF module under `data/wasm`.
+pub fn example_wasm() -> Vec<u8> {
+    let root_path = format!(
+        "{}/data",
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).to_str().unwrap()
+    );
+    let path = format!("{}/wasm/cellforce_wasm_udf_examples.wasm", root_path);
+    std::fs::read(path).unwrap()
+}
+
+/// Compiles a module made of `body` and one page of exported memory with a stub allocator:
+/// `wasm_alloc` always returns offset 1024 and `wasm_free` does nothing, which is enough for
+/// guests that handle one small buffer at a time.
+pub fn guest_module(body: &str) -> Vec<u8> {
+    wat::parse_str(format!(
+        r#"
+        (module
+          (memory (export "memory") 1)
+          (func (export "wasm_alloc") (param i32) (result i32) i32.const 1024)
+          (func (export "wasm_free") (param i32))
+          {})
+        "#,
+        body
+    ))
+    .unwrap()
+}
+
+/// Compiles a module made of `body` and a bump allocator: `$alloc`, exported as
+/// `wasm_alloc`, hands out fresh memory from offset 32768 on and grows memory as needed,
+/// returning 0 once it cannot. `wasm_free` takes the pointer and the size and only updates
+/// `$used`, the bytes allocated and not freed, which `wasm_heap_used` reports. The memory below
+/// 32768 is free for the data segments of `body`.
+pub fn bump_allocator_module(body: &str) -> Vec<u8> {
+    wat::parse_str(format!(
+        r#"
+        (module
+          (memory (export "memory") 1)
+          (global $next (mut i32) (i32.const 32768))
+          (global $used (mut i32) (i32.const 0))
+          (func $alloc (export "wasm_alloc") (param $size i32) (result i32)
+            (local $ptr i32)
+            (local $end i32)
+            (local.set $ptr (global.get $next))
+            (local.set $end (i32.add (local.get $ptr) (local.get $size)))
+            (if (i32.gt_u (local.get $end) (i32.mul (memory.size) (i32.const 65536)))
+              (then
+                (if (i32.eq
+                      (memory.grow
+                        (i32.div_u
+                          (i32.sub
+                            (i32.add (local.get $end) (i32.const 65535))
+                            (i32.mul (memory.size) (i32.const 65536)))
+                          (i32.const 65536)))
+                      (i32.const -1))
+                  (then (return (i32.const 0))))))
+            (global.set $next (local.get $end))
+            (global.set $used (i32.add (global.get $used) (local.get $size)))
+            (local.get $ptr))
+          (func (export "wasm_free") (param $ptr i32) (param $size i32)
+            (global.set $used (i32.sub (global.get $used) (local.get $size))))
+          (func (export "wasm_heap_used") (result i32) (global.get $used))
+          {})
+        "#,
+        body
+    ))
+    .unwrap()
+}
+
+/// Escapes `bytes` for the string of a WAT data segment.
+pub fn wat_data(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("\\{:02x}", b)).collect()
+}
+
+/// Options of a row-at-a-time scalar UDF whose export and internal name are both `name`.
+pub fn scalar_spec(
+    name: &str,
+    input_types: &[&str],
+    output_types: &[&str],
+) -> WasmScalarUdfOptions {
+    WasmScalarUdfOptions {
+        export_name: name.to_string(),
+        internal_name: name.to_string(),
+        input_types: input_types.iter().map(|t| t.to_string()).collect(),
+        output_types: output_types.iter().map(|t| t.to_string()).collect(),
+        output_names: vec![],
+        arrow: false,
+        null_handling: NullHandling::default(),
+    }
+}
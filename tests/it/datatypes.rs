@@ -0,0 +1,75 @@
+use std::sync::Arc;
+
+use arrow::datatypes::{DataType, Field, Fields, IntervalUnit, TimeUnit};
+use cellforce_wasm_core::errors::WasmError;
+use cellforce_wasm_core::runner::datatypes::udf_type_to_arrow_type;
+
+#[test]
+fn test_udf_type_to_arrow_type() {
+    let cases = vec![
+        ("int32", DataType::Int32),
+        ("float32", DataType::Float32),
+        ("string", DataType::LargeUtf8),
+        ("decimal(38,10)", DataType::Decimal128(38, 10)),
+        ("decimal(50, 2)", DataType::Decimal256(50, 2)),
+        ("timestamp(ms)", DataType::Timestamp(TimeUnit::Millisecond, None)),
+        (
+            "timestamp(us, UTC)",
+            DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())),
+        ),
+        (
+            "timestamp(ns, +08:00)",
+            DataType::Timestamp(TimeUnit::Nanosecond, Some("+08:00".into())),
+        ),
+        ("duration(s)", DataType::Duration(TimeUnit::Second)),
+        ("interval(year_month)", DataType::Interval(IntervalUnit::YearMonth)),
+        ("interval(month_day_nano)", DataType::Interval(IntervalUnit::MonthDayNano)),
+        (
+            "list<int32>",
+            DataType::List(Arc::new(Field::new_list_field(DataType::Int32, true))),
+        ),
+        (
+            "struct<name: string, at: timestamp(ms, UTC)>",
+            DataType::Struct(Fields::from(vec![
+                Field::new("name", DataType::LargeUtf8, true),
+                Field::new(
+                    "at",
+                    DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())),
+                    true,
+                ),
+            ])),
+        ),
+        (
+            "map<string, list<float64>>",
+            DataType::Map(
+                Arc::new(Field::new(
+                    "entries",
+                    DataType::Struct(Fields::from(vec![
+                        Field::new("key", DataType::LargeUtf8, false),
+                        Field::new(
+                            "value",
+                            DataType::List(Arc::new(Field::new_list_field(DataType::Float64, true))),
+                            true,
+                        ),
+                    ])),
+                    false,
+                )),
+                false,
+            ),
+        ),
+    ];
+    for (udf_type, expected) in cases {
+        assert_eq!(udf_type_to_arrow_type(udf_type).unwrap(), expected, "{}", udf_type);
+    }
+
+    for udf_type in ["decimal(77,2)", "decimal(10)", "timestamp(days)", "interval", "int32(", "list<int32", "struct<>", "map<int32>"] {
+        assert!(
+            matches!(
+                udf_type_to_arrow_type(udf_type),
+                Err(WasmError::UnsupportedType { .. })
+            ),
+            "{}",
+            udf_type
+        );
+    }
+}
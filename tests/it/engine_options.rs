@@ -0,0 +1,161 @@
+use arrow::array::Int32Array;
+use arrow::datatypes::{DataType, Field, Schema};
+use arrow::record_batch::RecordBatch;
+use cellforce_wasm_core::errors::WasmError;
+use cellforce_wasm_core::runner::engine::{EngineOptions, OptLevel, PoolingOptions};
+use cellforce_wasm_core::runner::instance_pool::{InstancePoolOptions, InstanceResetPolicy};
+use cellforce_wasm_core::runner::loader::WasmUdfRunnerLoader;
+use cellforce_wasm_core::runner::options::WasmRunnerOptions;
+use std::sync::Arc;
+
+use crate::common::{guest_module, scalar_spec};
+use crate::wasm_scalar_udf_runner::{create_add_expect_data, create_int_input_data};
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_engine_options() {
+    let (simd_add, spec) = (
+        simd_add_module(),
+        scalar_spec("add", &["int32", "int32"], &["int32"]),
+    );
+    let batch = create_int_input_data();
+
+    // every runner of the loader gets the engine configuration
+    for engine_options in [
+        EngineOptions::default(),
+        EngineOptions {
+            opt_level: OptLevel::None,
+            parallel_compilation: Some(false),
+            pooling: Some(PoolingOptions {
+                total_instances: 4,
+                total_memories: 4,
+                total_tables: 4,
+                max_memory_size: 1 << 20,
+                max_unused_warm_slots: 1,
+            }),
+            ..Default::default()
+        },
+    ] {
+        let loader = WasmUdfRunnerLoader::new().with_engine_options(engine_options.clone());
+        let runner = loader.load_scalar_udf_runner(&spec, &simd_add).unwrap();
+        for _ in 0..3 {
+            let result = runner.run(&batch).unwrap();
+            assert_eq!(result, create_add_expect_data(), "{:?}", engine_options);
+        }
+    }
+
+    // disabled wasm features reject the modules using them
+    let loader = WasmUdfRunnerLoader::new().with_engine_options(EngineOptions {
+        simd: Some(false),
+        relaxed_simd: Some(false),
+        ..Default::default()
+    });
+    let err = loader
+        .load_scalar_udf_runner(&spec, &simd_add)
+        .err()
+        .unwrap();
+    assert!(matches!(err, WasmError::ModuleCompile { .. }), "{}", err);
+}
+
+// Winch only compiles SIMD on x86_64
+#[cfg(target_arch = "x86_64")]
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_winch() {
+    use cellforce_wasm_core::runner::engine::Compiler;
+
+    let (simd_add, spec) = (
+        simd_add_module(),
+        scalar_spec("add", &["int32", "int32"], &["int32"]),
+    );
+    let loader = WasmUdfRunnerLoader::new().with_engine_options(EngineOptions {
+        compiler: Compiler::Winch,
+        ..Default::default()
+    });
+    let runner = loader.load_scalar_udf_runner(&spec, &simd_add).unwrap();
+    let result = runner.run(&create_int_input_data()).unwrap();
+    assert_eq!(result, create_add_expect_data());
+
+    // configurations wasmtime does not support are reported when building the engine
+    let loader = WasmUdfRunnerLoader::new().with_engine_options(EngineOptions {
+        compiler: Compiler::Winch,
+        relaxed_simd: Some(true),
+        ..Default::default()
+    });
+    let err = loader
+        .load_scalar_udf_runner(&spec, &simd_add)
+        .err()
+        .unwrap();
+    assert!(matches!(err, WasmError::ModuleCompile { .. }), "{}", err);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_pooling_allocator() {
+    // returns a counter kept in memory, initialized by a data segment
+    let counter = guest_module(
+        r#"
+        (data (i32.const 0) "\05")
+        (func (export "next") (param i32 i32) (result i32)
+          (local $n i32)
+          (local.set $n (i32.load (i32.const 0)))
+          (i32.store (i32.const 0) (i32.add (local.get $n) (i32.const 1)))
+          (local.get $n))
+        "#,
+    );
+    let spec = scalar_spec("next", &["int32", "int32"], &["int32"]);
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("val1", DataType::Int32, true),
+        Field::new("val2", DataType::Int32, true),
+    ]));
+    let batch = RecordBatch::try_new(
+        schema,
+        vec![
+            Arc::new(Int32Array::from(vec![1, 2, 3])),
+            Arc::new(Int32Array::from(vec![4, 5, 6])),
+        ],
+    )
+    .unwrap();
+    let counts = |result: RecordBatch| {
+        let column = result
+            .column(0)
+            .as_any()
+            .downcast_ref::<Int32Array>()
+            .unwrap();
+        column.values().to_vec()
+    };
+
+    // every batch gets a fresh instance whose memory starts from the module's image, even
+    // though the instances reuse the same slots
+    let loader = WasmUdfRunnerLoader::new().with_pooling_allocator(2);
+    let runner = loader.load_scalar_udf_runner(&spec, &counter).unwrap();
+    for _ in 0..5 {
+        assert_eq!(counts(runner.run(&batch).unwrap()), vec![5, 6, 7]);
+    }
+
+    // warm instances hold their slot, so the pool bounds how many may be alive at once
+    let loader = WasmUdfRunnerLoader::new()
+        .with_runner_options(WasmRunnerOptions {
+            instance_pool: Some(InstancePoolOptions {
+                pool_size: 1,
+                reset_policy: InstanceResetPolicy::Never,
+                max_uses_per_instance: None,
+            }),
+            ..Default::default()
+        })
+        .with_pooling_allocator(1);
+    let warm = loader.load_scalar_udf_runner(&spec, &counter).unwrap();
+    assert_eq!(counts(warm.run(&batch).unwrap()), vec![5, 6, 7]);
+    assert_eq!(counts(warm.run(&batch).unwrap()), vec![8, 9, 10]);
+    let other = loader.load_scalar_udf_runner(&spec, &counter).unwrap();
+    let err = other.run(&batch).err().unwrap();
+    assert!(matches!(err, WasmError::Instantiation { .. }), "{}", err);
+}
+
+/// `add` computed with SIMD instructions.
+fn simd_add_module() -> Vec<u8> {
+    guest_module(
+        r#"
+        (func (export "add") (param i32 i32) (result i32)
+          (i32x4.extract_lane 0
+            (i32x4.add (i32x4.splat (local.get 0)) (i32x4.splat (local.get 1)))))
+        "#,
+    )
+}
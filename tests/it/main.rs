@@ -1,4 +1,14 @@
 #![feature(const_trait_impl)]
 #![feature(trait_upcasting)]
+// `trait_upcasting` is stable on recent toolchains, and the helpers name their batches
+#![allow(stable_features, clippy::let_and_return)]
 
+mod common;
+mod datatypes;
+mod engine_options;
+mod module_cache;
+mod udf_manifest;
+mod wasm_aggregate_udf_runner;
 mod wasm_scalar_udf_runner;
+mod wasm_table_udf_runner;
+mod wasm_window_udf_runner;
@@ -0,0 +1,152 @@
+use cellforce_wasm_core::errors::WasmError;
+use cellforce_wasm_core::runner::loader::{WasmScalarUdfOptions, WasmUdfRunnerLoader};
+use cellforce_wasm_core::runner::module_cache::ModuleCache;
+use cellforce_wasm_core::runner::options::WasmRunnerOptions;
+use sha2::{Digest, Sha256};
+use wasmtime::{Config, Engine};
+
+use crate::common::{example_wasm, guest_module, scalar_spec};
+use crate::wasm_scalar_udf_runner::{create_add_expect_data, create_int_input_data};
+
+/// A module exporting `func(a, b) = op(a, b)` for an `i32` binary instruction `op`.
+fn binary_op_module(func: &str, op: &str) -> Vec<u8> {
+    guest_module(&format!(
+        r#"
+        (func (export "{}") (param i32 i32) (result i32)
+          ({} (local.get 0) (local.get 1)))
+        "#,
+        func, op
+    ))
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_module_cache() {
+    let wasm_data = example_wasm();
+    let cache = ModuleCache::new(Engine::default());
+    let loader = WasmUdfRunnerLoader::new().with_runner_options(WasmRunnerOptions {
+        module_cache: Some(cache.clone()),
+        ..Default::default()
+    });
+    let spec = |name: &str, data_type: &str, arrow| WasmScalarUdfOptions {
+        arrow,
+        ..scalar_spec(name, &[data_type, data_type], &[data_type])
+    };
+
+    // every UDF of the module shares one compilation
+    for (name, arrow) in [("add", false), ("add_arrow", true)] {
+        let runner = loader
+            .load_scalar_udf_runner(&spec(name, "int32", arrow), &wasm_data)
+            .unwrap();
+        assert_eq!(
+            runner.run(&create_int_input_data()).unwrap(),
+            create_add_expect_data()
+        );
+    }
+    for (name, arrow) in [("concat", false), ("concat_arrow", true)] {
+        loader
+            .load_scalar_udf_runner(&spec(name, "string", arrow), &wasm_data)
+            .unwrap();
+    }
+    assert_eq!(cache.len(), 1);
+
+    loader
+        .load_scalar_udf_runner(
+            &spec("add", "int32", false),
+            &binary_op_module("add", "i32.add"),
+        )
+        .unwrap();
+    assert_eq!(cache.len(), 2);
+
+    // modules that fail to compile are not cached
+    let err = loader
+        .load_scalar_udf_runner(&spec("add", "int32", false), b"not wasm")
+        .err()
+        .unwrap();
+    assert!(matches!(err, WasmError::ModuleCompile { .. }), "{}", err);
+    assert_eq!(cache.len(), 2);
+
+    // without a cache passed in, options share one until the shared caches are cleared
+    let options = WasmRunnerOptions::default();
+    let shared = options.module_cache().unwrap();
+    assert_eq!(shared, options.module_cache().unwrap());
+    WasmRunnerOptions::clear_shared_caches();
+    assert_ne!(shared, options.module_cache().unwrap());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_precompiled_modules() {
+    let (add, sub) = (
+        binary_op_module("add", "i32.add"),
+        binary_op_module("sub", "i32.sub"),
+    );
+    let dir = std::env::temp_dir().join(format!("cellforce-artifacts-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let fresh_cache = || ModuleCache::new(Engine::default()).with_artifact_dir(&dir);
+    let artifacts = || {
+        let mut paths = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        paths.sort();
+        paths
+    };
+
+    // compiling stores the artifact along with its digest
+    fresh_cache().compile(&add).unwrap();
+    let add_paths = artifacts();
+    assert_eq!(add_paths.len(), 2);
+    let (add_artifact, add_digest) = (&add_paths[0], &add_paths[1]);
+    assert_eq!(add_artifact.extension().unwrap(), "cwasm");
+
+    // a corrupt artifact is rebuilt from the wasm bytes
+    std::fs::write(add_artifact, b"garbage").unwrap();
+    let module = fresh_cache().compile(&add).unwrap();
+    assert!(module.get_export("add").is_some());
+    let digest = std::fs::read_to_string(add_digest).unwrap();
+    let expected: [u8; 32] = Sha256::digest(std::fs::read(add_artifact).unwrap()).into();
+    assert_eq!(
+        digest,
+        expected
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>()
+    );
+
+    // artifacts whose digest matches are loaded as is: swapping in the artifact of another
+    // module shows that the cache does not compile again
+    fresh_cache().compile(&sub).unwrap();
+    let sub_paths = artifacts()
+        .into_iter()
+        .filter(|path| !add_paths.contains(path))
+        .collect::<Vec<_>>();
+    std::fs::copy(&sub_paths[0], add_artifact).unwrap();
+    std::fs::copy(&sub_paths[1], add_digest).unwrap();
+    let module = fresh_cache().compile(&add).unwrap();
+    assert!(module.get_export("sub").is_some());
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    // the loader only accepts the artifacts it emits once they are trusted explicitly
+    let spec = scalar_spec("add", &["int32", "int32"], &["int32"]);
+    let loader = WasmUdfRunnerLoader::new();
+    let artifact = loader.precompile(&add).unwrap();
+    assert_ne!(artifact, add);
+    let err = loader
+        .load_scalar_udf_runner(&spec, &artifact)
+        .err()
+        .unwrap();
+    assert!(matches!(err, WasmError::InvalidInput { .. }), "{}", err);
+    unsafe { loader.trust_artifact(&artifact) }.unwrap();
+    let runner = loader.load_scalar_udf_runner(&spec, &artifact).unwrap();
+    let batch = create_int_input_data();
+    let result = runner.run(&batch).unwrap();
+    assert_eq!(result, create_add_expect_data());
+    // but not for an engine configured differently
+    let err = loader
+        .load_scalar_udf_runner_async(&spec, &artifact)
+        .err()
+        .unwrap();
+    assert!(matches!(err, WasmError::InvalidInput { .. }), "{}", err);
+    let fuel_cache = ModuleCache::new(Engine::new(Config::new().consume_fuel(true)).unwrap());
+    let err = unsafe { fuel_cache.deserialize_trusted(&artifact) }.unwrap_err();
+    assert!(matches!(err, WasmError::ModuleCompile { .. }), "{}", err);
+}
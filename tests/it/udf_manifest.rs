@@ -0,0 +1,303 @@
+use arrow::array::Int32Array;
+use arrow::datatypes::{DataType, Field, Schema};
+use arrow::record_batch::RecordBatch;
+use cellforce_wasm_core::errors::WasmError;
+use cellforce_wasm_core::runner::fuel::FuelBudget;
+use cellforce_wasm_core::runner::loader::{LoadedUdfRunner, WasmUdfRunnerLoader};
+use cellforce_wasm_core::runner::manifest::{UdfAbi, UdfKind, MANIFEST_SECTION};
+use cellforce_wasm_core::runner::module_cache::ModuleCache;
+use cellforce_wasm_core::runner::options::{NullHandling, WasmRunnerOptions};
+use cellforce_wasm_core::runner::wasi::WasiPolicy;
+use cellforce_wasm_core::runner::window_udf_runner::{
+    WindowFrame, WindowFrameBound, WindowFrameUnits,
+};
+use std::sync::Arc;
+use wasmtime::{Config, Engine};
+
+use crate::common::{guest_module, wat_data};
+
+/// An `add` UDF, with `manifest_export` returned by a `cellforce_manifest` export when set.
+fn add_module(manifest_export: Option<&str>) -> Vec<u8> {
+    let export = match manifest_export {
+        Some(json) => format!(
+            r#"
+            (data (i32.const 2048) "{}")
+            (func (export "cellforce_manifest") (result i64)
+              i64.const {})
+            "#,
+            wat_data(json.as_bytes()),
+            ((json.len() as i64) << 32) | 2048,
+        ),
+        None => String::new(),
+    };
+    guest_module(&format!(
+        r#"
+        (func (export "add") (param i32 i32) (result i32)
+          (i32.add (local.get 0) (local.get 1)))
+        {}
+        "#,
+        export
+    ))
+}
+
+/// Appends a `cellforce_manifest` custom section holding `json` to `wasm`.
+fn with_manifest_section(mut wasm: Vec<u8>, json: &str) -> Vec<u8> {
+    let mut section = leb128(MANIFEST_SECTION.len());
+    section.extend_from_slice(MANIFEST_SECTION.as_bytes());
+    section.extend_from_slice(json.as_bytes());
+    wasm.push(0);
+    wasm.extend(leb128(section.len()));
+    wasm.extend(section);
+    wasm
+}
+
+fn leb128(mut value: usize) -> Vec<u8> {
+    let mut bytes = vec![];
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        match value {
+            0 => return [bytes, vec![byte]].concat(),
+            _ => bytes.push(byte | 0x80),
+        }
+    }
+}
+
+fn create_input_data() -> RecordBatch {
+    let schema = Schema::new(vec![
+        Field::new("val1", DataType::Int32, true),
+        Field::new("val2", DataType::Int32, true),
+    ]);
+    RecordBatch::try_new(
+        Arc::new(schema),
+        vec![
+            Arc::new(Int32Array::from(vec![1, 2])),
+            Arc::new(Int32Array::from(vec![10, 20])),
+        ],
+    )
+    .unwrap()
+}
+
+const ADD_MANIFEST: &str = r#"{
+    "udfs": [
+        {
+            "name": "plus",
+            "export": "add",
+            "kind": "scalar",
+            "input_types": ["int32", "int32"],
+            "output_types": ["int32"]
+        }
+    ]
+}"#;
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_udf_manifest_read() {
+    let loader = WasmUdfRunnerLoader::new();
+    let json = r#"{
+        "udfs": [
+            {
+                "name": "add",
+                "kind": "scalar",
+                "input_types": ["int32", "int32"],
+                "output_types": ["int32"],
+                "abi": "arrow",
+                "null_handling": "called_on_null_input",
+                "deterministic": false
+            },
+            {
+                "name": "moving_sum",
+                "export": "sum",
+                "kind": "window",
+                "input_types": ["int64"],
+                "output_types": ["int64"],
+                "frame": {"units": "rows", "start": {"preceding": 2}, "end": "current_row"}
+            }
+        ]
+    }"#;
+    let manifest = loader
+        .read_manifest(&with_manifest_section(add_module(None), json))
+        .unwrap();
+    let [add, moving_sum] = manifest.udfs.as_slice() else {
+        panic!("{:?}", manifest);
+    };
+    assert_eq!(add.kind, UdfKind::Scalar);
+    assert_eq!(add.export(), "add");
+    assert_eq!(add.abi, UdfAbi::Arrow);
+    assert_eq!(add.null_handling, NullHandling::CalledOnNullInput);
+    assert!(!add.deterministic);
+    assert!(add.scalar_options().unwrap().arrow);
+    let options = moving_sum.window_options().unwrap();
+    assert_eq!(options.internal_name, "sum");
+    assert_eq!(options.null_handling, NullHandling::ReturnNullOnNullInput);
+    assert_eq!(
+        options.frame,
+        WindowFrame {
+            units: WindowFrameUnits::Rows,
+            start: WindowFrameBound::Preceding(2),
+            end: WindowFrameBound::CurrentRow,
+            order_by: None,
+        }
+    );
+    assert!(moving_sum.deterministic);
+    let err = moving_sum.scalar_options().err().unwrap();
+    assert!(matches!(err, WasmError::InvalidManifest { .. }), "{}", err);
+
+    // the section wins over the export
+    let module = with_manifest_section(add_module(Some(r#"{"udfs": []}"#)), ADD_MANIFEST);
+    assert_eq!(loader.read_manifest(&module).unwrap().udfs.len(), 1);
+
+    let invalid = |json: &str| {
+        let err = loader
+            .read_manifest(&with_manifest_section(add_module(None), json))
+            .unwrap_err();
+        assert!(matches!(err, WasmError::InvalidManifest { .. }), "{}", err);
+        err.to_string()
+    };
+    invalid("not json");
+    invalid(r#"{"udfs": [{"name": "add", "kind": "scalar"}]}"#);
+    let duplicate = format!(
+        r#"{{"udfs": [{0}, {0}]}}"#,
+        r#"{"name": "add", "kind": "scalar", "input_types": [], "output_types": ["int32"]}"#
+    );
+    assert!(invalid(&duplicate).contains("declared twice"));
+    let arrow_table = r#"{"udfs": [{"name": "t", "kind": "table", "abi": "arrow",
+        "input_types": [], "output_types": ["int32"]}]}"#;
+    assert!(invalid(arrow_table).contains("arrow abi"));
+    let two_outputs = r#"{"udfs": [{"name": "a", "kind": "aggregate",
+        "input_types": [], "output_types": ["int32", "int32"]}]}"#;
+    assert!(invalid(two_outputs).contains("exactly one output type"));
+
+    let err = loader.read_manifest(&add_module(None)).unwrap_err();
+    assert!(matches!(err, WasmError::InvalidManifest { .. }), "{}", err);
+
+    // a section size whose 5th LEB128 byte overflows 32 bits makes the module malformed,
+    // rather than a size truncated to its low bits
+    let mut malformed = add_module(None);
+    let mut section = leb128(MANIFEST_SECTION.len());
+    section.extend_from_slice(MANIFEST_SECTION.as_bytes());
+    section.extend_from_slice(br#"{"udfs": []}"#);
+    let size = section.len() as u32;
+    assert!(size < 0x80);
+    malformed.extend([0, size as u8 | 0x80, 0x80, 0x80, 0x80, 0x10]);
+    malformed.extend(section);
+    let err = loader.read_manifest(&malformed).unwrap_err();
+    assert!(matches!(err, WasmError::ModuleCompile { .. }), "{}", err);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_udf_manifest_load() {
+    let loader = WasmUdfRunnerLoader::new();
+    let expected = Int32Array::from(vec![11, 22]);
+    let modules = [
+        with_manifest_section(add_module(None), ADD_MANIFEST),
+        add_module(Some(ADD_MANIFEST)),
+    ];
+    for module in modules {
+        let udfs = loader.load_manifest_udfs(&module).unwrap();
+        assert_eq!(udfs.len(), 1);
+        assert_eq!(udfs[0].declaration.name, "plus");
+        let LoadedUdfRunner::Scalar(runner) = &udfs[0].runner else {
+            panic!("expected a scalar runner");
+        };
+        let result = runner.run(&create_input_data()).unwrap();
+        assert_eq!(result.column(0).as_ref(), &expected);
+    }
+
+    // a declaration the module does not implement fails to load
+    let missing = ADD_MANIFEST.replace(r#""export": "add""#, r#""export": "sub""#);
+    let err = loader
+        .load_manifest_udfs(&add_module(Some(&missing)))
+        .err()
+        .unwrap();
+    assert!(matches!(err, WasmError::MissingExport { .. }), "{}", err);
+
+    // precompiled artifacts carry no custom sections, and their export is not run
+    let artifact = loader.precompile(&add_module(Some(ADD_MANIFEST))).unwrap();
+    unsafe { loader.trust_artifact(&artifact) }.unwrap();
+    let err = loader.load_manifest_udfs(&artifact).err().unwrap();
+    assert!(matches!(err, WasmError::InvalidManifest { .. }), "{}", err);
+
+    // the export runs sandboxed, whatever the runner options
+    let spin = guest_module(
+        r#"
+        (func (export "cellforce_manifest") (result i64)
+          (loop $again (br $again))
+          i64.const 0)
+        "#,
+    );
+    let err = loader.read_manifest(&spin).unwrap_err();
+    assert!(matches!(err, WasmError::FuelExhausted { .. }), "{}", err);
+    // traps unless the guest sees an empty environment
+    let env_check = wat::parse_str(
+        r#"
+        (module
+          (import "wasi_snapshot_preview1" "environ_sizes_get"
+            (func $environ_sizes_get (param i32 i32) (result i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 100) "{\"udfs\": []}")
+          (func (export "wasm_alloc") (param i32) (result i32) i32.const 1024)
+          (func (export "wasm_free") (param i32))
+          (func (export "cellforce_manifest") (result i64)
+            (drop (call $environ_sizes_get (i32.const 0) (i32.const 4)))
+            (if (i32.load (i32.const 0)) (then unreachable))
+            i64.const 0x0000000c00000064))
+        "#,
+    )
+    .unwrap();
+    let loader = WasmUdfRunnerLoader::new().with_runner_options(WasmRunnerOptions {
+        wasi: WasiPolicy {
+            env: vec![("SECRET".to_string(), "1".to_string())],
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+    assert!(loader.read_manifest(&env_check).unwrap().udfs.is_empty());
+
+    // the export needs the allocator pair to hand its buffer back
+    let no_free = wat::parse_str(
+        r#"
+        (module
+          (memory (export "memory") 1)
+          (func (export "wasm_alloc") (param i32) (result i32) i32.const 1024)
+          (func (export "cellforce_manifest") (result i64) i64.const 0))
+        "#,
+    )
+    .unwrap();
+    let err = loader.read_manifest(&no_free).unwrap_err();
+    assert!(matches!(err, WasmError::MissingExport { .. }), "{}", err);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_udf_manifest_export_compiles_once() {
+    let module = add_module(Some(ADD_MANIFEST));
+
+    // an engine metering fuel runs the export on the module the UDFs then share
+    let cache = ModuleCache::new(Engine::new(Config::new().consume_fuel(true)).unwrap());
+    let loader = WasmUdfRunnerLoader::new().with_runner_options(WasmRunnerOptions {
+        fuel: Some(FuelBudget::PerBatch(u64::MAX)),
+        module_cache: Some(cache.clone()),
+        ..Default::default()
+    });
+    assert_eq!(loader.read_manifest(&module).unwrap().udfs.len(), 1);
+    assert_eq!(cache.len(), 1);
+    assert_eq!(loader.load_manifest_udfs(&module).unwrap().len(), 1);
+    assert_eq!(cache.len(), 1);
+    let spin = guest_module(
+        r#"
+        (func (export "cellforce_manifest") (result i64)
+          (loop $again (br $again))
+          i64.const 0)
+        "#,
+    );
+    let err = loader.read_manifest(&spin).unwrap_err();
+    assert!(matches!(err, WasmError::FuelExhausted { .. }), "{}", err);
+
+    // other engines leave the export to a throwaway engine that keeps nothing
+    let cache = ModuleCache::new(Engine::default());
+    let loader = WasmUdfRunnerLoader::new().with_runner_options(WasmRunnerOptions {
+        module_cache: Some(cache.clone()),
+        ..Default::default()
+    });
+    assert_eq!(loader.read_manifest(&module).unwrap().udfs.len(), 1);
+    assert_eq!(cache.len(), 0);
+}
@@ -0,0 +1,139 @@
+use arrow::array::{Int32Array, Int64Array};
+use arrow::datatypes::{DataType, Field, Schema};
+use arrow::record_batch::RecordBatch;
+use cellforce_wasm_core::errors::WasmError;
+use cellforce_wasm_core::runner::aggregate_udf_runner::{AggregateState, WasmAggregateUdfRunner};
+use cellforce_wasm_core::runner::ipc::encode_ipc;
+use cellforce_wasm_core::runner::loader::{WasmAggregateUdfOptions, WasmUdfRunnerLoader};
+use cellforce_wasm_core::runner::runner_base::WasmUdfRunner;
+use std::sync::Arc;
+
+use crate::common::{bump_allocator_module, wat_data};
+
+const RESULT_SENTINEL: i64 = 0x1122_3344_5566_7788;
+
+/// A `count_batches` aggregate: the state is a little-endian i64 counter, and the result is
+/// a prebuilt IPC stream whose value is patched in place by `finalize`.
+fn count_batches_module() -> Vec<u8> {
+    let result = RecordBatch::try_new(
+        Arc::new(Schema::new(vec![Field::new(
+            "count",
+            DataType::Int64,
+            false,
+        )])),
+        vec![Arc::new(Int64Array::from(vec![RESULT_SENTINEL]))],
+    )
+    .unwrap();
+    let ipc = encode_ipc(&result).unwrap();
+    let value_offset = ipc
+        .windows(8)
+        .position(|w| w == RESULT_SENTINEL.to_le_bytes())
+        .unwrap();
+    bump_allocator_module(&format!(
+        r#"
+        (data (i32.const 1024) "{}")
+        (func $load (param $state i64) (result i64)
+          (i64.load (i32.wrap_i64 (local.get $state))))
+        (func $store (param $count i64) (result i64)
+          (i64.store (i32.const 512) (local.get $count))
+          i64.const 0x800000200)
+        (func (export "count_batches_init") (result i64)
+          (call $store (i64.const 0)))
+        (func (export "count_batches_update") (param $state i64) (param $batch i64) (result i64)
+          (call $store (i64.add (call $load (local.get $state)) (i64.const 1))))
+        (func (export "count_batches_merge") (param $state i64) (param $other i64) (result i64)
+          (call $store (i64.add (call $load (local.get $state)) (call $load (local.get $other)))))
+        (func (export "count_batches_finalize") (param $state i64) (result i64)
+          (i64.store (i32.const {}) (call $load (local.get $state)))
+          i64.const {})
+        "#,
+        wat_data(&ipc),
+        1024 + value_offset,
+        ((ipc.len() as i64) << 32) | 1024,
+    ))
+}
+
+fn create_input_data(values: Vec<i32>) -> RecordBatch {
+    let schema = Schema::new(vec![Field::new("val1", DataType::Int32, true)]);
+    RecordBatch::try_new(Arc::new(schema), vec![Arc::new(Int32Array::from(values))]).unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_wasm_aggregate_udf_runner() {
+    let wasm_data = count_batches_module();
+    let runner = WasmAggregateUdfRunner::new_from_raw(
+        "count_batches".to_string(),
+        vec![DataType::Int32],
+        DataType::Int64,
+        &wasm_data,
+    )
+    .unwrap();
+
+    // two partitions aggregated separately, then combined
+    let mut left = runner.init().unwrap();
+    for values in [vec![1, 2], vec![3]] {
+        left = runner.update(&left, &create_input_data(values)).unwrap();
+    }
+    let right = runner
+        .update(&runner.init().unwrap(), &create_input_data(vec![4]))
+        .unwrap();
+    let right = AggregateState::from_bytes(right.into_bytes());
+    let merged = runner.merge(&left, &right).unwrap();
+    let result = runner.finalize(&merged).unwrap();
+    assert_eq!(result.num_rows(), 1);
+    assert_eq!(result.column(0).as_ref(), &Int64Array::from(vec![3]));
+
+    let result = runner.run(&create_input_data(vec![5, 6])).unwrap();
+    assert_eq!(result.column(0).as_ref(), &Int64Array::from(vec![1]));
+
+    let err = runner
+        .update(
+            &left,
+            &RecordBatch::try_new(
+                Arc::new(Schema::new(vec![Field::new("val1", DataType::Int64, true)])),
+                vec![Arc::new(Int64Array::from(vec![1]))],
+            )
+            .unwrap(),
+        )
+        .unwrap_err();
+    assert!(matches!(err, WasmError::InvalidInput { .. }), "{}", err);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_wasm_aggregate_udf_runner_loader() {
+    let wasm_data = count_batches_module();
+    let spec = WasmAggregateUdfOptions {
+        export_name: "count_batches".to_string(),
+        internal_name: "count_batches".to_string(),
+        input_types: vec!["int32".to_string()],
+        output_type: "int64".to_string(),
+    };
+    let runner = WasmUdfRunnerLoader::new()
+        .load_aggregate_udf_runner(&spec, &wasm_data)
+        .unwrap();
+    let result = runner.run(&create_input_data(vec![1])).unwrap();
+    assert_eq!(result.column(0).as_ref(), &Int64Array::from(vec![1]));
+
+    let err = WasmAggregateUdfRunner::new_from_raw(
+        "count_rows".to_string(),
+        vec![DataType::Int32],
+        DataType::Int64,
+        &wasm_data,
+    )
+    .err()
+    .unwrap();
+    assert_eq!(
+        err.to_string(),
+        "module does not export function `count_rows_init`"
+    );
+
+    let spec = WasmAggregateUdfOptions {
+        output_type: "string".to_string(),
+        ..spec
+    };
+    let runner = WasmUdfRunnerLoader::new()
+        .load_aggregate_udf_runner(&spec, &wasm_data)
+        .unwrap();
+    let err = runner.run(&create_input_data(vec![1])).unwrap_err();
+    assert!(matches!(err, WasmError::InvalidUdfResult { .. }), "{}", err);
+}
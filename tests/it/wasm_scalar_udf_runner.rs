@@ -1,25 +1,48 @@
-use arrow::array::{Int32Array, StringArray};
-use arrow::compute::concat_batches;
-use arrow::datatypes::{DataType, Field, Schema};
-use arrow::error::ArrowError;
+use arrow::array::{
+    ArrayRef, BinaryArray, BooleanArray, Date64Array, Decimal128Array, DurationMillisecondArray,
+    Int16Array, Int32Array, Int64Array, Int8Array, IntervalDayTimeArray, IntervalMonthDayNanoArray,
+    Int64Builder, LargeBinaryArray, LargeStringArray, LargeStringBuilder, ListArray, MapBuilder,
+    MapFieldNames, StringArray, StructArray, TimestampMicrosecondArray, TimestampMillisecondArray,
+    UInt64Array,
+};
+use arrow::datatypes::{
+    DataType, Field, Fields, Int32Type, IntervalDayTime, IntervalMonthDayNano, IntervalUnit,
+    Schema, TimeUnit,
+};
+use arrow::buffer::OffsetBuffer;
 use arrow::record_batch::RecordBatch;
-use itertools::Itertools;
-use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use cellforce_wasm_core::runner::runner_base::WasmUdfRunner;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::field::{Field as TracingField, Visit};
+use tracing::{span, Event, Level, Metadata, Subscriber};
+use wasmtime::{Config, Engine, Module};
+use cellforce_wasm_core::errors::WasmError;
+use cellforce_wasm_core::runner::datatypes::udf_type_to_arrow_type;
+use cellforce_wasm_core::runner::fuel::FuelBudget;
+use cellforce_wasm_core::runner::guest_output::{
+    GuestOutput, DEFAULT_MAX_CAPTURED_BYTES, GUEST_OUTPUT_TARGET,
+};
+use cellforce_wasm_core::runner::host::{GUEST_LOG_TARGET, GUEST_METRICS_TARGET};
+use cellforce_wasm_core::runner::ipc::encode_ipc;
+use cellforce_wasm_core::runner::limits::ResourceLimits;
+use cellforce_wasm_core::runner::instance_pool::{InstancePoolOptions, InstanceResetPolicy};
+use cellforce_wasm_core::runner::loader::{WasmScalarUdfOptions, WasmUdfRunnerLoader};
+use cellforce_wasm_core::runner::options::{NullHandling, WasmRunnerOptions};
+use cellforce_wasm_core::runner::runner_base::{AsyncWasmUdfRunner, WasmUdfRunner};
 use cellforce_wasm_core::runner::scalar_udf_runner::{WasmArrowScalarUdfRunner, WasmScalarUdfRunner};
+use cellforce_wasm_core::runner::wasi::{GuestStdio, PreopenedDir, WasiPolicy};
+
+use crate::common::{
+    bump_allocator_module, example_wasm, guest_module, scalar_spec, wat_data,
+};
 
 #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
 async fn test_wasm_scalar_udf_runner() {
     let expected_add_result_batch = create_add_expect_data();
     let expected_concat_result_batch = create_concat_expect_data();
 
-    let root_path = format!(
-        "{}/data",
-        PathBuf::from(env!("CARGO_MANIFEST_DIR")).to_str().unwrap()
-    );
-    let path = format!("{}/wasm/cellforce_wasm_udf_examples.wasm", root_path);
-    let wasm_data = std::fs::read(path).unwrap();
+    let wasm_data = example_wasm();
     let runner = WasmScalarUdfRunner::new_from_raw(
         "concat".to_string(),
         vec![DataType::Utf8, DataType::Utf8],
@@ -43,19 +66,12 @@ async fn test_wasm_scalar_udf_runner() {
     assert_eq!(result_batch, expected_add_result_batch);
 }
 
-
 #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
 async fn test_wasm_arrow_scalar_udf_runner() {
     let expected_add_result_batch = create_add_expect_data();
     let expected_concat_result_batch = create_concat_expect_data();
 
-    let root_path = format!(
-        "{}/data",
-        PathBuf::from(env!("CARGO_MANIFEST_DIR")).to_str().unwrap()
-    );
-
-    let path = format!("{}/wasm/cellforce_wasm_udf_examples.wasm", root_path);
-    let wasm_data = std::fs::read(path).unwrap();
+    let wasm_data = example_wasm();
     let runner = WasmArrowScalarUdfRunner::new_from_raw(
         "concat_arrow".to_string(),
         vec![DataType::Utf8, DataType::Utf8],
@@ -79,7 +95,112 @@ async fn test_wasm_arrow_scalar_udf_runner() {
     assert_eq!(result_batch, expected_add_result_batch);
 }
 
-fn create_int_input_data() -> RecordBatch {
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_wasm_scalar_udf_runner_with_instance_pool() {
+    let expected_add_result_batch = create_add_expect_data();
+    let expected_concat_result_batch = create_concat_expect_data();
+
+    let wasm_data = example_wasm();
+    let options = WasmRunnerOptions {
+        instance_pool: Some(InstancePoolOptions {
+            pool_size: 1,
+            reset_policy: InstanceResetPolicy::Never,
+            max_uses_per_instance: Some(2),
+        }),
+        ..Default::default()
+    };
+
+    let runner = WasmScalarUdfRunner::new_from_raw_with_options(
+        "add".to_string(),
+        vec![DataType::Int32, DataType::Int32],
+        DataType::Int32,
+        &wasm_data,
+        options.clone(),
+    )
+    .unwrap();
+    let batch = create_int_input_data();
+    for _ in 0..3 {
+        let result_batch = runner.run(&batch).unwrap();
+        assert_eq!(result_batch, expected_add_result_batch);
+    }
+
+    let runner = WasmArrowScalarUdfRunner::new_from_raw_with_options(
+        "concat_arrow".to_string(),
+        vec![DataType::Utf8, DataType::Utf8],
+        DataType::Utf8,
+        &wasm_data,
+        options,
+    )
+    .unwrap();
+    let batch = create_str_input_data();
+    for _ in 0..3 {
+        let result_batch = runner.run(&batch).unwrap();
+        assert_eq!(result_batch, expected_concat_result_batch);
+    }
+
+    // a guest counting its calls in a global shows which instance served each run; a
+    // negative argument makes it trap after counting
+    let counter = guest_module(
+        r#"
+        (global $calls (mut i32) (i32.const 0))
+        (func (export "count") (param $x i32) (result i32)
+          (global.set $calls (i32.add (global.get $calls) (i32.const 1)))
+          (if (i32.lt_s (local.get $x) (i32.const 0)) (then unreachable))
+          global.get $calls)
+        "#,
+    );
+    let counter_runner = |reset_policy, max_uses_per_instance| {
+        WasmScalarUdfRunner::new_from_raw_with_options(
+            "count".to_string(),
+            vec![DataType::Int32],
+            DataType::Int32,
+            &counter,
+            WasmRunnerOptions {
+                instance_pool: Some(InstancePoolOptions {
+                    pool_size: 1,
+                    reset_policy,
+                    max_uses_per_instance,
+                }),
+                ..Default::default()
+            },
+        )
+        .unwrap()
+    };
+    let schema = Arc::new(Schema::new(vec![Field::new("x", DataType::Int32, true)]));
+    let arg = |x: i32| {
+        RecordBatch::try_new(schema.clone(), vec![Arc::new(Int32Array::from(vec![x]))]).unwrap()
+    };
+    let calls = |runner: &WasmScalarUdfRunner, args: &[i32]| {
+        args.iter()
+            .map(|x| {
+                let result = runner.run(&arg(*x)).ok()?;
+                let column = result.column(0).as_any().downcast_ref::<Int32Array>().unwrap();
+                Some(column.value(0))
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // the instance is reused, and retired once it served max_uses_per_instance runs
+    let runner = counter_runner(InstanceResetPolicy::Never, None);
+    assert_eq!(calls(&runner, &[0, 0, 0]), vec![Some(1), Some(2), Some(3)]);
+    let runner = counter_runner(InstanceResetPolicy::Never, Some(2));
+    assert_eq!(
+        calls(&runner, &[0, 0, 0, 0, 0]),
+        vec![Some(1), Some(2), Some(1), Some(2), Some(1)]
+    );
+    // a failed run keeps the instance unless the reset policy discards it
+    let runner = counter_runner(InstanceResetPolicy::Never, None);
+    assert_eq!(calls(&runner, &[0, -1, 0]), vec![Some(1), None, Some(3)]);
+    let runner = counter_runner(InstanceResetPolicy::OnError, None);
+    assert_eq!(
+        calls(&runner, &[0, 0, -1, 0]),
+        vec![Some(1), Some(2), None, Some(1)]
+    );
+    let runner = counter_runner(InstanceResetPolicy::Always, None);
+    assert_eq!(calls(&runner, &[0, 0, 0]), vec![Some(1), Some(1), Some(1)]);
+}
+
+pub(crate) fn create_int_input_data() -> RecordBatch {
     // define schema
     let schema = Schema::new(vec![
         Field::new("val1", DataType::Int32, true),
@@ -126,7 +247,7 @@ fn create_str_input_data() -> RecordBatch {
     batch
 }
 
-fn create_add_expect_data() -> RecordBatch {
+pub(crate) fn create_add_expect_data() -> RecordBatch {
     let schema = Schema::new(vec![Field::new("", DataType::Int32, true)]);
     let contents = Int32Array::from(vec![14]);
     let batch =
@@ -143,3 +264,1523 @@ fn create_concat_expect_data() -> RecordBatch {
             .unwrap();
     batch
 }
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_wasm_scalar_udf_runner_rejects_bad_exports() {
+    let wasm_data = example_wasm();
+
+    let err = WasmScalarUdfRunner::new_from_raw(
+        "does_not_exist".to_string(),
+        vec![DataType::Int32, DataType::Int32],
+        DataType::Int32,
+        &wasm_data,
+    )
+    .err()
+    .unwrap();
+    assert_eq!(
+        err.to_string(),
+        "module does not export function `does_not_exist`"
+    );
+
+    let err = WasmScalarUdfRunner::new_from_raw(
+        "add".to_string(),
+        vec![DataType::Int64, DataType::Int64],
+        DataType::Int64,
+        &wasm_data,
+    )
+    .err()
+    .unwrap();
+    assert_eq!(
+        err.to_string(),
+        "export `add` has signature (i32, i32) -> (i32), expected (i64, i64) -> (i64)"
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_wasm_scalar_udf_runner_reports_errors() {
+    let wasm_data = example_wasm();
+
+    let err = WasmScalarUdfRunner::new_from_raw(
+        "add".to_string(),
+        vec![DataType::Int32, DataType::Int32],
+        DataType::Int32,
+        b"not a wasm module",
+    )
+    .err()
+    .unwrap();
+    assert!(matches!(err, WasmError::ModuleCompile { .. }));
+
+    let runner = WasmScalarUdfRunner::new_from_raw(
+        "add".to_string(),
+        vec![DataType::Int32, DataType::Int32],
+        DataType::Int32,
+        &wasm_data,
+    )
+    .unwrap();
+    let err = runner.run(&create_str_input_data()).unwrap_err();
+    assert!(matches!(err, WasmError::InvalidInput { .. }));
+
+    let runner = WasmScalarUdfRunner::new_from_raw(
+        "concat".to_string(),
+        vec![DataType::Utf8, DataType::Utf8],
+        DataType::Utf8,
+        &wasm_data,
+    )
+    .unwrap();
+    let err = runner.run(&create_one_col_str_input_data()).unwrap_err();
+    assert!(matches!(err, WasmError::InvalidInput { .. }), "{}", err);
+
+    let spec = scalar_spec("add", &["int32", "uuid"], &["int32"]);
+    let err = WasmUdfRunnerLoader::new()
+        .load_scalar_udf_runner(&spec, &wasm_data)
+        .err()
+        .unwrap();
+    assert!(matches!(err, WasmError::UnsupportedType { data_type } if data_type == "uuid"));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_wasm_scalar_udf_runner_null_handling() {
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("val1", DataType::Int32, true),
+        Field::new("val2", DataType::Int32, true),
+    ]));
+    let batch = RecordBatch::try_new(
+        schema,
+        vec![
+            Arc::new(Int32Array::from(vec![Some(1), None, None])),
+            Arc::new(Int32Array::from(vec![Some(2), Some(3), None])),
+        ],
+    )
+    .unwrap();
+
+    let wasm_data = example_wasm();
+    let runner = WasmScalarUdfRunner::new_from_raw(
+        "add".to_string(),
+        vec![DataType::Int32, DataType::Int32],
+        DataType::Int32,
+        &wasm_data,
+    )
+    .unwrap();
+    let result_batch = runner.run(&batch).unwrap();
+    assert_eq!(
+        result_batch.column(0).as_ref(),
+        &Int32Array::from(vec![Some(3), None, None])
+    );
+
+    let wasm_data = wat::parse_str(
+        r#"
+        (module
+          (import "cellforce_host" "set_result_null" (func $set_result_null))
+          (memory (export "memory") 1)
+          (func (export "wasm_alloc") (param i32) (result i32) i32.const 1024)
+          (func (export "wasm_free") (param i32))
+          (func (export "coalesce")
+            (param $a i32) (param $a_null i32) (param $b i32) (param $b_null i32)
+            (result i32)
+            (if (i32.eqz (local.get $a_null)) (then (return (local.get $a))))
+            (if (i32.eqz (local.get $b_null)) (then (return (local.get $b))))
+            call $set_result_null
+            i32.const 0))
+        "#,
+    )
+    .unwrap();
+    let runner = WasmScalarUdfRunner::new_from_raw_with_options(
+        "coalesce".to_string(),
+        vec![DataType::Int32, DataType::Int32],
+        DataType::Int32,
+        &wasm_data,
+        WasmRunnerOptions {
+            null_handling: NullHandling::CalledOnNullInput,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let result_batch = runner.run(&batch).unwrap();
+    assert_eq!(
+        result_batch.column(0).as_ref(),
+        &Int32Array::from(vec![Some(1), Some(3), None])
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_wasm_scalar_udf_runner_types() {
+    let wasm_data = example_wasm();
+    let spec = scalar_spec("concat", &["string", "string"], &["string"]);
+    let runner = WasmUdfRunnerLoader::new()
+        .load_scalar_udf_runner(&spec, &wasm_data)
+        .unwrap();
+    let schema = Schema::new(vec![
+        Field::new("val1", DataType::LargeUtf8, true),
+        Field::new("val2", DataType::LargeUtf8, true),
+    ]);
+    let batch = RecordBatch::try_new(
+        Arc::new(schema),
+        vec![
+            Arc::new(LargeStringArray::from(vec!["hello"])),
+            Arc::new(LargeStringArray::from(vec!["world"])),
+        ],
+    )
+    .unwrap();
+    let result_batch = runner.run(&batch).unwrap();
+    assert_eq!(
+        result_batch.column(0).as_ref(),
+        &LargeStringArray::from(vec!["helloworld"])
+    );
+
+    let wasm_data = bump_allocator_module(
+        r#"
+        (func (export "not") (param i32) (result i32)
+          local.get 0
+          i32.eqz)
+        (func (export "negate") (param i32) (result i32)
+          i32.const 0
+          local.get 0
+          i32.sub)
+        (func (export "increment") (param i64) (result i64)
+          local.get 0
+          i64.const 1
+          i64.add)
+        (func (export "identity") (param i64) (result i64)
+          local.get 0)
+        "#,
+    );
+
+    let list_type = udf_type_to_arrow_type("list<int32>").unwrap();
+    let list_array: ArrayRef = Arc::new(ListArray::from_iter_primitive::<Int32Type, _, _>(vec![
+        Some(vec![Some(1), None, Some(3)]),
+        None,
+        Some(vec![]),
+    ]));
+    let struct_type = udf_type_to_arrow_type("struct<name: string, at: timestamp(ms, UTC)>").unwrap();
+    let DataType::Struct(struct_fields) = &struct_type else {
+        unreachable!()
+    };
+    let struct_array: ArrayRef = Arc::new(StructArray::new(
+        struct_fields.clone(),
+        vec![
+            Arc::new(LargeStringArray::from(vec![Some("a"), None])),
+            Arc::new(TimestampMillisecondArray::from(vec![None, Some(1_000)]).with_timezone("UTC")),
+        ],
+        None,
+    ));
+    let map_type = udf_type_to_arrow_type("map<string, int64>").unwrap();
+    let mut map_builder = MapBuilder::new(
+        Some(MapFieldNames {
+            entry: "entries".to_string(),
+            key: "key".to_string(),
+            value: "value".to_string(),
+        }),
+        LargeStringBuilder::new(),
+        Int64Builder::new(),
+    );
+    map_builder.keys().append_value("x");
+    map_builder.values().append_value(1);
+    map_builder.append(true).unwrap();
+    map_builder.append(true).unwrap();
+    let map_array: ArrayRef = Arc::new(map_builder.finish());
+    // inputs only need to match the declared types up to nested field names and nullability,
+    // and reach the guest relabelled with the declared types
+    let mut default_map_builder =
+        MapBuilder::new(None, LargeStringBuilder::new(), Int64Builder::new());
+    default_map_builder.keys().append_value("x");
+    default_map_builder.values().append_value(1);
+    default_map_builder.append(true).unwrap();
+    default_map_builder.append(true).unwrap();
+    let default_map_array: ArrayRef = Arc::new(default_map_builder.finish());
+    let required_list_array: ArrayRef = Arc::new(ListArray::new(
+        Arc::new(Field::new_list_field(DataType::Int32, false)),
+        OffsetBuffer::from_lengths([2, 0]),
+        Arc::new(Int32Array::from(vec![1, 2])),
+        None,
+    ));
+    let required_struct_array: ArrayRef = Arc::new(StructArray::new(
+        Fields::from(vec![
+            Field::new("name", DataType::LargeUtf8, false),
+            Field::new("at", DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())), false),
+        ]),
+        vec![
+            Arc::new(LargeStringArray::from(vec!["a"])),
+            Arc::new(TimestampMillisecondArray::from(vec![1_000]).with_timezone("UTC")),
+        ],
+        None,
+    ));
+    let cases: Vec<(&str, DataType, ArrayRef, ArrayRef)> = vec![
+        (
+            "not",
+            DataType::Boolean,
+            Arc::new(BooleanArray::from(vec![true, false])),
+            Arc::new(BooleanArray::from(vec![false, true])),
+        ),
+        (
+            "negate",
+            DataType::Int8,
+            Arc::new(Int8Array::from(vec![1, -128])),
+            Arc::new(Int8Array::from(vec![-1, -128])),
+        ),
+        (
+            "negate",
+            DataType::Int16,
+            Arc::new(Int16Array::from(vec![300])),
+            Arc::new(Int16Array::from(vec![-300])),
+        ),
+        (
+            "increment",
+            DataType::UInt64,
+            Arc::new(UInt64Array::from(vec![u64::MAX - 1])),
+            Arc::new(UInt64Array::from(vec![u64::MAX])),
+        ),
+        (
+            "increment",
+            DataType::Date64,
+            Arc::new(Date64Array::from(vec![86_400_000])),
+            Arc::new(Date64Array::from(vec![86_400_001])),
+        ),
+        (
+            "identity",
+            DataType::Binary,
+            Arc::new(BinaryArray::from(vec![b"\x00\x01".as_ref(), b"".as_ref()])),
+            Arc::new(BinaryArray::from(vec![b"\x00\x01".as_ref(), b"".as_ref()])),
+        ),
+        (
+            "identity",
+            DataType::LargeBinary,
+            Arc::new(LargeBinaryArray::from(vec![b"wasm".as_ref()])),
+            Arc::new(LargeBinaryArray::from(vec![b"wasm".as_ref()])),
+        ),
+        (
+            "identity",
+            DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())),
+            Arc::new(TimestampMicrosecondArray::from(vec![1_700_000_000_000_000]).with_timezone("UTC")),
+            Arc::new(TimestampMicrosecondArray::from(vec![1_700_000_000_000_000]).with_timezone("UTC")),
+        ),
+        (
+            "identity",
+            list_type.clone(),
+            list_array.clone(),
+            list_array,
+        ),
+        (
+            "identity",
+            struct_type.clone(),
+            struct_array.clone(),
+            struct_array,
+        ),
+        (
+            "identity",
+            map_type.clone(),
+            map_array.clone(),
+            map_array.clone(),
+        ),
+        (
+            "identity",
+            map_type.clone(),
+            default_map_array,
+            map_array,
+        ),
+        (
+            "identity",
+            list_type.clone(),
+            required_list_array,
+            Arc::new(ListArray::from_iter_primitive::<Int32Type, _, _>(vec![
+                Some(vec![Some(1), Some(2)]),
+                Some(vec![]),
+            ])),
+        ),
+        (
+            "identity",
+            struct_type.clone(),
+            required_struct_array,
+            Arc::new(StructArray::new(
+                struct_fields.clone(),
+                vec![
+                    Arc::new(LargeStringArray::from(vec!["a"])),
+                    Arc::new(TimestampMillisecondArray::from(vec![1_000]).with_timezone("UTC")),
+                ],
+                None,
+            )),
+        ),
+        (
+            "increment",
+            DataType::Duration(TimeUnit::Millisecond),
+            Arc::new(DurationMillisecondArray::from(vec![1_000])),
+            Arc::new(DurationMillisecondArray::from(vec![1_001])),
+        ),
+        (
+            "identity",
+            DataType::Interval(IntervalUnit::DayTime),
+            Arc::new(IntervalDayTimeArray::from(vec![IntervalDayTime::new(-2, 500)])),
+            Arc::new(IntervalDayTimeArray::from(vec![IntervalDayTime::new(-2, 500)])),
+        ),
+        (
+            "identity",
+            DataType::Interval(IntervalUnit::MonthDayNano),
+            Arc::new(IntervalMonthDayNanoArray::from(vec![IntervalMonthDayNano::new(1, -2, 3)])),
+            Arc::new(IntervalMonthDayNanoArray::from(vec![IntervalMonthDayNano::new(1, -2, 3)])),
+        ),
+        (
+            "identity",
+            DataType::Decimal128(38, 10),
+            Arc::new(
+                Decimal128Array::from(vec![-123_456_789_012_345_678_901_234_567i128])
+                    .with_precision_and_scale(38, 10)
+                    .unwrap(),
+            ),
+            Arc::new(
+                Decimal128Array::from(vec![-123_456_789_012_345_678_901_234_567i128])
+                    .with_precision_and_scale(38, 10)
+                    .unwrap(),
+            ),
+        ),
+    ];
+    for (func, data_type, input, expected) in cases {
+        let runner = WasmScalarUdfRunner::new_from_raw(
+            func.to_string(),
+            vec![data_type.clone()],
+            data_type.clone(),
+            &wasm_data,
+        )
+        .unwrap();
+        let schema = Schema::new(vec![Field::new("val1", input.data_type().clone(), true)]);
+        let batch = RecordBatch::try_new(Arc::new(schema), vec![input]).unwrap();
+        let result_batch = runner.run(&batch).unwrap();
+        assert_eq!(result_batch.column(0), &expected, "{}", func);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_wasm_arrow_scalar_udf_runner_checks_result_type() {
+    let wasm_data = example_wasm();
+    let runner = WasmArrowScalarUdfRunner::new_from_raw(
+        "add_arrow".to_string(),
+        vec![DataType::Int32, DataType::Int32],
+        DataType::Int64,
+        &wasm_data,
+    )
+    .unwrap();
+    let err = runner.run(&create_int_input_data()).unwrap_err();
+    assert!(matches!(err, WasmError::InvalidUdfResult { .. }), "{}", err);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_wasm_scalar_udf_runner_multiple_outputs() {
+    let outputs = vec![
+        Field::new("quot", DataType::Int32, true),
+        Field::new("rem", DataType::Int32, true),
+    ];
+
+    // the row runner receives all outputs of a row as one struct; `identity` echoes its input
+    let wasm_data = bump_allocator_module(
+        r#"
+        (func (export "identity") (param i64) (result i64)
+          local.get 0)
+        "#,
+    );
+    let runner = WasmScalarUdfRunner::new_from_raw_with_outputs(
+        "identity".to_string(),
+        vec![DataType::Struct(outputs.clone().into())],
+        outputs.clone(),
+        &wasm_data,
+        WasmRunnerOptions::default(),
+    )
+    .unwrap();
+    let input = StructArray::new(
+        outputs.clone().into(),
+        vec![
+            Arc::new(Int32Array::from(vec![Some(3), Some(4), None])),
+            Arc::new(Int32Array::from(vec![Some(1), Some(0), Some(2)])),
+        ],
+        Some(vec![true, false, true].into()),
+    );
+    let schema = Schema::new(vec![Field::new("pair", DataType::Struct(outputs.clone().into()), true)]);
+    let batch = RecordBatch::try_new(Arc::new(schema), vec![Arc::new(input)]).unwrap();
+    let result_batch = runner.run(&batch).unwrap();
+    let expected = RecordBatch::try_new(
+        Arc::new(Schema::new(outputs.clone())),
+        vec![
+            Arc::new(Int32Array::from(vec![Some(3), None, None])),
+            Arc::new(Int32Array::from(vec![Some(1), None, Some(2)])),
+        ],
+    )
+    .unwrap();
+    assert_eq!(result_batch, expected);
+
+    // the arrow runner receives a batch with one column per output, renamed to the spec
+    let guest_result = RecordBatch::try_new(
+        Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Int32, false),
+            Field::new("b", DataType::Int32, false),
+        ])),
+        vec![
+            Arc::new(Int32Array::from(vec![3])),
+            Arc::new(Int32Array::from(vec![1])),
+        ],
+    )
+    .unwrap();
+    let ipc = encode_ipc(&guest_result).unwrap();
+    let wasm_data = bump_allocator_module(&format!(
+        r#"
+        (data (i32.const 1024) "{}")
+        (func (export "divmod") (param i64 i64) (result i64)
+          i64.const {})
+        "#,
+        wat_data(&ipc),
+        ((ipc.len() as i64) << 32) | 1024,
+    ));
+    let runner = WasmArrowScalarUdfRunner::new_from_raw_with_outputs(
+        "divmod".to_string(),
+        vec![DataType::Int32, DataType::Int32],
+        outputs.clone(),
+        &wasm_data,
+        WasmRunnerOptions::default(),
+    )
+    .unwrap();
+    let result_batch = runner.run(&create_int_input_data()).unwrap();
+    let expected = RecordBatch::try_new(
+        Arc::new(Schema::new(outputs.clone())),
+        vec![
+            Arc::new(Int32Array::from(vec![3])),
+            Arc::new(Int32Array::from(vec![1])),
+        ],
+    )
+    .unwrap();
+    assert_eq!(result_batch, expected);
+
+    let runner = WasmArrowScalarUdfRunner::new_from_raw_with_outputs(
+        "divmod".to_string(),
+        vec![DataType::Int32, DataType::Int32],
+        outputs[..1].to_vec(),
+        &wasm_data,
+        WasmRunnerOptions::default(),
+    )
+    .unwrap();
+    let err = runner.run(&create_int_input_data()).unwrap_err();
+    assert!(matches!(err, WasmError::InvalidUdfResult { .. }), "{}", err);
+
+    let spec = WasmScalarUdfOptions {
+        output_names: vec!["quot".to_string()],
+        arrow: true,
+        ..scalar_spec("divmod", &["int32", "int32"], &["int32", "int32"])
+    };
+    let err = WasmUdfRunnerLoader::new()
+        .load_scalar_udf_runner(&spec, &wasm_data)
+        .err()
+        .unwrap();
+    assert!(matches!(err, WasmError::InvalidInput { .. }), "{}", err);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_wasm_scalar_udf_runner_stream() {
+    // `row_number` numbers the rows of a whole stream; `close` traps once more than
+    // `$limit` rows were seen
+    let wasm_data = guest_module(
+        r#"
+        (global $count (mut i32) (i32.const 0))
+        (global $limit (mut i32) (i32.const 0))
+        (func (export "row_number_open")
+          (global.set $count (i32.const 0))
+          (global.set $limit (i32.const 3)))
+        (func (export "row_number_close")
+          (if (i32.gt_u (global.get $count) (global.get $limit)) (then unreachable)))
+        (func (export "row_number") (param i32) (result i32)
+          (global.set $count (i32.add (global.get $count) (i32.const 1)))
+          global.get $count)
+        "#,
+    );
+    let runner = WasmScalarUdfRunner::new_from_raw(
+        "row_number".to_string(),
+        vec![DataType::Int32],
+        DataType::Int32,
+        &wasm_data,
+    )
+    .unwrap();
+    let create_batch = |len: i32| {
+        let schema = Schema::new(vec![Field::new("val1", DataType::Int32, true)]);
+        RecordBatch::try_new(Arc::new(schema), vec![Arc::new(Int32Array::from_iter_values(0..len))])
+            .unwrap()
+    };
+
+    let results = runner
+        .run_stream(Box::new(vec![Ok(create_batch(2)), Ok(create_batch(1))].into_iter()))
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(results[0].column(0).as_ref(), &Int32Array::from(vec![1, 2]));
+    assert_eq!(results[1].column(0).as_ref(), &Int32Array::from(vec![3]));
+
+    // input batches are only pulled when an output is requested
+    let pulled = std::cell::Cell::new(0);
+    let input = (0..).map(|_| {
+        pulled.set(pulled.get() + 1);
+        Ok(create_batch(1))
+    });
+    let results = runner.run_stream(Box::new(input)).take(2).count();
+    assert_eq!((results, pulled.get()), (2, 2));
+
+    let mut results = runner.run_stream(Box::new(vec![Ok(create_batch(4))].into_iter()));
+    assert!(results.next().unwrap().is_ok());
+    let err = results.next().unwrap().unwrap_err();
+    assert!(
+        matches!(&err, WasmError::GuestTrap { func, .. } if func == "row_number_close"),
+        "{}",
+        err
+    );
+    assert!(results.next().is_none());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_wasm_scalar_udf_runner_async() {
+    let wasm_data = example_wasm();
+    let loader = WasmUdfRunnerLoader::new();
+    for (name, arrow) in [("add", false), ("add_arrow", true)] {
+        let spec = WasmScalarUdfOptions {
+            arrow,
+            ..scalar_spec(name, &["int32", "int32"], &["int32"])
+        };
+        let runner = loader.load_scalar_udf_runner_async(&spec, &wasm_data).unwrap();
+        let result_batch = runner.run_async(&create_int_input_data()).await.unwrap();
+        assert_eq!(result_batch, create_add_expect_data());
+    }
+
+    // the blocking entry point is refused on an async engine
+    let options = WasmRunnerOptions {
+        async_support: true,
+        ..Default::default()
+    };
+    let runner = WasmScalarUdfRunner::new_from_raw_with_options(
+        "add".to_string(),
+        vec![DataType::Int32, DataType::Int32],
+        DataType::Int32,
+        &wasm_data,
+        options.clone(),
+    )
+    .unwrap();
+    let err = runner.run(&create_int_input_data()).unwrap_err();
+    assert!(matches!(err, WasmError::InvalidInput { .. }), "{}", err);
+
+    // and the async one on a synchronous engine
+    let sync_runner = WasmScalarUdfRunner::new_from_raw(
+        "add".to_string(),
+        vec![DataType::Int32, DataType::Int32],
+        DataType::Int32,
+        &wasm_data,
+    )
+    .unwrap();
+    let err = sync_runner
+        .run_async(&create_int_input_data())
+        .await
+        .unwrap_err();
+    assert!(matches!(err, WasmError::InvalidInput { .. }), "{}", err);
+
+    // a long-running guest lets other futures of the same task make progress
+    let wasm_data = guest_module(
+        r#"
+        (func (export "spin") (param $n i32) (result i32)
+          (local $i i32)
+          (loop $again
+            (local.set $i (i32.add (local.get $i) (i32.const 1)))
+            (br_if $again (i32.lt_u (local.get $i) (local.get $n))))
+          local.get $i)
+        "#,
+    );
+    let runner = WasmScalarUdfRunner::new_from_raw_with_options(
+        "spin".to_string(),
+        vec![DataType::Int32],
+        DataType::Int32,
+        &wasm_data,
+        WasmRunnerOptions {
+            timeout: Some(Duration::from_secs(60)),
+            ..options
+        },
+    )
+    .unwrap();
+    let schema = Schema::new(vec![Field::new("val1", DataType::Int32, true)]);
+    let batch = RecordBatch::try_new(
+        Arc::new(schema),
+        vec![Arc::new(Int32Array::from(vec![1_000_000]))],
+    )
+    .unwrap();
+    let done = std::cell::Cell::new(false);
+    let mut ticks = 0;
+    let (result, ()) = tokio::join!(
+        async {
+            let result = runner.run_async(&batch).await;
+            done.set(true);
+            result
+        },
+        async {
+            while !done.get() {
+                ticks += 1;
+                tokio::task::yield_now().await;
+            }
+        }
+    );
+    assert_eq!(result.unwrap().column(0).as_ref(), &Int32Array::from(vec![1_000_000]));
+    assert!(ticks > 1, "{}", ticks);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_wasm_scalar_udf_runner_fuel() {
+    // `spin` loops `n` times
+    let wasm_data = guest_module(
+        r#"
+        (func (export "spin") (param $n i32) (result i32)
+          (local $i i32)
+          (loop $again
+            (local.set $i (i32.add (local.get $i) (i32.const 1)))
+            (br_if $again (i32.lt_u (local.get $i) (local.get $n))))
+          local.get $i)
+        "#,
+    );
+    let create_runner = |fuel| {
+        WasmScalarUdfRunner::new_from_raw_with_options(
+            "spin".to_string(),
+            vec![DataType::Int32],
+            DataType::Int32,
+            &wasm_data,
+            WasmRunnerOptions {
+                fuel: Some(fuel),
+                ..Default::default()
+            },
+        )
+        .unwrap()
+    };
+    let create_batch = |values: Vec<i32>| {
+        let schema = Schema::new(vec![Field::new("val1", DataType::Int32, true)]);
+        RecordBatch::try_new(Arc::new(schema), vec![Arc::new(Int32Array::from(values))]).unwrap()
+    };
+    let is_fuel_exhausted =
+        |err: &WasmError| matches!(err, WasmError::FuelExhausted { func } if func == "spin");
+
+    // each iteration of the loop costs 8 units of fuel
+    let runner = create_runner(FuelBudget::PerRow(100_000));
+    let (result, stats) = runner.run_with_stats(&create_batch(vec![5000, 10000])).unwrap();
+    assert_eq!(result.column(0).as_ref(), &Int32Array::from(vec![5000, 10000]));
+    assert!((120_000..121_000).contains(&stats.fuel_consumed), "{:?}", stats);
+    let err = runner.run(&create_batch(vec![1, 20000])).unwrap_err();
+    assert!(is_fuel_exhausted(&err), "{}", err);
+    // the next call gets a fresh budget
+    assert!(runner.run(&create_batch(vec![10000])).is_ok());
+
+    let runner = create_runner(FuelBudget::PerBatch(100_000));
+    assert!(runner.run(&create_batch(vec![5000, 5000])).is_ok());
+    let err = runner.run(&create_batch(vec![5000, 10000])).unwrap_err();
+    assert!(is_fuel_exhausted(&err), "{}", err);
+
+    let runner = create_runner(FuelBudget::PerStream(100_000));
+    let results = runner
+        .run_stream(Box::new((0..3).map(|_| Ok(create_batch(vec![5000])))))
+        .collect::<Vec<_>>();
+    assert!(results[0].is_ok() && results[1].is_ok());
+    assert!(is_fuel_exhausted(results[2].as_ref().unwrap_err()));
+    // each batch reports the fuel the stream burnt so far
+    let consumed = runner
+        .run_stream_with_stats(Box::new((0..2).map(|_| Ok(create_batch(vec![5000])))))
+        .map(|result| result.unwrap().1.fuel_consumed)
+        .collect::<Vec<_>>();
+    assert!(consumed[0] > 0 && consumed[1] > consumed[0], "{:?}", consumed);
+
+    // a budget needs an engine that meters fuel
+    let engine = Engine::default();
+    let module = Module::from_binary(&engine, &wasm_data).unwrap();
+    let err = WasmScalarUdfRunner::new_with_options(
+        engine,
+        module,
+        "spin".to_string(),
+        vec![DataType::Int32],
+        DataType::Int32,
+        WasmRunnerOptions {
+            fuel: Some(FuelBudget::PerRow(100_000)),
+            ..Default::default()
+        },
+    )
+    .err()
+    .unwrap();
+    assert!(matches!(err, WasmError::InvalidInput { .. }), "{}", err);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_wasm_scalar_udf_runner_timeout() {
+    // `spin` loops `n` times; it traps when entered on an instance whose previous call was
+    // interrupted
+    let wasm_data = guest_module(
+        r#"
+        (global $busy (mut i32) (i32.const 0))
+        (func (export "spin") (param $n i32) (result i32)
+          (local $i i32)
+          (if (global.get $busy) (then unreachable))
+          (global.set $busy (i32.const 1))
+          (loop $again
+            (local.set $i (i32.add (local.get $i) (i32.const 1)))
+            (br_if $again (i32.lt_u (local.get $i) (local.get $n))))
+          (global.set $busy (i32.const 0))
+          local.get $i)
+        "#,
+    );
+    let runner = WasmScalarUdfRunner::new_from_raw_with_options(
+        "spin".to_string(),
+        vec![DataType::Int32],
+        DataType::Int32,
+        &wasm_data,
+        WasmRunnerOptions {
+            instance_pool: Some(InstancePoolOptions {
+                pool_size: 1,
+                reset_policy: InstanceResetPolicy::Never,
+                max_uses_per_instance: None,
+            }),
+            timeout: Some(Duration::from_millis(20)),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let create_batch = |values: Vec<i32>| {
+        let schema = Schema::new(vec![Field::new("val1", DataType::Int32, true)]);
+        RecordBatch::try_new(Arc::new(schema), vec![Arc::new(Int32Array::from(values))]).unwrap()
+    };
+
+    assert!(runner.run(&create_batch(vec![10])).is_ok());
+    let start = Instant::now();
+    let err = runner.run(&create_batch(vec![i32::MAX])).unwrap_err();
+    assert!(
+        matches!(&err, WasmError::Timeout { func } if func == "spin"),
+        "{}",
+        err
+    );
+    assert!(start.elapsed() < Duration::from_secs(5));
+    // the interrupted instance is not handed out again, even though the pool keeps
+    // instances on error
+    let result = runner.run(&create_batch(vec![10])).unwrap();
+    assert_eq!(result.column(0).as_ref(), &Int32Array::from(vec![10]));
+
+    // an engine with epoch interruption runs guests to completion without a timeout
+    let mut config = Config::new();
+    config.epoch_interruption(true);
+    let engine = Engine::new(&config).unwrap();
+    let module = Module::from_binary(&engine, &wasm_data).unwrap();
+    let runner = WasmScalarUdfRunner::new_with_options(
+        engine,
+        module,
+        "spin".to_string(),
+        vec![DataType::Int32],
+        DataType::Int32,
+        WasmRunnerOptions::default(),
+    )
+    .unwrap();
+    let result = runner.run(&create_batch(vec![10])).unwrap();
+    assert_eq!(result.column(0).as_ref(), &Int32Array::from(vec![10]));
+
+    // a timeout needs an engine with epoch interruption
+    let engine = Engine::default();
+    let module = Module::from_binary(&engine, &wasm_data).unwrap();
+    let err = WasmScalarUdfRunner::new_with_options(
+        engine,
+        module,
+        "spin".to_string(),
+        vec![DataType::Int32],
+        DataType::Int32,
+        WasmRunnerOptions {
+            timeout: Some(Duration::from_millis(20)),
+            ..Default::default()
+        },
+    )
+    .err()
+    .unwrap();
+    assert!(matches!(err, WasmError::InvalidInput { .. }), "{}", err);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_wasm_arrow_scalar_udf_runner_memory_limits() {
+    // the allocator grows memory on demand; `echo` returns its input and `hog` grows memory
+    // by 100 pages first
+    let wasm_data = bump_allocator_module(
+        r#"
+        (func (export "echo") (param i64) (result i64) local.get 0)
+        (func (export "hog") (param i64) (result i64)
+          (drop (memory.grow (i32.const 100)))
+          local.get 0)
+        "#,
+    );
+    let options = WasmRunnerOptions {
+        limits: Some(ResourceLimits {
+            max_memory_bytes: Some(4 * 65536),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let create_runner = |func: &str| {
+        WasmArrowScalarUdfRunner::new_from_raw_with_options(
+            func.to_string(),
+            vec![DataType::Int32],
+            DataType::Int32,
+            &wasm_data,
+            options.clone(),
+        )
+        .unwrap()
+    };
+    let schema = Schema::new(vec![Field::new("val1", DataType::Int32, true)]);
+    let batch = RecordBatch::try_new(
+        Arc::new(schema),
+        vec![Arc::new(Int32Array::from_iter_values(0..100_000))],
+    )
+    .unwrap();
+
+    // 400KB of input do not fit in 256KB of guest memory, but each half does
+    let result = create_runner("echo").run(&batch).unwrap();
+    assert_eq!(result.column(0).as_ref(), batch.column(0).as_ref());
+
+    let err = create_runner("hog").run(&batch.slice(0, 2)).unwrap_err();
+    assert!(
+        matches!(&err, WasmError::OutOfMemory { msg } if msg.contains("exceeds the limit")),
+        "{}",
+        err
+    );
+
+    // an allocator handing out memory it does not have is a guest bug, not worth a split
+    let wasm_data = wat::parse_str(
+        r#"
+        (module
+          (memory (export "memory") 1)
+          (func (export "wasm_alloc") (param i32) (result i32) i32.const 65000)
+          (func (export "wasm_free") (param i32))
+          (func (export "echo") (param i64) (result i64) local.get 0))
+        "#,
+    )
+    .unwrap();
+    let runner = WasmArrowScalarUdfRunner::new_from_raw(
+        "echo".to_string(),
+        vec![DataType::Int32],
+        DataType::Int32,
+        &wasm_data,
+    )
+    .unwrap();
+    let err = runner.run(&batch).unwrap_err();
+    assert!(
+        matches!(&err, WasmError::InvalidUdfResult { msg, .. } if msg.contains("out of bounds")),
+        "{}",
+        err
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_wasm_scalar_udf_runner_wasi_policy() {
+    // each export ignores its argument and reports what WASI lets the guest see; `open`
+    // opens `data.txt` in the first preopened directory for reading (0), writing (1) or
+    // creation (2) and returns the errno
+    let wasm_data = wat::parse_str(
+        r#"
+        (module
+          (import "wasi_snapshot_preview1" "args_sizes_get"
+            (func $args_sizes_get (param i32 i32) (result i32)))
+          (import "wasi_snapshot_preview1" "environ_sizes_get"
+            (func $environ_sizes_get (param i32 i32) (result i32)))
+          (import "wasi_snapshot_preview1" "path_open"
+            (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+          (import "wasi_snapshot_preview1" "clock_time_get"
+            (func $clock_time_get (param i32 i64 i32) (result i32)))
+          (import "wasi_snapshot_preview1" "random_get"
+            (func $random_get (param i32 i32) (result i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 100) "data.txt")
+          (func (export "wasm_alloc") (param i32) (result i32) i32.const 1024)
+          (func (export "wasm_free") (param i32))
+          (func (export "arg_count") (param i32) (result i32)
+            (drop (call $args_sizes_get (i32.const 0) (i32.const 4)))
+            (i32.load (i32.const 0)))
+          (func (export "env_count") (param i32) (result i32)
+            (drop (call $environ_sizes_get (i32.const 0) (i32.const 4)))
+            (i32.load (i32.const 0)))
+          (func (export "open") (param $mode i32) (result i32)
+            (call $path_open
+              (i32.const 3) (i32.const 0) (i32.const 100) (i32.const 8)
+              (select (i32.const 1) (i32.const 0) (i32.eq (local.get $mode) (i32.const 2)))
+              (select (i64.const 66) (i64.const 2) (i32.eq (local.get $mode) (i32.const 1)))
+              (i64.const 0) (i32.const 0) (i32.const 8)))
+          (func (export "now") (param i32) (result i64)
+            (drop (call $clock_time_get (i32.const 0) (i64.const 1) (i32.const 16)))
+            (i64.load (i32.const 16)))
+          (func (export "random") (param i32) (result i64)
+            (drop (call $random_get (i32.const 16) (i32.const 8)))
+            (i64.load (i32.const 16)))
+          (func (export "random_repeats") (param i32) (result i32)
+            (drop (call $random_get (i32.const 4096) (i32.const 8192)))
+            (i64.eq (i64.load (i32.const 4096)) (i64.load (i32.const 8192)))))
+        "#,
+    )
+    .unwrap();
+    let schema = Schema::new(vec![Field::new("val1", DataType::Int32, true)]);
+    let batch = |mode: i32| {
+        RecordBatch::try_new(
+            Arc::new(schema.clone()),
+            vec![Arc::new(Int32Array::from(vec![mode]))],
+        )
+        .unwrap()
+    };
+    let call = |func: &str, output_type: DataType, policy: &WasiPolicy, mode: i32| {
+        let runner = WasmScalarUdfRunner::new_from_raw_with_options(
+            func.to_string(),
+            vec![DataType::Int32],
+            output_type,
+            &wasm_data,
+            WasmRunnerOptions {
+                wasi: policy.clone(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        runner.run(&batch(mode)).unwrap().column(0).clone()
+    };
+    let call_i32 = |func: &str, policy: &WasiPolicy, mode: i32| {
+        call(func, DataType::Int32, policy, mode)
+            .as_any()
+            .downcast_ref::<Int32Array>()
+            .unwrap()
+            .value(0)
+    };
+    let call_i64 = |func: &str, policy: &WasiPolicy| {
+        call(func, DataType::Int64, policy, 0)
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap()
+            .value(0)
+    };
+
+    // nothing is granted by default
+    let policy = WasiPolicy::default();
+    assert_eq!(call_i32("arg_count", &policy, 0), 0);
+    assert_eq!(call_i32("env_count", &policy, 0), 0);
+    assert_ne!(call_i32("open", &policy, 0), 0);
+
+    let dir = std::env::temp_dir().join(format!("cellforce-wasi-policy-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("data.txt"), "data").unwrap();
+    let policy = WasiPolicy {
+        args: vec!["udf".to_string(), "--flag".to_string()],
+        env: vec![("MODE".to_string(), "test".to_string())],
+        inherit_env: vec!["PATH".to_string(), "CELLFORCE_UNSET_VARIABLE".to_string()],
+        preopened_dirs: vec![PreopenedDir {
+            host_path: dir.clone(),
+            guest_path: "data".to_string(),
+        }],
+        ..Default::default()
+    };
+    assert_eq!(call_i32("arg_count", &policy, 0), 2);
+    let inherited = std::env::var("PATH").is_ok() as i32;
+    assert_eq!(call_i32("env_count", &policy, 0), 1 + inherited);
+    assert_eq!(call_i32("open", &policy, 0), 0);
+    // preopened directories are read-only
+    assert_ne!(call_i32("open", &policy, 1), 0);
+    assert_ne!(call_i32("open", &policy, 2), 0);
+    assert_eq!(std::fs::read_to_string(dir.join("data.txt")).unwrap(), "data");
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let seeded = |seed| WasiPolicy {
+        deterministic_seed: Some(seed),
+        ..Default::default()
+    };
+    assert_eq!(call_i64("now", &seeded(7)), 0);
+    assert_ne!(call_i64("now", &WasiPolicy::default()), 0);
+    assert_eq!(call_i64("random", &seeded(7)), call_i64("random", &seeded(7)));
+    assert_ne!(call_i64("random", &seeded(7)), call_i64("random", &seeded(8)));
+    assert_eq!(call_i32("random_repeats", &seeded(7), 0), 0);
+
+    // directories that cannot be opened are reported when the runner is created
+    let policy = WasiPolicy {
+        preopened_dirs: vec![PreopenedDir {
+            host_path: dir,
+            guest_path: "data".to_string(),
+        }],
+        ..Default::default()
+    };
+    let err = WasmScalarUdfRunner::new_from_raw_with_options(
+        "arg_count".to_string(),
+        vec![DataType::Int32],
+        DataType::Int32,
+        &wasm_data,
+        WasmRunnerOptions {
+            wasi: policy,
+            ..Default::default()
+        },
+    )
+    .err()
+    .unwrap();
+    assert!(matches!(err, WasmError::Instantiation { .. }), "{}", err);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_wasm_scalar_udf_runner_captures_stdio() {
+    // `echo` prints a line to stdout and another to stderr for every row
+    let wasm_data = wat::parse_str(
+        r#"
+        (module
+          (import "wasi_snapshot_preview1" "fd_write"
+            (func $fd_write (param i32 i32 i32 i32) (result i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 100) "hello\n")
+          (data (i32.const 110) "oops\n")
+          (data (i32.const 200) "\64\00\00\00\06\00\00\00\6e\00\00\00\05\00\00\00")
+          (func (export "wasm_alloc") (param i32) (result i32) i32.const 1024)
+          (func (export "wasm_free") (param i32))
+          (func (export "echo") (param $x i32) (result i32)
+            (drop (call $fd_write (i32.const 1) (i32.const 200) (i32.const 1) (i32.const 300)))
+            (drop (call $fd_write (i32.const 2) (i32.const 208) (i32.const 1) (i32.const 300)))
+            (local.get $x)))
+        "#,
+    )
+    .unwrap();
+    let schema = Schema::new(vec![Field::new("val1", DataType::Int32, true)]);
+    let batch = RecordBatch::try_new(
+        Arc::new(schema),
+        vec![Arc::new(Int32Array::from(vec![1, 2]))],
+    )
+    .unwrap();
+    let runner = |stdio: GuestStdio| {
+        WasmScalarUdfRunner::new_from_raw_with_options(
+            "echo".to_string(),
+            vec![DataType::Int32],
+            DataType::Int32,
+            &wasm_data,
+            WasmRunnerOptions {
+                instance_pool: Some(InstancePoolOptions::default()),
+                wasi: WasiPolicy {
+                    stdio,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .unwrap()
+    };
+
+    let expected = GuestOutput {
+        stdout: "hello\nhello\n".to_string(),
+        stderr: "oops\noops\n".to_string(),
+    };
+    let runner_with_output = runner(GuestStdio::Capture {
+        return_output: true,
+        max_captured_bytes: DEFAULT_MAX_CAPTURED_BYTES,
+    });
+    // the output of a pooled instance is not carried over to the next run
+    for _ in 0..2 {
+        let (result, stats) = runner_with_output.run_with_stats(&batch).unwrap();
+        assert_eq!(result.num_rows(), 2);
+        assert_eq!(stats.output.as_ref(), Some(&expected));
+    }
+
+    for stdio in [
+        GuestStdio::Discard,
+        GuestStdio::Capture {
+            return_output: false,
+            max_captured_bytes: DEFAULT_MAX_CAPTURED_BYTES,
+        },
+    ] {
+        let (result, stats) = runner(stdio).run_with_stats(&batch).unwrap();
+        assert_eq!(result.num_rows(), 2);
+        assert_eq!(stats.output, None);
+    }
+
+    // output past the limit is dropped, and reported once per stream
+    let runner_with_limit = runner(GuestStdio::Capture {
+        return_output: true,
+        max_captured_bytes: 8,
+    });
+    let recorder = EventRecorder::default();
+    let (_, stats) = tracing::subscriber::with_default(recorder.clone(), || {
+        runner_with_limit.run_with_stats(&batch).unwrap()
+    });
+    let truncated = GuestOutput {
+        stdout: "hello\nhe".to_string(),
+        stderr: "oops\noop".to_string(),
+    };
+    assert_eq!(stats.output.as_ref(), Some(&truncated));
+    let dropped: Vec<_> = recorder
+        .0
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, _, fields)| fields.contains_key("dropped_bytes"))
+        .map(|(_, _, fields)| (fields["stream"].clone(), fields["dropped_bytes"].clone()))
+        .collect();
+    assert_eq!(
+        dropped,
+        [("stdout".to_string(), "4".to_string()), ("stderr".to_string(), "2".to_string())]
+    );
+}
+
+/// Target, level and fields of an event.
+type RecordedEvent = (String, Level, BTreeMap<String, String>);
+
+/// Subscriber keeping every event.
+#[derive(Clone, Default)]
+struct EventRecorder(Arc<Mutex<Vec<RecordedEvent>>>);
+
+struct FieldRecorder(BTreeMap<String, String>);
+
+impl Visit for FieldRecorder {
+    fn record_str(&mut self, field: &TracingField, value: &str) {
+        self.0.insert(field.name().to_string(), value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &TracingField, value: &dyn std::fmt::Debug) {
+        self.0.insert(field.name().to_string(), format!("{:?}", value));
+    }
+}
+
+impl Subscriber for EventRecorder {
+    fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, _span: &span::Attributes<'_>) -> span::Id {
+        span::Id::from_u64(1)
+    }
+
+    fn record(&self, _span: &span::Id, _values: &span::Record<'_>) {}
+
+    fn record_follows_from(&self, _span: &span::Id, _follows: &span::Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut fields = FieldRecorder(BTreeMap::new());
+        event.record(&mut fields);
+        let metadata = event.metadata();
+        self.0.lock().unwrap().push((
+            metadata.target().to_string(),
+            *metadata.level(),
+            fields.0,
+        ));
+    }
+
+    fn enter(&self, _span: &span::Id) {}
+
+    fn exit(&self, _span: &span::Id) {}
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_wasm_scalar_udf_runner_host_logging() {
+    // `observe` logs its argument as the level, bumps a counter, records a histogram value
+    // and prints a line
+    let wasm_data = wat::parse_str(
+        r#"
+        (module
+          (import "cellforce_host" "log" (func $log (param i32 i32 i32)))
+          (import "cellforce_host" "counter_add" (func $counter_add (param i32 i32 i64)))
+          (import "cellforce_host" "histogram_record"
+            (func $histogram_record (param i32 i32 f64)))
+          (import "wasi_snapshot_preview1" "fd_write"
+            (func $fd_write (param i32 i32 i32 i32) (result i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 100) "row seen")
+          (data (i32.const 110) "rows")
+          (data (i32.const 120) "latency")
+          (data (i32.const 130) "printed\n")
+          (data (i32.const 200) "\82\00\00\00\08\00\00\00")
+          (func (export "wasm_alloc") (param i32) (result i32) i32.const 1024)
+          (func (export "wasm_free") (param i32))
+          (func (export "observe") (param $level i32) (result i32)
+            (call $log (local.get $level) (i32.const 100) (i32.const 8))
+            (call $counter_add (i32.const 110) (i32.const 4) (i64.const 1))
+            (call $histogram_record (i32.const 120) (i32.const 7) (f64.const 2.5))
+            (drop (call $fd_write (i32.const 1) (i32.const 200) (i32.const 1) (i32.const 300)))
+            (local.get $level)))
+        "#,
+    )
+    .unwrap();
+    let runner = WasmScalarUdfRunner::new_from_raw_with_options(
+        "observe".to_string(),
+        vec![DataType::Int32],
+        DataType::Int32,
+        &wasm_data,
+        WasmRunnerOptions {
+            wasi: WasiPolicy {
+                stdio: GuestStdio::Capture {
+                    return_output: false,
+                    max_captured_bytes: DEFAULT_MAX_CAPTURED_BYTES,
+                },
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let schema = Arc::new(Schema::new(vec![Field::new("val1", DataType::Int32, true)]));
+    let batch = |levels: Vec<i32>| {
+        RecordBatch::try_new(schema.clone(), vec![Arc::new(Int32Array::from(levels))]).unwrap()
+    };
+
+    let recorder = EventRecorder::default();
+    tracing::subscriber::with_default(recorder.clone(), || {
+        runner.run(&batch(vec![1, 3])).unwrap();
+    });
+    let events = recorder.0.lock().unwrap().clone();
+    let logs: Vec<_> = events
+        .iter()
+        .filter(|(target, _, _)| target == GUEST_LOG_TARGET)
+        .collect();
+    assert_eq!(logs.len(), 2);
+    assert_eq!((logs[0].1, logs[1].1), (Level::DEBUG, Level::WARN));
+    assert_eq!(logs[0].2["message"], "row seen");
+    assert_eq!(logs[0].2["udf"], "observe");
+    let batch_id = &logs[0].2["batch_id"];
+    let metrics: Vec<_> = events
+        .iter()
+        .filter(|(target, _, _)| target == GUEST_METRICS_TARGET)
+        .map(|(_, _, fields)| {
+            assert_eq!(&fields["batch_id"], batch_id);
+            (fields["metric"].as_str(), fields["kind"].as_str(), fields["value"].as_str())
+        })
+        .collect();
+    assert_eq!(
+        metrics,
+        [
+            ("rows", "counter", "1"),
+            ("latency", "histogram", "2.5"),
+            ("rows", "counter", "1"),
+            ("latency", "histogram", "2.5"),
+        ]
+    );
+    let printed: Vec<_> = events
+        .iter()
+        .filter(|(target, _, _)| target == GUEST_OUTPUT_TARGET)
+        .map(|(_, level, fields)| (*level, fields["message"].as_str(), &fields["batch_id"]))
+        .collect();
+    assert_eq!(
+        printed,
+        [(Level::INFO, "printed", batch_id), (Level::INFO, "printed", batch_id)]
+    );
+
+    // unknown levels trap
+    let err = runner.run(&batch(vec![5])).unwrap_err();
+    assert!(matches!(err, WasmError::GuestTrap { .. }), "{}", err);
+    assert!(err.to_string().contains("unknown log level 5"), "{}", err);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_wasm_scalar_udf_runner_frees_buffers() {
+    // `copy` returns a copy of its argument and `leak` also keeps 8 bytes for itself
+    let wasm_data = bump_allocator_module(
+        r#"
+        (func $copy (export "copy") (param $arg i64) (result i64)
+          (local $len i32)
+          (local $ptr i32)
+          (local.set $len (i32.wrap_i64 (i64.shr_u (local.get $arg) (i64.const 32))))
+          (local.set $ptr (call $alloc (local.get $len)))
+          (memory.copy (local.get $ptr) (i32.wrap_i64 (local.get $arg)) (local.get $len))
+          (i64.or
+            (i64.shl (i64.extend_i32_u (local.get $len)) (i64.const 32))
+            (i64.extend_i32_u (local.get $ptr))))
+        (func (export "leak") (param $arg i64) (result i64)
+          (drop (call $alloc (i32.const 8)))
+          (call $copy (local.get $arg)))
+        "#,
+    );
+    let schema = Schema::new(vec![Field::new("val1", DataType::Binary, true)]);
+    let batch = RecordBatch::try_new(
+        Arc::new(schema),
+        vec![Arc::new(BinaryArray::from(vec![
+            Some(b"abc".as_ref()),
+            None,
+            Some(b"defgh".as_ref()),
+        ]))],
+    )
+    .unwrap();
+    let runner = |func: &str| {
+        WasmScalarUdfRunner::new_from_raw_with_options(
+            func.to_string(),
+            vec![DataType::Binary],
+            DataType::Binary,
+            &wasm_data,
+            WasmRunnerOptions {
+                instance_pool: Some(InstancePoolOptions::default()),
+                check_leaks: true,
+                ..Default::default()
+            },
+        )
+    };
+
+    // arguments and results are handed back to the guest, run after run
+    let copy = runner("copy").unwrap();
+    for _ in 0..3 {
+        let result = copy.run(&batch).unwrap();
+        let values = result.column(0).as_any().downcast_ref::<BinaryArray>().unwrap();
+        assert_eq!(
+            values.iter().collect::<Vec<_>>(),
+            vec![Some(b"abc".as_ref()), None, Some(b"defgh".as_ref())]
+        );
+    }
+
+    let err = runner("leak").unwrap().run(&batch).unwrap_err();
+    assert!(
+        matches!(&err, WasmError::MemoryLeak { func, bytes: 16 } if func == "leak"),
+        "{}",
+        err
+    );
+
+    // leak checks need the guest to report its heap usage
+    let err = WasmScalarUdfRunner::new_from_raw_with_options(
+        "inc".to_string(),
+        vec![DataType::Int32],
+        DataType::Int32,
+        &guest_module(
+            r#"
+            (func (export "inc") (param i32) (result i32)
+              (i32.add (local.get 0) (i32.const 1)))
+            "#,
+        ),
+        WasmRunnerOptions {
+            check_leaks: true,
+            ..Default::default()
+        },
+    )
+    .err()
+    .unwrap();
+    assert!(matches!(err, WasmError::MissingExport { .. }), "{}", err);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_wasm_scalar_udf_runner_passthrough_results() {
+    // `identity` returns its argument buffer; the allocator hands out 64-byte slots and
+    // reuses the last freed one first, and a double free shows up as a heap underflow
+    let wasm_data = wat::parse_str(
+        r#"
+        (module
+          (memory (export "memory") 1)
+          (global $next (mut i32) (i32.const 1024))
+          (global $free (mut i32) (i32.const 0))
+          (global $used (mut i32) (i32.const 0))
+          (func (export "wasm_alloc") (param $size i32) (result i32)
+            (local $ptr i32)
+            (global.set $used (i32.add (global.get $used) (local.get $size)))
+            (if (global.get $free)
+              (then
+                (local.set $ptr (global.get $free))
+                (global.set $free (i32.const 0))
+                (return (local.get $ptr))))
+            (local.set $ptr (global.get $next))
+            (global.set $next (i32.add (global.get $next) (i32.const 64)))
+            (local.get $ptr))
+          (func (export "wasm_free") (param $ptr i32) (param $size i32)
+            (global.set $free (local.get $ptr))
+            (global.set $used (i32.sub (global.get $used) (local.get $size))))
+          (func (export "wasm_heap_used") (result i32) (global.get $used))
+          (func (export "identity") (param i64) (result i64) (local.get 0)))
+        "#,
+    )
+    .unwrap();
+    let schema = Schema::new(vec![Field::new("val1", DataType::Binary, true)]);
+    let expected = vec![
+        Some(b"aaaa".as_ref()),
+        Some(b"bb".as_ref()),
+        None,
+        Some(b"cccccc".as_ref()),
+    ];
+    let batch = RecordBatch::try_new(
+        Arc::new(schema),
+        vec![Arc::new(BinaryArray::from(expected.clone()))],
+    )
+    .unwrap();
+    let runner = |async_support: bool| {
+        WasmScalarUdfRunner::new_from_raw_with_options(
+            "identity".to_string(),
+            vec![DataType::Binary],
+            DataType::Binary,
+            &wasm_data,
+            WasmRunnerOptions {
+                instance_pool: Some(InstancePoolOptions::default()),
+                async_support,
+                check_leaks: true,
+                ..Default::default()
+            },
+        )
+        .unwrap()
+    };
+
+    let sync_runner = runner(false);
+    let async_runner = runner(true);
+    for _ in 0..2 {
+        for result in [
+            sync_runner.run(&batch).unwrap(),
+            async_runner.run_async(&batch).await.unwrap(),
+        ] {
+            let values = result.column(0).as_any().downcast_ref::<BinaryArray>().unwrap();
+            assert_eq!(values.iter().collect::<Vec<_>>(), expected);
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_wasm_scalar_udf_runner_frees_buffers_of_failed_calls() {
+    // `heap` traps on 3-byte arguments and otherwise returns the bytes allocated in the guest
+    let wasm_data = bump_allocator_module(
+        r#"
+        (func (export "heap") (param $arg i64) (result i32)
+          (if (i64.eq (i64.shr_u (local.get $arg) (i64.const 32)) (i64.const 3))
+            (then unreachable))
+          (global.get $used))
+        "#,
+    );
+    let runner = WasmScalarUdfRunner::new_from_raw_with_options(
+        "heap".to_string(),
+        vec![DataType::Binary],
+        DataType::Int32,
+        &wasm_data,
+        WasmRunnerOptions {
+            // the instance is kept after the failed call
+            instance_pool: Some(InstancePoolOptions {
+                pool_size: 1,
+                reset_policy: InstanceResetPolicy::Never,
+                max_uses_per_instance: None,
+            }),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let batch = |values: Vec<&[u8]>| {
+        let schema = Schema::new(vec![Field::new("val1", DataType::Binary, true)]);
+        RecordBatch::try_new(Arc::new(schema), vec![Arc::new(BinaryArray::from(values))]).unwrap()
+    };
+
+    let err = runner.run(&batch(vec![b"a", b"abc"])).unwrap_err();
+    assert!(matches!(err, WasmError::GuestTrap { .. }), "{}", err);
+    // only the argument of the current row is allocated
+    let result = runner.run(&batch(vec![b"de"])).unwrap();
+    assert_eq!(result.column(0).as_ref(), &Int32Array::from(vec![2]));
+
+    // `echo` traps on its first call, then returns its argument as long as nothing else is
+    // allocated
+    let wasm_data = bump_allocator_module(
+        r#"
+        (global $calls (mut i32) (i32.const 0))
+        (func (export "echo") (param $arg i64) (result i64)
+          (global.set $calls (i32.add (global.get $calls) (i32.const 1)))
+          (if (i32.eq (global.get $calls) (i32.const 1))
+            (then unreachable))
+          (if (i64.ne
+                (i64.extend_i32_u (global.get $used))
+                (i64.shr_u (local.get $arg) (i64.const 32)))
+            (then unreachable))
+          (local.get $arg))
+        "#,
+    );
+    let runner = WasmArrowScalarUdfRunner::new_from_raw_with_options(
+        "echo".to_string(),
+        vec![DataType::Int32],
+        DataType::Int32,
+        &wasm_data,
+        WasmRunnerOptions {
+            instance_pool: Some(InstancePoolOptions {
+                pool_size: 1,
+                reset_policy: InstanceResetPolicy::Never,
+                max_uses_per_instance: None,
+            }),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let schema = Schema::new(vec![Field::new("val1", DataType::Int32, true)]);
+    let batch = RecordBatch::try_new(
+        Arc::new(schema),
+        vec![Arc::new(Int32Array::from(vec![1, 2, 3]))],
+    )
+    .unwrap();
+    let err = runner.run(&batch).unwrap_err();
+    assert!(matches!(err, WasmError::GuestTrap { .. }), "{}", err);
+    let result = runner.run(&batch).unwrap();
+    assert_eq!(result.column(0).as_ref(), batch.column(0).as_ref());
+}
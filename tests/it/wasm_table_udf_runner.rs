@@ -0,0 +1,129 @@
+use arrow::array::{Int32Array, Int64Array, UInt32Array};
+use arrow::datatypes::{DataType, Field, Schema};
+use arrow::record_batch::RecordBatch;
+use cellforce_wasm_core::errors::WasmError;
+use cellforce_wasm_core::runner::instance_pool::InstancePoolOptions;
+use cellforce_wasm_core::runner::ipc::encode_ipc;
+use cellforce_wasm_core::runner::loader::{WasmTableUdfOptions, WasmUdfRunnerLoader};
+use cellforce_wasm_core::runner::options::{NullHandling, WasmRunnerOptions};
+use cellforce_wasm_core::runner::runner_base::WasmUdfRunner;
+use cellforce_wasm_core::runner::table_udf_runner::{WasmTableUdfRunner, PARENT_ROW_COLUMN};
+use std::sync::Arc;
+
+use crate::common::{bump_allocator_module, wat_data};
+
+/// A `generate_series(n)` UDF returning the rows `1..=n` for `n` up to 3. The results are
+/// prebuilt IPC streams; a table at offset 256 holds the packed buffer for each `n`.
+fn generate_series_module() -> Vec<u8> {
+    let mut data = String::new();
+    let mut table = String::new();
+    let mut offset = 1024;
+    for n in 0..=3 {
+        let result = RecordBatch::try_new(
+            Arc::new(Schema::new(vec![Field::new(
+                "value",
+                DataType::Int64,
+                false,
+            )])),
+            vec![Arc::new(Int64Array::from_iter_values(1..=n))],
+        )
+        .unwrap();
+        let ipc = encode_ipc(&result).unwrap();
+        data.push_str(&format!(
+            "(data (i32.const {}) \"{}\")\n",
+            offset,
+            wat_data(&ipc)
+        ));
+        let packed = ((ipc.len() as u64) << 32) | offset as u64;
+        table.push_str(&wat_data(&packed.to_le_bytes()));
+        offset += ipc.len();
+    }
+    bump_allocator_module(&format!(
+        r#"
+        (data (i32.const 256) "{}")
+        {}
+        (func (export "generate_series") (param $n i32) (result i64)
+          (if (i32.gt_u (local.get $n) (i32.const 3)) (then unreachable))
+          (i64.load (i32.add (i32.const 256) (i32.shl (local.get $n) (i32.const 3)))))
+        "#,
+        table, data,
+    ))
+}
+
+fn create_input_data(values: Vec<Option<i32>>) -> RecordBatch {
+    let schema = Schema::new(vec![Field::new("n", DataType::Int32, true)]);
+    RecordBatch::try_new(Arc::new(schema), vec![Arc::new(Int32Array::from(values))]).unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_wasm_table_udf_runner() {
+    let wasm_data = generate_series_module();
+    let runner = WasmTableUdfRunner::new_from_raw_with_options(
+        "generate_series".to_string(),
+        vec![DataType::Int32],
+        vec![Field::new("value", DataType::Int64, true)],
+        &wasm_data,
+        WasmRunnerOptions {
+            instance_pool: Some(InstancePoolOptions::default()),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let input = create_input_data(vec![Some(2), Some(0), None, Some(3)]);
+
+    let batches = runner
+        .run_iter(&input)
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(batches.len(), 2);
+    assert_eq!(batches[0].schema(), runner.schema());
+
+    let result = runner.run(&input).unwrap();
+    assert_eq!(result.schema().field(0).name(), PARENT_ROW_COLUMN);
+    assert_eq!(
+        result.column(0).as_ref(),
+        &UInt32Array::from(vec![0, 0, 3, 3, 3])
+    );
+    assert_eq!(
+        result.column(1).as_ref(),
+        &Int64Array::from(vec![1, 2, 1, 2, 3])
+    );
+
+    // a trap stops the iteration after the rows produced so far
+    let input = create_input_data(vec![Some(1), Some(4), Some(1)]);
+    let mut batches = runner.run_iter(&input).unwrap();
+    assert!(batches.next().unwrap().is_ok());
+    let err = batches.next().unwrap().unwrap_err();
+    assert!(matches!(err, WasmError::GuestTrap { .. }), "{}", err);
+    assert!(batches.next().is_none());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_wasm_table_udf_runner_loader() {
+    let wasm_data = generate_series_module();
+    let spec = WasmTableUdfOptions {
+        export_name: "generate_series".to_string(),
+        internal_name: "generate_series".to_string(),
+        input_types: vec!["int32".to_string()],
+        output_types: vec!["int64".to_string()],
+        output_names: vec!["value".to_string()],
+        null_handling: NullHandling::default(),
+    };
+    let runner = WasmUdfRunnerLoader::new()
+        .load_table_udf_runner(&spec, &wasm_data)
+        .unwrap();
+    let result = runner.run(&create_input_data(vec![Some(1)])).unwrap();
+    assert_eq!(result.num_rows(), 1);
+    assert_eq!(result.schema().field(1).name(), "value");
+
+    let spec = WasmTableUdfOptions {
+        output_names: vec![PARENT_ROW_COLUMN.to_string()],
+        ..spec
+    };
+    let err = WasmUdfRunnerLoader::new()
+        .load_table_udf_runner(&spec, &wasm_data)
+        .err()
+        .unwrap();
+    assert!(matches!(err, WasmError::InvalidInput { .. }), "{}", err);
+}
@@ -0,0 +1,262 @@
+use arrow::array::{Int64Array, TimestampMillisecondArray};
+use arrow::datatypes::{DataType, Field, Schema, TimeUnit};
+use arrow::record_batch::RecordBatch;
+use cellforce_wasm_core::errors::WasmError;
+use cellforce_wasm_core::runner::ipc::encode_ipc;
+use cellforce_wasm_core::runner::loader::{WasmUdfRunnerLoader, WasmWindowUdfOptions};
+use cellforce_wasm_core::runner::options::NullHandling;
+use cellforce_wasm_core::runner::runner_base::WasmUdfRunner;
+use cellforce_wasm_core::runner::window_udf_runner::{
+    WasmWindowUdfRunner, WindowFrame, WindowFrameBound,
+};
+use std::sync::Arc;
+
+use crate::common::{bump_allocator_module, guest_module, wat_data};
+
+const NUM_ROWS: usize = 4;
+
+/// A `frame_size` window UDF over partitions of `NUM_ROWS` rows, returning the number of
+/// rows in each frame. The result is a prebuilt IPC stream whose values are patched in place.
+fn frame_size_module() -> Vec<u8> {
+    let sentinel = 0x1122_3344_5566_7788i64;
+    let result = RecordBatch::try_new(
+        Arc::new(Schema::new(vec![Field::new(
+            "size",
+            DataType::Int64,
+            false,
+        )])),
+        vec![Arc::new(Int64Array::from(vec![sentinel; NUM_ROWS]))],
+    )
+    .unwrap();
+    let ipc = encode_ipc(&result).unwrap();
+    let values_offset = ipc
+        .windows(8)
+        .position(|w| w == sentinel.to_le_bytes())
+        .unwrap();
+    bump_allocator_module(&format!(
+        r#"
+        (data (i32.const 1024) "{}")
+        (func (export "frame_size") (param $partition i64) (param $frames i64) (result i64)
+          (local $frame i32) (local $row i32)
+          (local.set $frame (i32.wrap_i64 (local.get $frames)))
+          (block $done
+            (loop $rows
+              (br_if $done (i32.ge_u (local.get $row) (i32.const {})))
+              (i64.store
+                (i32.add (i32.const {}) (i32.shl (local.get $row) (i32.const 3)))
+                (i64.extend_i32_u
+                  (i32.sub
+                    (i32.load offset=4 (local.get $frame))
+                    (i32.load (local.get $frame)))))
+              (local.set $frame (i32.add (local.get $frame) (i32.const 8)))
+              (local.set $row (i32.add (local.get $row) (i32.const 1)))
+              (br $rows)))
+          i64.const {})
+        "#,
+        wat_data(&ipc),
+        NUM_ROWS,
+        1024 + values_offset,
+        ((ipc.len() as i64) << 32) | 1024,
+    ))
+}
+
+/// A `moving_sum` window UDF implemented with the incremental entry points.
+fn moving_sum_module() -> Vec<u8> {
+    guest_module(
+        r#"
+        (global $sum (mut i64) (i64.const 0))
+        (func (export "moving_sum_open")
+          (global.set $sum (i64.const 0)))
+        (func (export "moving_sum_add") (param $v i64)
+          (global.set $sum (i64.add (global.get $sum) (local.get $v))))
+        (func (export "moving_sum_retract") (param $v i64)
+          (global.set $sum (i64.sub (global.get $sum) (local.get $v))))
+        (func (export "moving_sum_evaluate") (result i64)
+          global.get $sum)
+        "#,
+    )
+}
+
+fn create_input_data(values: Vec<Option<i64>>) -> RecordBatch {
+    let schema = Schema::new(vec![Field::new("val1", DataType::Int64, true)]);
+    RecordBatch::try_new(Arc::new(schema), vec![Arc::new(Int64Array::from(values))]).unwrap()
+}
+
+#[test]
+fn test_window_frame_bounds() {
+    let batch = create_input_data(vec![Some(1), Some(2), Some(2), Some(5)]);
+    let cases = vec![
+        (
+            WindowFrame::rows(WindowFrameBound::Preceding(1), WindowFrameBound::CurrentRow),
+            vec![0..1, 0..2, 1..3, 2..4],
+        ),
+        (
+            WindowFrame::rows(
+                WindowFrameBound::Following(1),
+                WindowFrameBound::Following(2),
+            ),
+            vec![1..3, 2..4, 3..4, 4..4],
+        ),
+        (
+            WindowFrame::range(
+                WindowFrameBound::Preceding(1),
+                WindowFrameBound::CurrentRow,
+                0,
+            ),
+            vec![0..1, 0..3, 0..3, 3..4],
+        ),
+        (
+            WindowFrame::range(
+                WindowFrameBound::CurrentRow,
+                WindowFrameBound::UnboundedFollowing,
+                0,
+            ),
+            vec![0..4, 1..4, 1..4, 3..4],
+        ),
+        (WindowFrame::default(), vec![0..4, 0..4, 0..4, 0..4]),
+    ];
+    for (frame, expected) in cases {
+        assert_eq!(frame.bounds(&batch).unwrap(), expected, "{:?}", frame);
+    }
+
+    // offsets beyond the partition or the range of the keys clamp to its ends
+    let cases = vec![
+        WindowFrame::rows(
+            WindowFrameBound::Preceding(u64::MAX),
+            WindowFrameBound::Following(u64::MAX),
+        ),
+        WindowFrame::range(
+            WindowFrameBound::Preceding(u64::MAX),
+            WindowFrameBound::Following(u64::MAX),
+            0,
+        ),
+    ];
+    for frame in cases {
+        assert_eq!(
+            frame.bounds(&batch).unwrap(),
+            vec![0..4, 0..4, 0..4, 0..4],
+            "{:?}",
+            frame
+        );
+    }
+    let frame = WindowFrame::rows(
+        WindowFrameBound::Following(u64::MAX),
+        WindowFrameBound::UnboundedFollowing,
+    );
+    assert_eq!(frame.bounds(&batch).unwrap(), vec![4..4, 4..4, 4..4, 4..4]);
+    let extremes = create_input_data(vec![Some(i64::MIN), Some(0), Some(i64::MAX)]);
+    let frame = WindowFrame::range(
+        WindowFrameBound::Preceding(1),
+        WindowFrameBound::Following(1),
+        0,
+    );
+    assert_eq!(frame.bounds(&extremes).unwrap(), vec![0..1, 1..2, 2..3]);
+
+    let frame = WindowFrame::range(
+        WindowFrameBound::Preceding(1),
+        WindowFrameBound::CurrentRow,
+        0,
+    );
+    let unsorted = create_input_data(vec![Some(2), Some(1)]);
+    assert!(matches!(
+        frame.bounds(&unsorted),
+        Err(WasmError::InvalidInput { .. })
+    ));
+    let unordered = WindowFrame {
+        order_by: None,
+        ..frame
+    };
+    assert!(matches!(
+        unordered.bounds(&batch),
+        Err(WasmError::InvalidInput { .. })
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_wasm_window_udf_runner() {
+    let wasm_data = frame_size_module();
+    let timestamps = TimestampMillisecondArray::from(vec![0, 1_000, 1_500, 10_000]);
+    let schema = Schema::new(vec![Field::new(
+        "at",
+        DataType::Timestamp(TimeUnit::Millisecond, None),
+        false,
+    )]);
+    let partition = RecordBatch::try_new(Arc::new(schema), vec![Arc::new(timestamps)]).unwrap();
+    let runner = WasmWindowUdfRunner::new_from_raw(
+        "frame_size".to_string(),
+        vec![DataType::Timestamp(TimeUnit::Millisecond, None)],
+        DataType::Int64,
+        WindowFrame::range(
+            WindowFrameBound::Preceding(1_000),
+            WindowFrameBound::CurrentRow,
+            0,
+        ),
+        &wasm_data,
+    )
+    .unwrap();
+    assert!(!runner.is_incremental());
+    let result = runner.run_partition(&partition).unwrap();
+    assert_eq!(result.as_ref(), &Int64Array::from(vec![1, 2, 2, 1]));
+
+    let wasm_data = moving_sum_module();
+    let runner = WasmWindowUdfRunner::new_from_raw(
+        "moving_sum".to_string(),
+        vec![DataType::Int64],
+        DataType::Int64,
+        WindowFrame::rows(
+            WindowFrameBound::Preceding(1),
+            WindowFrameBound::Following(1),
+        ),
+        &wasm_data,
+    )
+    .unwrap();
+    assert!(runner.is_incremental());
+    let partition = create_input_data(vec![Some(1), Some(10), None, Some(1000)]);
+    let result = runner.run(&partition).unwrap();
+    assert_eq!(
+        result.column(0).as_ref(),
+        &Int64Array::from(vec![11, 11, 1010, 1000])
+    );
+    // each partition starts from a fresh state
+    let result = runner.run(&partition).unwrap();
+    assert_eq!(
+        result.column(0).as_ref(),
+        &Int64Array::from(vec![11, 11, 1010, 1000])
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_wasm_window_udf_runner_loader() {
+    let wasm_data = moving_sum_module();
+    let spec = WasmWindowUdfOptions {
+        export_name: "moving_sum".to_string(),
+        internal_name: "moving_sum".to_string(),
+        input_types: vec!["int64".to_string()],
+        output_type: "int64".to_string(),
+        frame: WindowFrame::rows(
+            WindowFrameBound::UnboundedPreceding,
+            WindowFrameBound::CurrentRow,
+        ),
+        null_handling: NullHandling::default(),
+    };
+    let runner = WasmUdfRunnerLoader::new()
+        .load_window_udf_runner(&spec, &wasm_data)
+        .unwrap();
+    let result = runner
+        .run_partition(&create_input_data(vec![Some(1), Some(2), Some(3)]))
+        .unwrap();
+    assert_eq!(result.as_ref(), &Int64Array::from(vec![1, 3, 6]));
+
+    let spec = WasmWindowUdfOptions {
+        internal_name: "moving_avg".to_string(),
+        ..spec
+    };
+    let err = WasmUdfRunnerLoader::new()
+        .load_window_udf_runner(&spec, &wasm_data)
+        .err()
+        .unwrap();
+    assert_eq!(
+        err.to_string(),
+        "module does not export function `moving_avg`"
+    );
+}